@@ -9,9 +9,14 @@ pub struct DatTable {
     pub entries: u32,
 }
 
+#[cfg(feature = "game")]
 whack_vars!(init_vars, 0x00400000,
     0x00513C30 => units_dat: [DatTable; 0x35];
     0x005136E0 => upgrades_dat: [DatTable; 0xb];
     0x005137D8 => techdata_dat: [DatTable; 0x8];
-    0x00513868 => weapons_dat: [DatTable; 0x17];
+    0x00513868 => weapons_dat: [DatTable; 0x18];
+    0x006C9858 => flingy_dat: [DatTable; 0x7];
+    0x00513FB8 => sprites_dat: [DatTable; 0x6];
+    0x00514010 => images_dat: [DatTable; 0xe];
+    0x00665880 => orders_dat: [DatTable; 0x13];
 );
@@ -1,200 +1,1075 @@
 extern crate libc;
+#[macro_use] extern crate quick_error;
+#[cfg(feature = "game")]
 #[macro_use] extern crate whack;
+#[macro_use] extern crate lazy_static;
 
 mod bw;
+pub mod parse;
+mod patch;
+mod tables;
 
+use std::mem;
+
+pub use parse::{ColumnLayout, DatFile, DatFiles, ParseError};
+pub use patch::{apply, patches, revert_patches, revert_patches_since, Dat, Patch};
+
+#[cfg(feature = "game")]
 pub unsafe fn init(patcher: &mut whack::ModulePatcher) {
     bw::init_vars(patcher);
 }
 
-unsafe fn get(dat: &bw::DatTable, id: u32) -> u32 {
-    assert!(dat.entries > id);
-    match dat.entry_size {
-        1 => *(dat.data as *const u8).offset(id as isize) as u32,
-        2 => *(dat.data as *const u16).offset(id as isize) as u32,
-        4 => *(dat.data as *const u32).offset(id as isize),
-        x => panic!("Invalid dat entry size: {}", x),
+quick_error! {
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum DatError {
+        InvalidId(table: &'static str, id: u32, entries: u32) {
+            display("Invalid {} id {}, the table has {} entries", table, id, entries)
+        }
+        InvalidEntrySize(table: &'static str, column: u32, size: u32) {
+            display("Column {} of {} has unsupported entry size {}", column, table, size)
+        }
+        NoEntry(table: &'static str, column: u32, id: u32) {
+            display("Column {} of {} has no entry for id {}", column, table, id)
+        }
+        InvalidColumn(table: &'static str, column: u32) {
+            display("{} doesn't have column {}", table, column)
+        }
+        InvalidValue(table: &'static str, column: u32, value: u32) {
+            display("Value {} doesn't fit in column {} of {}", value, column, table)
+        }
     }
 }
 
-pub mod units {
-    use bw;
-    pub fn get(index: usize, id: u16) -> u32 {
-        unsafe {
-            super::get(&bw::units_dat[index], id as u32)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct UnitId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct WeaponId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct UpgradeId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct TechId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct FlingyId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct SpriteId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct ImageId(pub u16);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct OrderId(pub u8);
+
+/// Returns pointer to the entry of `id` in a column, and the size of the entry.
+fn entry(
+    tables: &[bw::DatTable],
+    layout: &[ColumnLayout],
+    table: &'static str,
+    column: usize,
+    id: u32,
+) -> Result<(*mut u8, u32), DatError> {
+    // BW doesn't load some of the last columns in the files.
+    if column >= tables.len() || column >= layout.len() {
+        return Err(DatError::InvalidColumn(table, column as u32));
+    }
+    let dat = &tables[column];
+    let entries = dat.entries;
+    let first_id = layout[column].first_id as u32;
+    let index = match id.checked_sub(first_id) {
+        Some(s) if s < entries => s,
+        _ => {
+            if layout[column].count.is_some() || id < first_id {
+                return Err(DatError::NoEntry(table, column as u32, id));
+            }
+            return Err(DatError::InvalidId(table, id, entries + first_id));
         }
+    };
+    let size = dat.entry_size;
+    let data = dat.data as *mut u8;
+    Ok((unsafe { data.offset((index * size) as isize) }, size))
+}
+
+fn get(
+    tables: &[bw::DatTable],
+    layout: &[ColumnLayout],
+    table: &'static str,
+    column: usize,
+    id: u32,
+) -> Result<u32, DatError> {
+    let (entry, size) = entry(tables, layout, table, column, id)?;
+    // The columns are stored back to back like they are in the file, so the larger
+    // entries aren't necessarily aligned.
+    unsafe {
+        match size {
+            1 => Ok(*entry as u32),
+            2 => Ok((entry as *const u16).read_unaligned() as u32),
+            4 => Ok((entry as *const u32).read_unaligned()),
+            x => Err(DatError::InvalidEntrySize(table, column as u32, x)),
+        }
+    }
+}
+
+/// Writes a value to the dat table, returning the previous one.
+fn set(
+    tables: &[bw::DatTable],
+    layout: &[ColumnLayout],
+    table: &'static str,
+    column: usize,
+    id: u32,
+    value: u32,
+) -> Result<u32, DatError> {
+    let (entry, size) = entry(tables, layout, table, column, id)?;
+    if size != 1 && size != 2 && size != 4 {
+        return Err(DatError::InvalidEntrySize(table, column as u32, size));
+    }
+    if size < 4 && value >> (size * 8) != 0 {
+        return Err(DatError::InvalidValue(table, column as u32, value));
+    }
+    unsafe {
+        Ok(match size {
+            1 => mem::replace(&mut *entry, value as u8) as u32,
+            2 => {
+                let old = (entry as *const u16).read_unaligned();
+                (entry as *mut u16).write_unaligned(value as u16);
+                old as u32
+            }
+            _ => {
+                let old = (entry as *const u32).read_unaligned();
+                (entry as *mut u32).write_unaligned(value);
+                old
+            }
+        })
+    }
+}
+
+pub mod units {
+    use parse::{all, fixed, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, UnitId, UpgradeId, WeaponId};
+
+    /// Columns of units.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Flingy = 0,
+        Subunit = 1,
+        Subunit2 = 2,
+        InfestedUnit = 3,
+        ConstructionImage = 4,
+        Direction = 5,
+        HasShields = 6,
+        Shields = 7,
+        Hitpoints = 8,
+        Elevation = 9,
+        MovementFlags = 10,
+        Rank = 11,
+        AiIdleOrder = 12,
+        HumanIdleOrder = 13,
+        ReturnToIdleOrder = 14,
+        AttackUnitOrder = 15,
+        AttackMoveOrder = 16,
+        GroundWeapon = 17,
+        MaxGroundHits = 18,
+        AirWeapon = 19,
+        MaxAirHits = 20,
+        AiFlags = 21,
+        Flags = 22,
+        TargetAcquisitionRange = 23,
+        SightRange = 24,
+        ArmorUpgrade = 25,
+        UnitSize = 26,
+        Armor = 27,
+        RightClickAction = 28,
+        ReadySound = 29,
+        FirstWhatSound = 30,
+        LastWhatSound = 31,
+        FirstPissedSound = 32,
+        LastPissedSound = 33,
+        FirstYesSound = 34,
+        LastYesSound = 35,
+        PlacementBox = 36,
+        AddonPosition = 37,
+        DimensionBox = 38,
+        Portrait = 39,
+        MineralCost = 40,
+        GasCost = 41,
+        BuildTime = 42,
+        Requirements = 43,
+        GroupFlags = 44,
+        SupplyProvided = 45,
+        SupplyCost = 46,
+        TransportSpaceRequired = 47,
+        TransportSpaceProvided = 48,
+        BuildScore = 49,
+        KillScore = 50,
+        MapLabel = 51,
+        BroodwarOnly = 52,
+    }
+
+    /// How the columns are stored in units.dat. The last column is StarEdit's availability
+    /// flags, which BW doesn't load.
+    pub const LAYOUT: [ColumnLayout; 0x36] = [
+        all(1), all(2), all(2), fixed(2, 106, 96), all(4), all(1), all(1), all(2),
+        all(4), all(1), all(1), all(1), all(1), all(1), all(1), all(1),
+        all(1), all(1), all(1), all(1), all(1), all(1), all(4), all(1),
+        all(1), all(1), all(1), all(1), all(1), fixed(2, 0, 106), all(2), all(2),
+        fixed(2, 0, 106), fixed(2, 0, 106), fixed(2, 0, 106), fixed(2, 0, 106), all(4),
+        fixed(4, 106, 96), all(8), all(2), all(2), all(2), all(2), all(2),
+        all(1), all(1), all(1), all(1), all(1), all(2), all(2), all(2),
+        all(1), all(2),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0x36] = [
+        "flingy", "subunit", "subunit_2", "infested_unit", "construction_image", "direction",
+        "has_shields", "shields", "hitpoints", "elevation", "movement_flags", "rank",
+        "ai_idle_order", "human_idle_order", "return_to_idle_order", "attack_unit_order",
+        "attack_move_order", "ground_weapon", "max_ground_hits", "air_weapon", "max_air_hits",
+        "ai_flags", "flags", "target_acquisition_range", "sight_range", "armor_upgrade",
+        "unit_size", "armor", "right_click_action", "ready_sound", "first_what_sound",
+        "last_what_sound", "first_pissed_sound", "last_pissed_sound", "first_yes_sound",
+        "last_yes_sound", "placement_box", "addon_position", "dimension_box", "portrait",
+        "mineral_cost", "gas_cost", "build_time", "requirements", "group_flags", "supply_provided",
+        "supply_cost", "transport_space_required", "transport_space_provided", "build_score",
+        "kill_score", "map_label", "broodwar_only", "star_edit_availability",
+    ];
+
+    pub fn get(column: Column, id: UnitId) -> Result<u32, DatError> {
+        super::get(tables::units(), &LAYOUT, "units.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: UnitId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Units,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
     }
 
     pub fn amount() -> u32 {
-        unsafe { bw::units_dat[0].entries }
+        tables::units()[0].entries
     }
 
-    pub fn hitpoints(id: u16) -> i32 {
-        get(8, id) as i32
+    pub fn hitpoints(id: UnitId) -> Option<i32> {
+        get(Column::Hitpoints, id).ok().map(|x| x as i32)
     }
 
-    pub fn shields(id: u16) -> i32 {
+    pub fn set_hitpoints(id: UnitId, value: i32) -> Result<(), DatError> {
+        set(Column::Hitpoints, id, value as u32)
+    }
+
+    pub fn shields(id: UnitId) -> Option<i32> {
         // Yeah, it is stored as displayed
-        get(7, id) as i32 * 256
+        get(Column::Shields, id).ok().map(|x| x as i32 * 256)
+    }
+
+    pub fn has_shields(id: UnitId) -> Option<bool> {
+        get(Column::HasShields, id).ok().map(|x| x != 0)
+    }
+
+    pub fn ground_weapon(id: UnitId) -> Option<WeaponId> {
+        get(Column::GroundWeapon, id).ok().map(|x| WeaponId(x as u16))
+    }
+
+    pub fn air_weapon(id: UnitId) -> Option<WeaponId> {
+        get(Column::AirWeapon, id).ok().map(|x| WeaponId(x as u16))
+    }
+
+    pub fn set_ground_weapon(id: UnitId, weapon: WeaponId) -> Result<(), DatError> {
+        set(Column::GroundWeapon, id, weapon.0 as u32)
     }
 
-    pub fn has_shields(id: u16) -> u32 {
-        get(6, id)
+    pub fn set_air_weapon(id: UnitId, weapon: WeaponId) -> Result<(), DatError> {
+        set(Column::AirWeapon, id, weapon.0 as u32)
     }
 
-    pub fn ground_weapon(id: u16) -> u32 {
-        get(17, id)
+    pub fn flags(id: UnitId) -> Option<u32> {
+        get(Column::Flags, id).ok()
     }
 
-    pub fn air_weapon(id: u16) -> u32 {
-        get(19, id)
+    pub fn set_flags(id: UnitId, value: u32) -> Result<(), DatError> {
+        set(Column::Flags, id, value)
     }
 
-    pub fn flags(id: u16) -> u32 {
-        get(22, id)
+    pub fn group_flags(id: UnitId) -> Option<u32> {
+        get(Column::GroupFlags, id).ok()
     }
 
-    pub fn group_flags(id: u16) -> u32 {
-        get(44, id)
+    pub fn armor(id: UnitId) -> Option<u32> {
+        get(Column::Armor, id).ok()
     }
 
-    pub fn armor(id: u16) -> u32 {
-        get(27, id)
+    pub fn set_armor(id: UnitId, value: u32) -> Result<(), DatError> {
+        set(Column::Armor, id, value)
     }
 
-    pub fn armor_upgrade(id: u16) -> u32 {
-        get(25, id)
+    pub fn armor_upgrade(id: UnitId) -> Option<UpgradeId> {
+        get(Column::ArmorUpgrade, id).ok().map(|x| UpgradeId(x as u16))
     }
 
-    pub fn mineral_cost(id: u16) -> u32 {
-        get(40, id)
+    pub fn mineral_cost(id: UnitId) -> Option<u32> {
+        get(Column::MineralCost, id).ok()
     }
 
-    pub fn gas_cost(id: u16) -> u32 {
-        get(41, id)
+    pub fn gas_cost(id: UnitId) -> Option<u32> {
+        get(Column::GasCost, id).ok()
     }
 
-    pub fn build_time(id: u16) -> u32 {
-        get(42, id)
+    pub fn build_time(id: UnitId) -> Option<u32> {
+        get(Column::BuildTime, id).ok()
     }
 
-    pub fn supply_cost(id: u16) -> u32 {
-        get(46, id)
+    pub fn supply_cost(id: UnitId) -> Option<u32> {
+        get(Column::SupplyCost, id).ok()
     }
 }
 
 pub mod weapons {
-    use bw;
-    pub fn get(index: usize, id: u32) -> u32 {
-        unsafe {
-            super::get(&bw::weapons_dat[index], id)
-        }
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, FlingyId, UpgradeId, WeaponId};
+
+    /// Columns of weapons.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Label = 0,
+        Flingy = 1,
+        Unused = 2,
+        TargetFlags = 3,
+        MinRange = 4,
+        MaxRange = 5,
+        Upgrade = 6,
+        DamageType = 7,
+        Behaviour = 8,
+        RemoveAfter = 9,
+        ExplosionType = 10,
+        InnerSplash = 11,
+        MiddleSplash = 12,
+        OuterSplash = 13,
+        Damage = 14,
+        Bonus = 15,
+        Cooldown = 16,
+        Factor = 17,
+        AttackAngle = 18,
+        LaunchSpin = 19,
+        ForwardOffset = 20,
+        UpwardOffset = 21,
+        TargetErrorMessage = 22,
+        Icon = 23,
+    }
+
+    /// How the columns are stored in weapons.dat.
+    pub const LAYOUT: [ColumnLayout; 0x18] = [
+        all(2), all(4), all(1), all(2), all(4), all(4), all(1), all(1),
+        all(1), all(1), all(1), all(2), all(2), all(2), all(2), all(2),
+        all(1), all(1), all(1), all(1), all(1), all(1), all(2), all(2),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0x18] = [
+        "label", "flingy", "unused", "target_flags", "min_range", "max_range", "upgrade",
+        "damage_type", "behaviour", "remove_after", "explosion_type", "inner_splash",
+        "middle_splash", "outer_splash", "damage", "bonus", "cooldown", "factor", "attack_angle",
+        "launch_spin", "forward_offset", "upward_offset", "target_error_message", "icon",
+    ];
+
+    pub fn get(column: Column, id: WeaponId) -> Result<u32, DatError> {
+        super::get(tables::weapons(), &LAYOUT, "weapons.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: WeaponId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Weapons,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
     }
 
     pub fn amount() -> u32 {
-        unsafe { bw::weapons_dat[0].entries }
+        tables::weapons()[0].entries
     }
 
-    pub fn damage(id: u32) -> u32 {
-        get(14, id)
+    pub fn label(id: WeaponId) -> Option<u32> {
+        get(Column::Label, id).ok()
     }
 
-    pub fn upgrade(id: u32) -> u32{
-        get(6, id)
+    /// The flingy of bullets the weapon creates.
+    pub fn flingy(id: WeaponId) -> Option<FlingyId> {
+        get(Column::Flingy, id).ok().map(|x| FlingyId(x as u16))
     }
 
-    pub fn bonus(id: u32) -> u32 {
-        get(15, id)
+    pub fn target_flags(id: WeaponId) -> Option<u32> {
+        get(Column::TargetFlags, id).ok()
     }
 
-    pub fn factor(id: u32) -> u32{
-        get(17, id)
+    pub fn min_range(id: WeaponId) -> Option<u32> {
+        get(Column::MinRange, id).ok()
     }
 
-    pub fn label(id: u32) -> u32 {
-        get(0, id)
+    pub fn set_min_range(id: WeaponId, value: u32) -> Result<(), DatError> {
+        set(Column::MinRange, id, value)
     }
 
+    pub fn max_range(id: WeaponId) -> Option<u32> {
+        get(Column::MaxRange, id).ok()
+    }
+
+    pub fn set_max_range(id: WeaponId, value: u32) -> Result<(), DatError> {
+        set(Column::MaxRange, id, value)
+    }
+
+    pub fn upgrade(id: WeaponId) -> Option<UpgradeId> {
+        get(Column::Upgrade, id).ok().map(|x| UpgradeId(x as u16))
+    }
+
+    pub fn damage_type(id: WeaponId) -> Option<u32> {
+        get(Column::DamageType, id).ok()
+    }
+
+    /// How the bullet moves, e.g. 0 flies to the target position and 2 appears on the target.
+    pub fn behaviour(id: WeaponId) -> Option<u32> {
+        get(Column::Behaviour, id).ok()
+    }
+
+    /// Frames until the bullet gets removed, for behaviours that don't end on their own.
+    pub fn remove_after(id: WeaponId) -> Option<u32> {
+        get(Column::RemoveAfter, id).ok()
+    }
+
+    pub fn explosion_type(id: WeaponId) -> Option<u32> {
+        get(Column::ExplosionType, id).ok()
+    }
+
+    /// Inner, middle and outer splash radii.
+    pub fn splash_radii(id: WeaponId) -> Option<(u32, u32, u32)> {
+        let inner = get(Column::InnerSplash, id).ok()?;
+        let middle = get(Column::MiddleSplash, id).ok()?;
+        let outer = get(Column::OuterSplash, id).ok()?;
+        Some((inner, middle, outer))
+    }
+
+    pub fn damage(id: WeaponId) -> Option<u32> {
+        get(Column::Damage, id).ok()
+    }
+
+    pub fn set_damage(id: WeaponId, value: u32) -> Result<(), DatError> {
+        set(Column::Damage, id, value)
+    }
+
+    pub fn bonus(id: WeaponId) -> Option<u32> {
+        get(Column::Bonus, id).ok()
+    }
+
+    pub fn set_bonus(id: WeaponId, value: u32) -> Result<(), DatError> {
+        set(Column::Bonus, id, value)
+    }
+
+    pub fn cooldown(id: WeaponId) -> Option<u32> {
+        get(Column::Cooldown, id).ok()
+    }
+
+    pub fn set_cooldown(id: WeaponId, value: u32) -> Result<(), DatError> {
+        set(Column::Cooldown, id, value)
+    }
+
+    pub fn factor(id: WeaponId) -> Option<u32> {
+        get(Column::Factor, id).ok()
+    }
+
+    pub fn attack_angle(id: WeaponId) -> Option<u32> {
+        get(Column::AttackAngle, id).ok()
+    }
+
+    pub fn launch_spin(id: WeaponId) -> Option<u32> {
+        get(Column::LaunchSpin, id).ok()
+    }
+
+    pub fn forward_offset(id: WeaponId) -> Option<u32> {
+        get(Column::ForwardOffset, id).ok()
+    }
+
+    pub fn upward_offset(id: WeaponId) -> Option<u32> {
+        get(Column::UpwardOffset, id).ok()
+    }
+
+    pub fn target_error_message(id: WeaponId) -> Option<u32> {
+        get(Column::TargetErrorMessage, id).ok()
+    }
+
+    pub fn icon(id: WeaponId) -> Option<u32> {
+        get(Column::Icon, id).ok()
+    }
 }
 
 pub mod upgrades {
-    use bw;
-    pub fn get(index: usize, id: u32) -> u32 {
-        unsafe {
-            super::get(&bw::upgrades_dat[index], id)
-        }
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, UpgradeId};
+
+    /// Columns of upgrades.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        MineralCost = 0,
+        MineralFactor = 1,
+        GasCost = 2,
+        GasFactor = 3,
+        Time = 4,
+        TimeFactor = 5,
+        Requirements = 6,
+        Icon = 7,
+        Label = 8,
+        Race = 9,
+        RepeatCount = 10,
+    }
+
+    /// How the columns are stored in upgrades.dat. The last column is the Brood War flag,
+    /// which BW doesn't load.
+    pub const LAYOUT: [ColumnLayout; 0xc] = [
+        all(2), all(2), all(2), all(2), all(2), all(2), all(2), all(2),
+        all(2), all(1), all(1), all(1),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0xc] = [
+        "mineral_cost", "mineral_factor", "gas_cost", "gas_factor", "time", "time_factor",
+        "requirements", "icon", "label", "race", "repeat_count", "broodwar_only",
+    ];
+
+    pub fn get(column: Column, id: UpgradeId) -> Result<u32, DatError> {
+        super::get(tables::upgrades(), &LAYOUT, "upgrades.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: UpgradeId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Upgrades,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
     }
 
     pub fn amount() -> u32 {
-        unsafe { bw::upgrades_dat[0].entries }
+        tables::upgrades()[0].entries
     }
 
-    pub fn label(id: u32) -> u32 {
-        get(8, id)
+    pub fn label(id: UpgradeId) -> Option<u32> {
+        get(Column::Label, id).ok()
     }
 
-    pub fn mineral_cost(id: u32) -> u32 {
-        get(0, id)
+    pub fn mineral_cost(id: UpgradeId) -> Option<u32> {
+        get(Column::MineralCost, id).ok()
     }
 
-    pub fn gas_cost(id: u32) -> u32 {
-        get(2, id)
+    pub fn gas_cost(id: UpgradeId) -> Option<u32> {
+        get(Column::GasCost, id).ok()
     }
 
-    pub fn time(id: u32) -> u32 {
-        get(4, id)
+    pub fn time(id: UpgradeId) -> Option<u32> {
+        get(Column::Time, id).ok()
     }
 
-    pub fn mineral_factor(id: u32) -> u32 {
-        get(1, id)
+    pub fn mineral_factor(id: UpgradeId) -> Option<u32> {
+        get(Column::MineralFactor, id).ok()
     }
 
-    pub fn gas_factor(id: u32) -> u32 {
-        get(3, id)
+    pub fn gas_factor(id: UpgradeId) -> Option<u32> {
+        get(Column::GasFactor, id).ok()
     }
 
-    pub fn time_factor(id: u32) -> u32 {
-        get(5, id)
+    pub fn time_factor(id: UpgradeId) -> Option<u32> {
+        get(Column::TimeFactor, id).ok()
     }
 
-    pub fn icon(id: u32) -> u32 {
-        get(7, id)
+    pub fn icon(id: UpgradeId) -> Option<u32> {
+        get(Column::Icon, id).ok()
     }
 
-    pub fn repeat_count(id: u32) -> u32 {
-        get(10, id)
+    pub fn repeat_count(id: UpgradeId) -> Option<u32> {
+        get(Column::RepeatCount, id).ok()
     }
 }
 
 pub mod techdata {
-    use bw;
-    pub fn get(index: usize, id: u32) -> u32 {
-        unsafe {
-            super::get(&bw::techdata_dat[index], id)
-        }
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, TechId};
+
+    /// Columns of techdata.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        MineralCost = 0,
+        GasCost = 1,
+        Time = 2,
+        EnergyCost = 3,
+        ResearchRequirements = 4,
+        UseRequirements = 5,
+        Icon = 6,
+        Label = 7,
+    }
+
+    /// How the columns are stored in techdata.dat. The last three columns are race, an unused
+    /// byte and the Brood War flag, which BW doesn't load.
+    pub const LAYOUT: [ColumnLayout; 0xb] = [
+        all(2), all(2), all(2), all(2), all(2), all(2), all(2), all(2),
+        all(1), all(1), all(1),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0xb] = [
+        "mineral_cost", "gas_cost", "time", "energy_cost", "research_requirements",
+        "use_requirements", "icon", "label", "race", "unused", "broodwar_only",
+    ];
+
+    pub fn get(column: Column, id: TechId) -> Result<u32, DatError> {
+        super::get(tables::techdata(), &LAYOUT, "techdata.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: TechId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Techdata,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
+    }
+
+    pub fn amount() -> u32 {
+        tables::techdata()[0].entries
+    }
+
+    pub fn mineral_cost(id: TechId) -> Option<u32> {
+        get(Column::MineralCost, id).ok()
+    }
+
+    pub fn gas_cost(id: TechId) -> Option<u32> {
+        get(Column::GasCost, id).ok()
+    }
+
+    pub fn time(id: TechId) -> Option<u32> {
+        get(Column::Time, id).ok()
+    }
+
+    pub fn energy_cost(id: TechId) -> Option<u32> {
+        get(Column::EnergyCost, id).ok()
+    }
+
+    pub fn icon(id: TechId) -> Option<u32> {
+        get(Column::Icon, id).ok()
+    }
+}
+
+pub mod flingy {
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, FlingyId, SpriteId};
+
+    /// Columns of flingy.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Sprite = 0,
+        TopSpeed = 1,
+        Acceleration = 2,
+        HaltDistance = 3,
+        TurnRadius = 4,
+        Unused = 5,
+        MovementControl = 6,
+    }
+
+    /// How the columns are stored in flingy.dat.
+    pub const LAYOUT: [ColumnLayout; 0x7] = [
+        all(2), all(4), all(2), all(4), all(1), all(1), all(1),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0x7] = [
+        "sprite", "top_speed", "acceleration", "halt_distance", "turn_radius", "unused",
+        "movement_control",
+    ];
+
+    pub fn get(column: Column, id: FlingyId) -> Result<u32, DatError> {
+        super::get(tables::flingy(), &LAYOUT, "flingy.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: FlingyId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Flingy,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
     }
 
     pub fn amount() -> u32 {
-        unsafe { bw::techdata_dat[0].entries }
+        tables::flingy()[0].entries
+    }
+
+    pub fn sprite(id: FlingyId) -> Option<SpriteId> {
+        get(Column::Sprite, id).ok().map(|x| SpriteId(x as u16))
+    }
+
+    pub fn top_speed(id: FlingyId) -> Option<u32> {
+        get(Column::TopSpeed, id).ok()
     }
 
-    pub fn mineral_cost(id: u32) -> u32 {
-        get(0, id)
+    pub fn acceleration(id: FlingyId) -> Option<u32> {
+        get(Column::Acceleration, id).ok()
     }
 
-    pub fn gas_cost(id: u32) -> u32 {
-        get(1, id)
+    pub fn halt_distance(id: FlingyId) -> Option<u32> {
+        get(Column::HaltDistance, id).ok()
     }
 
-    pub fn time(id: u32) -> u32 {
-        get(2, id)
+    pub fn turn_radius(id: FlingyId) -> Option<u32> {
+        get(Column::TurnRadius, id).ok()
     }
 
-    pub fn energy_cost(id: u32) -> u32 {
-        get(3, id)
+    /// 0 for flingy.dat movement, 1 for partially and 2 for fully iscript-controlled movement.
+    pub fn movement_control(id: FlingyId) -> Option<u32> {
+        get(Column::MovementControl, id).ok()
+    }
+}
+
+pub mod sprites {
+    use parse::{all, starting_from, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, ImageId, SpriteId};
+
+    /// The health bar and selection circle columns only have entries for sprites starting
+    /// from this id, as the ones before it are never used by units.
+    pub const FIRST_UNIT_SPRITE: u16 = 130;
+
+    /// Columns of sprites.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Image = 0,
+        HealthBar = 1,
+        Unused = 2,
+        IncludeInVisionSync = 3,
+        SelectionCircle = 4,
+        SelectionCircleOffset = 5,
+    }
+
+    /// How the columns are stored in sprites.dat.
+    pub const LAYOUT: [ColumnLayout; 0x6] = [
+        all(2),
+        starting_from(1, FIRST_UNIT_SPRITE),
+        all(1),
+        all(1),
+        starting_from(1, FIRST_UNIT_SPRITE),
+        starting_from(1, FIRST_UNIT_SPRITE),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0x6] = [
+        "image", "health_bar", "unused", "include_in_vision_sync", "selection_circle",
+        "selection_circle_offset",
+    ];
+
+    pub fn get(column: Column, id: SpriteId) -> Result<u32, DatError> {
+        super::get(tables::sprites(), &LAYOUT, "sprites.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: SpriteId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Sprites,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
+    }
+
+    pub fn amount() -> u32 {
+        tables::sprites()[0].entries
+    }
+
+    pub fn image(id: SpriteId) -> Option<ImageId> {
+        get(Column::Image, id).ok().map(|x| ImageId(x as u16))
+    }
+
+    pub fn health_bar(id: SpriteId) -> Option<u32> {
+        get(Column::HealthBar, id).ok()
+    }
+
+    pub fn include_in_vision_sync(id: SpriteId) -> Option<bool> {
+        get(Column::IncludeInVisionSync, id).ok().map(|x| x != 0)
+    }
+
+    /// Index of the selection circle size, the circle's image id is
+    /// `images::FIRST_SELECTION_CIRCLE` + this.
+    pub fn selection_circle(id: SpriteId) -> Option<u32> {
+        get(Column::SelectionCircle, id).ok()
+    }
+
+    pub fn selection_circle_offset(id: SpriteId) -> Option<u32> {
+        get(Column::SelectionCircleOffset, id).ok()
+    }
+}
+
+pub mod images {
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, ImageId};
+
+    pub const FIRST_SELECTION_CIRCLE: u16 = 0x231;
+
+    /// Columns of images.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Grp = 0,
+        Turns = 1,
+        Clickable = 2,
+        FullIscript = 3,
+        DrawIfCloaked = 4,
+        DrawFunction = 5,
+        Remapping = 6,
+        Iscript = 7,
+        ShieldOverlay = 8,
+        AttackOverlay = 9,
+        DamageOverlay = 10,
+        SpecialOverlay = 11,
+        LandingDustOverlay = 12,
+        LiftOffDustOverlay = 13,
+    }
+
+    /// How the columns are stored in images.dat.
+    pub const LAYOUT: [ColumnLayout; 0xe] = [
+        all(4), all(1), all(1), all(1), all(1), all(1), all(1), all(4),
+        all(4), all(4), all(4), all(4), all(4), all(4),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0xe] = [
+        "grp", "turns", "clickable", "full_iscript", "draw_if_cloaked", "draw_function",
+        "remapping", "iscript", "shield_overlay", "attack_overlay", "damage_overlay",
+        "special_overlay", "landing_dust_overlay", "lift_off_dust_overlay",
+    ];
+
+    pub fn get(column: Column, id: ImageId) -> Result<u32, DatError> {
+        super::get(tables::images(), &LAYOUT, "images.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: ImageId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Images,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
+    }
+
+    pub fn amount() -> u32 {
+        tables::images()[0].entries
+    }
+
+    /// Index of the grp filename in images.tbl.
+    pub fn grp(id: ImageId) -> Option<u32> {
+        get(Column::Grp, id).ok()
+    }
+
+    pub fn turns(id: ImageId) -> Option<bool> {
+        get(Column::Turns, id).ok().map(|x| x != 0)
+    }
+
+    pub fn clickable(id: ImageId) -> Option<bool> {
+        get(Column::Clickable, id).ok().map(|x| x != 0)
+    }
+
+    pub fn draw_if_cloaked(id: ImageId) -> Option<bool> {
+        get(Column::DrawIfCloaked, id).ok().map(|x| x != 0)
+    }
+
+    pub fn draw_function(id: ImageId) -> Option<u8> {
+        get(Column::DrawFunction, id).ok().map(|x| x as u8)
+    }
+
+    /// Remap palette used by images with the remapping draw function.
+    pub fn remapping(id: ImageId) -> Option<u8> {
+        get(Column::Remapping, id).ok().map(|x| x as u8)
+    }
+
+    pub fn iscript(id: ImageId) -> Option<u32> {
+        get(Column::Iscript, id).ok()
+    }
+}
+
+pub mod orders {
+    use parse::{all, ColumnLayout};
+    use patch::{self, Dat, Patch};
+    use tables;
+    use super::{DatError, OrderId, TechId, WeaponId};
+
+    /// Columns of orders.dat, in the order BW keeps them.
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub enum Column {
+        Label = 0,
+        UseWeaponTargeting = 1,
+        Secondary = 2,
+        NonSubunit = 3,
+        SubunitInheritance = 4,
+        SubunitCanUse = 5,
+        Interruptable = 6,
+        StopMovingBeforeNextOrder = 7,
+        Queueable = 8,
+        KeepTargetWhileDisabled = 9,
+        ClipToWalkable = 10,
+        Fleeable = 11,
+        Unknown12 = 12,
+        Weapon = 13,
+        Tech = 14,
+        Animation = 15,
+        Icon = 16,
+        Requirements = 17,
+        ObscuredOrder = 18,
+    }
+
+    /// How the columns are stored in orders.dat.
+    pub const LAYOUT: [ColumnLayout; 0x13] = [
+        all(2), all(1), all(1), all(1), all(1), all(1), all(1), all(1),
+        all(1), all(1), all(1), all(1), all(1), all(1), all(1), all(1),
+        all(2), all(2), all(1),
+    ];
+
+    /// Names of the columns in `LAYOUT`, for tools that print the tables.
+    pub const COLUMN_NAMES: [&str; 0x13] = [
+        "label", "use_weapon_targeting", "secondary", "non_subunit", "subunit_inheritance",
+        "subunit_can_use", "interruptable", "stop_moving_before_next_order", "queueable",
+        "keep_target_while_disabled", "clip_to_walkable", "fleeable", "unknown_12", "weapon",
+        "tech", "animation", "icon", "requirements", "obscured_order",
+    ];
+
+    pub fn get(column: Column, id: OrderId) -> Result<u32, DatError> {
+        super::get(tables::orders(), &LAYOUT, "orders.dat", column as usize, id.0 as u32)
+    }
+
+    /// Changes an entry, see `bw_dat::apply`.
+    pub fn set(column: Column, id: OrderId, value: u32) -> Result<(), DatError> {
+        patch::apply(Patch {
+            dat: Dat::Orders,
+            column: column as u8,
+            id: id.0 as u32,
+            value,
+        })
+    }
+
+    pub fn amount() -> u32 {
+        tables::orders()[0].entries
+    }
+
+    pub fn label(id: OrderId) -> Option<u32> {
+        get(Column::Label, id).ok()
+    }
+
+    pub fn use_weapon_targeting(id: OrderId) -> Option<bool> {
+        get(Column::UseWeaponTargeting, id).ok().map(|x| x != 0)
+    }
+
+    pub fn interruptable(id: OrderId) -> Option<bool> {
+        get(Column::Interruptable, id).ok().map(|x| x != 0)
+    }
+
+    pub fn queueable(id: OrderId) -> Option<bool> {
+        get(Column::Queueable, id).ok().map(|x| x != 0)
+    }
+
+    pub fn weapon(id: OrderId) -> Option<WeaponId> {
+        get(Column::Weapon, id).ok().map(|x| WeaponId(x as u16))
+    }
+
+    pub fn tech(id: OrderId) -> Option<TechId> {
+        get(Column::Tech, id).ok().map(|x| TechId(x as u16))
+    }
+
+    pub fn animation(id: OrderId) -> Option<u32> {
+        get(Column::Animation, id).ok()
+    }
+
+    /// The order shown to players who can't see the unit.
+    pub fn obscured_order(id: OrderId) -> Option<OrderId> {
+        get(Column::ObscuredOrder, id).ok().map(|x| OrderId(x as u8))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use parse::DatFile;
+    use super::weapons;
+    #[cfg(not(feature = "game"))]
+    use super::{DatFiles, WeaponId};
+
+    #[test]
+    fn weapon_columns() {
+        const WEAPONS: u32 = 130;
+        let layout = &weapons::LAYOUT;
+        let file_size = layout.iter().map(|x| x.size as u32 * WEAPONS).sum::<u32>();
+        // The size of weapons.dat shipped with the game.
+        assert_eq!(file_size, 5460);
+        assert_eq!(weapons::Column::Icon as usize, layout.len() - 1);
+
+        // Each entry has the id in its highest byte, and the column number in the lowest
+        // if there's space for both.
+        let mut file = vec![0u8; file_size as usize];
+        let mut pos = 0;
+        for (column, x) in layout.iter().enumerate() {
+            let size = x.size as usize;
+            for id in 0..WEAPONS {
+                file[pos] = column as u8;
+                file[pos + size - 1] = id as u8;
+                pos += size;
+            }
+        }
+        let file = DatFile::parse(file, layout, "weapons.dat").unwrap();
+        assert_eq!(file.entries(), WEAPONS);
+        for column in 0..layout.len() {
+            for &id in &[0, 1, 0x40, WEAPONS - 1] {
+                let value = super::get(&file.tables, layout, "weapons.dat", column, id);
+                let expected = match layout[column].size {
+                    1 => id,
+                    size => column as u32 | (id << ((size - 1) * 8)),
+                };
+                assert_eq!(value, Ok(expected), "Column {} id {}", column, id);
+            }
+            let value = super::get(&file.tables, layout, "weapons.dat", column, WEAPONS);
+            assert!(value.is_err());
+        }
     }
 
-    pub fn icon(id: u32) -> u32 {
-        get(6, id)
+    #[cfg(not(feature = "game"))]
+    #[test]
+    fn patches() {
+        let mut files = DatFiles::empty();
+        files.weapons = DatFile::parse(vec![0; 5460], &weapons::LAYOUT, "weapons.dat").unwrap();
+        files.install();
+        let id = WeaponId(5);
+        weapons::set_damage(id, 40).unwrap();
+        weapons::set(weapons::Column::Damage, id, 50).unwrap();
+        assert_eq!(weapons::damage(id), Some(50));
+        assert!(weapons::set(weapons::Column::Cooldown, id, 0x100).is_err());
+        assert!(weapons::set_damage(WeaponId(130), 1).is_err());
+        assert_eq!(super::patches().len(), 2);
+        super::revert_patches_since(1);
+        assert_eq!(weapons::damage(id), Some(40));
+        assert_eq!(super::patches().len(), 1);
+        super::revert_patches();
+        assert_eq!(weapons::damage(id), Some(0));
+        assert!(super::patches().is_empty());
     }
 }
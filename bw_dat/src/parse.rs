@@ -0,0 +1,288 @@
+//! Loading dat files without the game, for tools and tests.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::slice;
+
+use libc::c_void;
+
+use bw::DatTable;
+use patch::Dat;
+use DatError;
+use {flingy, images, orders, sprites, techdata, units, upgrades, weapons};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ParseError {
+        Io(file: PathBuf, err: io::Error) {
+            display("Couldn't read {}: {}", file.display(), err)
+        }
+        Size(name: &'static str, size: usize) {
+            display("{} can't be {} bytes", name, size)
+        }
+    }
+}
+
+/// How the entries of a single column are stored in a dat file.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ColumnLayout {
+    pub size: u8,
+    /// The first id that has an entry in the column.
+    pub first_id: u16,
+    /// Amount of entries, if the column doesn't have one for every id starting from
+    /// `first_id`.
+    pub count: Option<u32>,
+}
+
+pub(crate) const fn all(size: u8) -> ColumnLayout {
+    ColumnLayout {
+        size,
+        first_id: 0,
+        count: None,
+    }
+}
+
+pub(crate) const fn starting_from(size: u8, first_id: u16) -> ColumnLayout {
+    ColumnLayout {
+        size,
+        first_id,
+        count: None,
+    }
+}
+
+pub(crate) const fn fixed(size: u8, first_id: u16, count: u32) -> ColumnLayout {
+    ColumnLayout {
+        size,
+        first_id,
+        count: Some(count),
+    }
+}
+
+/// A dat file, with a table for each column like the ones BW creates when it loads the file.
+pub struct DatFile {
+    data: Vec<u8>,
+    pub(crate) tables: Vec<DatTable>,
+    layout: Vec<ColumnLayout>,
+    name: &'static str,
+}
+
+impl DatFile {
+    /// Splits the file to columns. The amount of entries is determined from the size of
+    /// the file, so files with more entries than the ones shipped with the game work too.
+    pub fn parse(
+        mut data: Vec<u8>,
+        layout: &[ColumnLayout],
+        name: &'static str,
+    ) -> Result<DatFile, ParseError> {
+        let entries = entry_count(data.len() as u64, layout)
+            .ok_or(ParseError::Size(name, data.len()))?;
+        let mut pos = 0;
+        let tables = layout.iter().map(|column| {
+            let count = column.count.unwrap_or(entries - column.first_id as u32);
+            let table = DatTable {
+                data: unsafe { data.as_mut_ptr().add(pos) as *mut c_void },
+                entry_size: column.size as u32,
+                entries: count,
+            };
+            pos += column.size as usize * count as usize;
+            table
+        }).collect();
+        Ok(DatFile {
+            data,
+            tables,
+            layout: layout.into(),
+            name,
+        })
+    }
+
+    pub fn from_file(
+        path: &Path,
+        layout: &[ColumnLayout],
+        name: &'static str,
+    ) -> Result<DatFile, ParseError> {
+        let data = fs::read(path).map_err(|e| ParseError::Io(path.into(), e))?;
+        DatFile::parse(data, layout, name)
+    }
+
+    /// A file without any entries.
+    pub fn empty(layout: &[ColumnLayout], name: &'static str) -> DatFile {
+        DatFile {
+            data: Vec::new(),
+            tables: layout.iter().map(|column| DatTable {
+                data: null_mut(),
+                entry_size: column.size as u32,
+                entries: 0,
+            }).collect(),
+            layout: layout.into(),
+            name,
+        }
+    }
+
+    pub fn entries(&self) -> u32 {
+        self.tables.first().map(|x| x.entries).unwrap_or(0)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn layout(&self) -> &[ColumnLayout] {
+        &self.layout
+    }
+
+    /// Reads an entry of a column with 1, 2 or 4 byte entries.
+    pub fn get(&self, column: usize, id: u32) -> Result<u32, DatError> {
+        ::get(&self.tables, &self.layout, self.name, column, id)
+    }
+
+    /// The bytes of an entry, which also works for columns with larger entries.
+    pub fn raw(&self, column: usize, id: u32) -> Result<&[u8], DatError> {
+        let (entry, size) = ::entry(&self.tables, &self.layout, self.name, column, id)?;
+        Ok(unsafe { slice::from_raw_parts(entry, size as usize) })
+    }
+
+    /// Makes the accessors of `dat` read this file for the rest of the process.
+    ///
+    /// In the game, this repoints BW's own tables, so BW uses the file as well. That has to
+    /// be done from the game thread once BW has loaded its files, and while no game is
+    /// running. BW's arrays that are sized by the vanilla entry counts are not grown, so ids
+    /// past them still can't be used for everything.
+    pub fn install(self, dat: Dat) {
+        ::tables::install(dat, self);
+    }
+}
+
+/// Solves the entry count from `size = sum(column size * column entries)`.
+fn entry_count(size: u64, layout: &[ColumnLayout]) -> Option<u32> {
+    let mut per_entry = 0u64;
+    let mut fixed_size = 0u64;
+    let mut skipped = 0u64;
+    for column in layout {
+        match column.count {
+            Some(count) => fixed_size += column.size as u64 * count as u64,
+            None => {
+                per_entry += column.size as u64;
+                skipped += column.size as u64 * column.first_id as u64;
+            }
+        }
+    }
+    let varying_size = (size + skipped).checked_sub(fixed_size)?;
+    if per_entry == 0 || varying_size % per_entry != 0 {
+        return None;
+    }
+    let entries = varying_size / per_entry;
+    let max_first_id = layout.iter()
+        .filter(|x| x.count.is_none())
+        .map(|x| x.first_id as u64)
+        .max()
+        .unwrap_or(0);
+    if entries < max_first_id || entries > u32::MAX as u64 {
+        return None;
+    }
+    Some(entries as u32)
+}
+
+/// All dat files that the accessors can read.
+pub struct DatFiles {
+    pub units: DatFile,
+    pub weapons: DatFile,
+    pub flingy: DatFile,
+    pub sprites: DatFile,
+    pub images: DatFile,
+    pub upgrades: DatFile,
+    pub techdata: DatFile,
+    pub orders: DatFile,
+}
+
+impl DatFiles {
+    /// Loads the files from a directory, which is usually `arr` of extracted game data.
+    pub fn from_dir(dir: &Path) -> Result<DatFiles, ParseError> {
+        let load = |name: &'static str, layout: &[ColumnLayout]| {
+            DatFile::from_file(&dir.join(name), layout, name)
+        };
+        Ok(DatFiles {
+            units: load("units.dat", &units::LAYOUT)?,
+            weapons: load("weapons.dat", &weapons::LAYOUT)?,
+            flingy: load("flingy.dat", &flingy::LAYOUT)?,
+            sprites: load("sprites.dat", &sprites::LAYOUT)?,
+            images: load("images.dat", &images::LAYOUT)?,
+            upgrades: load("upgrades.dat", &upgrades::LAYOUT)?,
+            techdata: load("techdata.dat", &techdata::LAYOUT)?,
+            orders: load("orders.dat", &orders::LAYOUT)?,
+        })
+    }
+
+    pub fn empty() -> DatFiles {
+        DatFiles {
+            units: DatFile::empty(&units::LAYOUT, "units.dat"),
+            weapons: DatFile::empty(&weapons::LAYOUT, "weapons.dat"),
+            flingy: DatFile::empty(&flingy::LAYOUT, "flingy.dat"),
+            sprites: DatFile::empty(&sprites::LAYOUT, "sprites.dat"),
+            images: DatFile::empty(&images::LAYOUT, "images.dat"),
+            upgrades: DatFile::empty(&upgrades::LAYOUT, "upgrades.dat"),
+            techdata: DatFile::empty(&techdata::LAYOUT, "techdata.dat"),
+            orders: DatFile::empty(&orders::LAYOUT, "orders.dat"),
+        }
+    }
+
+    /// Installs every file, see `DatFile::install`.
+    pub fn install(self) {
+        self.units.install(Dat::Units);
+        self.weapons.install(Dat::Weapons);
+        self.flingy.install(Dat::Flingy);
+        self.sprites.install(Dat::Sprites);
+        self.images.install(Dat::Images);
+        self.upgrades.install(Dat::Upgrades);
+        self.techdata.install(Dat::Techdata);
+        self.orders.install(Dat::Orders);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vanilla_sizes() {
+        let files: &[(&[ColumnLayout], &'static str, usize, u32)] = &[
+            (&units::LAYOUT, "units.dat", 19876, 228),
+            (&weapons::LAYOUT, "weapons.dat", 5460, 130),
+            (&flingy::LAYOUT, "flingy.dat", 3135, 209),
+            (&sprites::LAYOUT, "sprites.dat", 3229, 517),
+            (&images::LAYOUT, "images.dat", 37962, 999),
+            (&upgrades::LAYOUT, "upgrades.dat", 1281, 61),
+            (&techdata::LAYOUT, "techdata.dat", 836, 44),
+            (&orders::LAYOUT, "orders.dat", 4158, 189),
+        ];
+        for &(layout, name, size, entries) in files {
+            let file = DatFile::parse(vec![0; size], layout, name).unwrap();
+            assert_eq!(file.entries(), entries, "{}", name);
+            let end = file.tables.iter()
+                .map(|x| x.data as usize + x.entries as usize * x.entry_size as usize)
+                .max()
+                .unwrap();
+            assert_eq!(end, file.data().as_ptr() as usize + size, "{}", name);
+            assert!(DatFile::parse(vec![0; size - 1], layout, name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn extended_units() {
+        let per_entry = units::LAYOUT.iter()
+            .filter(|x| x.count.is_none())
+            .map(|x| x.size as usize)
+            .sum::<usize>();
+        let size = 19876 + (300 - 228) * per_entry;
+        let file = DatFile::parse(vec![0; size], &units::LAYOUT, "units.dat").unwrap();
+        assert_eq!(file.entries(), 300);
+        // The columns that only have entries for vanilla units stay the same.
+        let infested_units = file.tables[units::Column::InfestedUnit as usize].entries;
+        assert_eq!(infested_units, 96);
+    }
+}
@@ -0,0 +1,163 @@
+//! Changing dat entries while the game is running.
+//!
+//! Every change is logged with the value it replaced, so that all of them can be reverted
+//! when the game ends, and the ones made during a game can be written to its saves.
+
+use std::sync::Mutex;
+
+use bw::DatTable;
+use parse::ColumnLayout;
+use tables;
+use {flingy, images, orders, sprites, techdata, units, upgrades, weapons};
+use DatError;
+
+/// The dat file a patch changes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Dat {
+    Units = 0,
+    Weapons = 1,
+    Flingy = 2,
+    Sprites = 3,
+    Images = 4,
+    Upgrades = 5,
+    Techdata = 6,
+    Orders = 7,
+}
+
+impl Dat {
+    pub const ALL: [Dat; 8] = [
+        Dat::Units,
+        Dat::Weapons,
+        Dat::Flingy,
+        Dat::Sprites,
+        Dat::Images,
+        Dat::Upgrades,
+        Dat::Techdata,
+        Dat::Orders,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Dat> {
+        Some(match value {
+            0 => Dat::Units,
+            1 => Dat::Weapons,
+            2 => Dat::Flingy,
+            3 => Dat::Sprites,
+            4 => Dat::Images,
+            5 => Dat::Upgrades,
+            6 => Dat::Techdata,
+            7 => Dat::Orders,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dat::Units => "units.dat",
+            Dat::Weapons => "weapons.dat",
+            Dat::Flingy => "flingy.dat",
+            Dat::Sprites => "sprites.dat",
+            Dat::Images => "images.dat",
+            Dat::Upgrades => "upgrades.dat",
+            Dat::Techdata => "techdata.dat",
+            Dat::Orders => "orders.dat",
+        }
+    }
+
+    pub fn layout(self) -> &'static [ColumnLayout] {
+        match self {
+            Dat::Units => &units::LAYOUT,
+            Dat::Weapons => &weapons::LAYOUT,
+            Dat::Flingy => &flingy::LAYOUT,
+            Dat::Sprites => &sprites::LAYOUT,
+            Dat::Images => &images::LAYOUT,
+            Dat::Upgrades => &upgrades::LAYOUT,
+            Dat::Techdata => &techdata::LAYOUT,
+            Dat::Orders => &orders::LAYOUT,
+        }
+    }
+
+    pub fn column_names(self) -> &'static [&'static str] {
+        match self {
+            Dat::Units => &units::COLUMN_NAMES,
+            Dat::Weapons => &weapons::COLUMN_NAMES,
+            Dat::Flingy => &flingy::COLUMN_NAMES,
+            Dat::Sprites => &sprites::COLUMN_NAMES,
+            Dat::Images => &images::COLUMN_NAMES,
+            Dat::Upgrades => &upgrades::COLUMN_NAMES,
+            Dat::Techdata => &techdata::COLUMN_NAMES,
+            Dat::Orders => &orders::COLUMN_NAMES,
+        }
+    }
+
+    fn tables(self) -> &'static [DatTable] {
+        match self {
+            Dat::Units => tables::units(),
+            Dat::Weapons => tables::weapons(),
+            Dat::Flingy => tables::flingy(),
+            Dat::Sprites => tables::sprites(),
+            Dat::Images => tables::images(),
+            Dat::Upgrades => tables::upgrades(),
+            Dat::Techdata => tables::techdata(),
+            Dat::Orders => tables::orders(),
+        }
+    }
+}
+
+/// Sets the entry of `id` in a column to `value`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Patch {
+    pub dat: Dat,
+    pub column: u8,
+    pub id: u32,
+    pub value: u32,
+}
+
+struct LoggedPatch {
+    patch: Patch,
+    previous: u32,
+}
+
+lazy_static! {
+    static ref PATCHES: Mutex<Vec<LoggedPatch>> = Mutex::new(Vec::new());
+}
+
+fn write(patch: &Patch) -> Result<u32, DatError> {
+    let dat = patch.dat;
+    ::set(dat.tables(), dat.layout(), dat.name(), patch.column as usize, patch.id, patch.value)
+}
+
+/// Writes the value, if the entry exists and the value fits in it.
+pub fn apply(patch: Patch) -> Result<(), DatError> {
+    let mut patches = PATCHES.lock().unwrap();
+    let previous = write(&patch)?;
+    patches.push(LoggedPatch {
+        patch,
+        previous,
+    });
+    Ok(())
+}
+
+/// The patches that have been applied since they were last reverted, in the order they
+/// were applied.
+pub fn patches() -> Vec<Patch> {
+    PATCHES.lock().unwrap().iter().map(|x| x.patch).collect()
+}
+
+/// Restores the values that the patches replaced.
+pub fn revert_patches() {
+    revert_patches_since(0);
+}
+
+/// Restores the values that were replaced after the first `count` patches, keeping those.
+pub fn revert_patches_since(count: usize) {
+    let mut patches = PATCHES.lock().unwrap();
+    let count = count.min(patches.len());
+    for logged in patches.drain(count..).rev() {
+        let patch = Patch {
+            value: logged.previous,
+            ..logged.patch
+        };
+        // Can't fail, as the same entry was written successfully before.
+        let _ = write(&patch);
+    }
+}
@@ -0,0 +1,112 @@
+//! The tables that the accessors read: BW's own ones when running in the game, and the
+//! ones installed with `DatFile::install` otherwise.
+
+#[cfg(feature = "game")]
+mod imp {
+    use bw::{self, DatTable};
+    use parse::DatFile;
+    use patch::Dat;
+
+    // The tables are in BW's memory, so they stay valid for the entire process.
+    macro_rules! bw_tables {
+        ($($name:ident => $var:ident,)*) => {
+            $(pub fn $name() -> &'static [DatTable] {
+                unsafe { &*(&bw::$var[..] as *const [DatTable]) }
+            })*
+        }
+    }
+
+    bw_tables! {
+        units => units_dat,
+        weapons => weapons_dat,
+        flingy => flingy_dat,
+        sprites => sprites_dat,
+        images => images_dat,
+        upgrades => upgrades_dat,
+        techdata => techdata_dat,
+        orders => orders_dat,
+    }
+
+    /// Points BW's tables to the file. The file is leaked, as BW keeps using it for the rest
+    /// of the process.
+    pub fn install(dat: Dat, file: DatFile) {
+        let file: &'static DatFile = Box::leak(Box::new(file));
+        unsafe {
+            let tables = match dat {
+                Dat::Units => &mut bw::units_dat[..],
+                Dat::Weapons => &mut bw::weapons_dat[..],
+                Dat::Flingy => &mut bw::flingy_dat[..],
+                Dat::Sprites => &mut bw::sprites_dat[..],
+                Dat::Images => &mut bw::images_dat[..],
+                Dat::Upgrades => &mut bw::upgrades_dat[..],
+                Dat::Techdata => &mut bw::techdata_dat[..],
+                Dat::Orders => &mut bw::orders_dat[..],
+            };
+            // BW doesn't have tables for some of the last columns, those are just skipped.
+            for (table, new) in tables.iter_mut().zip(&file.tables) {
+                table.data = new.data;
+                table.entry_size = new.entry_size;
+                table.entries = new.entries;
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "game"))]
+mod imp {
+    use std::ptr::null_mut;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+
+    use bw::DatTable;
+    use parse::DatFile;
+    use patch::Dat;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NOT_INSTALLED: AtomicPtr<DatFile> = AtomicPtr::new(null_mut());
+    static INSTALLED: [AtomicPtr<DatFile>; 8] = [NOT_INSTALLED; 8];
+
+    /// The previously installed file is leaked, as the accessors may still be reading it.
+    pub fn install(dat: Dat, file: DatFile) {
+        INSTALLED[dat as usize].swap(Box::into_raw(Box::new(file)), Ordering::AcqRel);
+    }
+
+    /// The installed file, or a file without any entries if there isn't one.
+    fn installed(dat: Dat) -> &'static DatFile {
+        let installed = &INSTALLED[dat as usize];
+        let mut file = installed.load(Ordering::Acquire);
+        if file.is_null() {
+            let empty = Box::into_raw(Box::new(DatFile::empty(dat.layout(), dat.name())));
+            let result =
+                installed.compare_exchange(null_mut(), empty, Ordering::AcqRel, Ordering::Acquire);
+            match result {
+                Ok(_) => file = empty,
+                Err(other) => {
+                    unsafe { drop(Box::from_raw(empty)) };
+                    file = other;
+                }
+            }
+        }
+        unsafe { &*file }
+    }
+
+    macro_rules! installed_tables {
+        ($($name:ident => $dat:ident,)*) => {
+            $(pub fn $name() -> &'static [DatTable] {
+                &installed(Dat::$dat).tables
+            })*
+        }
+    }
+
+    installed_tables! {
+        units => Units,
+        weapons => Weapons,
+        flingy => Flingy,
+        sprites => Sprites,
+        images => Images,
+        upgrades => Upgrades,
+        techdata => Techdata,
+        orders => Orders,
+    }
+}
+
+pub use self::imp::*;
@@ -0,0 +1,301 @@
+//! Prints dat files as CSV or JSON, and compares two versions of a dat file, so that dat
+//! edits can be reviewed as text.
+//!
+//! ```text
+//! dat_tool dump [--json] [--type <dat>] <file>
+//! dat_tool diff [--type <dat>] <old file> <new file>
+//! ```
+//!
+//! The type of the file is determined from its name, e.g. `units.dat`, unless it is given
+//! with `--type units`. `diff` exits with 1 if the files differ, like diff(1).
+
+extern crate bw_dat;
+#[macro_use] extern crate quick_error;
+extern crate serde_json;
+
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use bw_dat::{Dat, DatError, DatFile, ParseError};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Usage(msg: String) {
+            display("{}", msg)
+        }
+        Parse(err: ParseError) {
+            display("{}", err)
+            from()
+        }
+        Dat(err: DatError) {
+            display("{}", err)
+            from()
+        }
+        Json(err: serde_json::Error) {
+            display("JSON error: {}", err)
+            from()
+        }
+        Io(err: io::Error) {
+            display("I/O error: {}", err)
+            from()
+        }
+    }
+}
+
+const USAGE: &str = "\
+Usage:
+    dat_tool dump [--json] [--type <dat>] <file>
+    dat_tool diff [--type <dat>] <old file> <new file>";
+
+/// A single entry of a column.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    Number(u32),
+    /// Columns that are several u16s per entry, e.g. dimension boxes.
+    Words(Vec<u16>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Number(x) => write!(f, "{}", x),
+            Value::Words(ref words) => {
+                for (i, x) in words.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Value {
+    fn to_json(&self) -> serde_json::Value {
+        match *self {
+            Value::Number(x) => x.into(),
+            Value::Words(ref words) => words.iter().map(|&x| serde_json::Value::from(x)).collect(),
+        }
+    }
+}
+
+/// Units.dat has two columns which are a pair of u16s, even though they fit in an u32.
+fn is_word_column(dat: Dat, name: &str, size: u8) -> bool {
+    size > 4 || (dat == Dat::Units && (name == "placement_box" || name == "addon_position"))
+}
+
+/// Returns `None` for ids that the column has no entry for.
+fn value(file: &DatFile, dat: Dat, column: usize, id: u32) -> Result<Option<Value>, DatError> {
+    let name = dat.column_names()[column];
+    let size = file.layout()[column].size;
+    let result = if is_word_column(dat, name, size) {
+        file.raw(column, id).map(|bytes| {
+            let words = bytes.chunks(2).map(|x| x[0] as u16 | (x[1] as u16) << 8).collect();
+            Value::Words(words)
+        })
+    } else {
+        file.get(column, id).map(Value::Number)
+    };
+    match result {
+        Ok(o) => Ok(Some(o)),
+        Err(DatError::NoEntry(..)) | Err(DatError::InvalidId(..)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn dat_from_name(name: &str) -> Option<Dat> {
+    let name = name.to_ascii_lowercase();
+    let name = name.trim_end_matches(".dat");
+    Dat::ALL.iter().cloned().find(|x| x.name().trim_end_matches(".dat") == name)
+}
+
+fn load(path: &str, dat: Option<Dat>) -> Result<(Dat, DatFile), Error> {
+    let dat = match dat {
+        Some(s) => s,
+        None => {
+            let name = Path::new(path).file_name().and_then(|x| x.to_str()).unwrap_or("");
+            dat_from_name(name).ok_or_else(|| {
+                Error::Usage(format!("Can't tell the type of {}, use --type", path))
+            })?
+        }
+    };
+    let file = DatFile::from_file(Path::new(path), dat.layout(), dat.name())?;
+    Ok((dat, file))
+}
+
+fn write_csv<W: Write>(out: &mut W, dat: Dat, file: &DatFile) -> Result<(), Error> {
+    let names = dat.column_names();
+    writeln!(out, "id,{}", names.join(","))?;
+    for id in 0..file.entries() {
+        write!(out, "{}", id)?;
+        for column in 0..names.len() {
+            match value(file, dat, column, id)? {
+                Some(value) => write!(out, ",{}", value)?,
+                None => write!(out, ",")?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn write_json<W: Write>(out: &mut W, dat: Dat, file: &DatFile) -> Result<(), Error> {
+    let names = dat.column_names();
+    let mut entries = Vec::with_capacity(file.entries() as usize);
+    for id in 0..file.entries() {
+        let mut entry = serde_json::Map::new();
+        entry.insert("id".into(), id.into());
+        for (column, &name) in names.iter().enumerate() {
+            let value = value(file, dat, column, id)?;
+            let json = value.map(|x| x.to_json()).unwrap_or(serde_json::Value::Null);
+            entry.insert(name.into(), json);
+        }
+        entries.push(serde_json::Value::Object(entry));
+    }
+    serde_json::to_writer_pretty(&mut *out, &entries)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Change {
+    id: u32,
+    column: usize,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+/// Every entry that differs between the files, ordered by id. Entries that only one of
+/// the files has show up with the other side as `None`.
+fn diff(dat: Dat, old: &DatFile, new: &DatFile) -> Result<Vec<Change>, Error> {
+    let mut changes = Vec::new();
+    for id in 0..old.entries().max(new.entries()) {
+        for column in 0..dat.column_names().len() {
+            let old_value = value(old, dat, column, id)?;
+            let new_value = value(new, dat, column, id)?;
+            if old_value != new_value {
+                changes.push(Change {
+                    id,
+                    column,
+                    old: old_value,
+                    new: new_value,
+                });
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn write_diff<W: Write>(out: &mut W, dat: Dat, changes: &[Change]) -> Result<(), Error> {
+    let names = dat.column_names();
+    let format = |x: &Option<Value>| x.as_ref().map(|x| x.to_string()).unwrap_or("-".into());
+    let mut previous_id = None;
+    for change in changes {
+        if previous_id != Some(change.id) {
+            writeln!(out, "{} {}:", dat.name(), change.id)?;
+            previous_id = Some(change.id);
+        }
+        let name = names[change.column];
+        writeln!(out, "    {}: {} -> {}", name, format(&change.old), format(&change.new))?;
+    }
+    Ok(())
+}
+
+/// Returns the exit code.
+fn run(args: &[String]) -> Result<i32, Error> {
+    let mut json = false;
+    let mut dat = None;
+    let mut free = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match &**arg {
+            "--json" => json = true,
+            "--type" => {
+                let name = args.next()
+                    .ok_or_else(|| Error::Usage("--type needs a value".into()))?;
+                dat = Some(dat_from_name(name).ok_or_else(|| {
+                    Error::Usage(format!("Unknown dat type {}", name))
+                })?);
+            }
+            _ => free.push(&**arg),
+        }
+    }
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match &free[..] {
+        ["dump", path] => {
+            let (dat, file) = load(path, dat)?;
+            if json {
+                write_json(&mut out, dat, &file)?;
+            } else {
+                write_csv(&mut out, dat, &file)?;
+            }
+            out.flush()?;
+            Ok(0)
+        }
+        ["diff", old, new] => {
+            let (dat, old) = load(old, dat)?;
+            let (_, new) = load(new, Some(dat))?;
+            let changes = diff(dat, &old, &new)?;
+            write_diff(&mut out, dat, &changes)?;
+            out.flush()?;
+            Ok(if changes.is_empty() { 0 } else { 1 })
+        }
+        _ => Err(Error::Usage(USAGE.into())),
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bw_dat::{weapons, Dat, DatFile};
+
+    use super::{diff, write_diff, Value};
+
+    const WEAPONS: usize = 130;
+
+    fn weapons_file(damage: &[(usize, u16)]) -> DatFile {
+        let layout = &weapons::LAYOUT;
+        let mut data = vec![0; layout.iter().map(|x| x.size as usize * WEAPONS).sum()];
+        let column = weapons::Column::Damage as usize;
+        let offset = layout[..column].iter().map(|x| x.size as usize * WEAPONS).sum::<usize>();
+        for &(id, value) in damage {
+            data[offset + id * 2] = value as u8;
+            data[offset + id * 2 + 1] = (value >> 8) as u8;
+        }
+        DatFile::parse(data, layout, "weapons.dat").unwrap()
+    }
+
+    #[test]
+    fn damage_change() {
+        let old = weapons_file(&[(3, 20), (7, 0x100)]);
+        let new = weapons_file(&[(3, 25), (7, 0x100)]);
+        let changes = diff(Dat::Weapons, &old, &new).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].id, 3);
+        assert_eq!(changes[0].column, weapons::Column::Damage as usize);
+        assert_eq!(changes[0].old, Some(Value::Number(20)));
+        assert_eq!(changes[0].new, Some(Value::Number(25)));
+
+        let mut out = Vec::new();
+        write_diff(&mut out, Dat::Weapons, &changes).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "weapons.dat 3:\n    damage: 20 -> 25\n");
+        assert!(diff(Dat::Weapons, &old, &old).unwrap().is_empty());
+    }
+}
@@ -1,27 +1,28 @@
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::mem;
 use std::ptr::null_mut;
 
 use bincode;
-use flate2;
+#[cfg(feature = "game")]
 use libc::c_void;
 
 use bw;
-use entity_serialize::{self, deserialize_entity, entity_serializable, EntitySerializable};
+use entity_serialize::{self, deserialize_entity, entity_serializable};
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
 use units::{unit_to_id, unit_from_id};
-use save::{fread, fwrite, fread_num, fwrite_num, SaveError, LoadError, print_text};
+use save::{ErrorContext, SaveError, LoadError};
+#[cfg(feature = "game")]
+use save::{fwrite_compressed, fwrite_num, read_chunk, print_text, show_load_error, BwFile};
 use save::{SaveMapping, LoadMapping};
-use send_pointer::SendPtr;
-
-ome2_thread_local! {
-    BULLETS: RefCell<HashSet<SendPtr<bw::Bullet>>> =
-        all_bullets(RefCell::new(HashSet::new()));
-}
-
-const BULLET_SAVE_MAGIC: u16 = 0xffed;
-// 8 megabytes, should be more than enough, both compressed and without.
-const BULLET_SAVE_MAX_SIZE: u32 = 0x800000;
+use save_format::{self, BulletGlobals, BulletSerializable};
+#[cfg(feature = "game")]
+use save_format::{BULLET_SAVE_MAGIC, BULLET_SAVE_VERSION};
+use save_format::BULLET_SAVE_MAX_SIZE;
+#[cfg(feature = "game")]
+use state::Chunk;
+use state::{plugin_state, PluginState};
 
 impl entity_serialize::SaveEntityPointer for SaveMapping<bw::Bullet> {
     type Pointer = bw::Bullet;
@@ -37,6 +38,7 @@ impl entity_serialize::LoadEntityPointer for LoadMapping<bw::Bullet> {
     }
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn create_bullet(
     parent: *mut bw::Unit,
     bullet_id: u32,
@@ -71,11 +73,12 @@ pub unsafe fn create_bullet(
             actual_bullet
         );
     }
-    let mut bullets = all_bullets().borrow_mut();
-    bullets.insert(bullet.into());
+    let mut state = plugin_state().borrow_mut();
+    state.bullets.insert(bullet.into());
     bullet
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn delete_bullet(bullet: *mut bw::Bullet, orig: unsafe extern fn(*mut bw::Bullet)) {
     if (*bullet).entity.sprite == null_mut() {
         // Have to call orig to remove the bullet from active bullet list
@@ -83,87 +86,73 @@ pub unsafe fn delete_bullet(bullet: *mut bw::Bullet, orig: unsafe extern fn(*mut
         *bw::last_free_bullet = null_mut();
         orig(bullet);
         let _ = Box::from_raw(bullet);
-        let mut bullets = all_bullets().borrow_mut();
-        bullets.remove(&bullet.into());
+        let mut state = plugin_state().borrow_mut();
+        state.bullets.remove(&bullet.into());
     }
 }
 
-pub unsafe fn delete_all() {
-    let mut bullets = all_bullets().borrow_mut();
-    for bullet in bullets.iter() {
-        let _ = Box::from_raw(**bullet);
+pub unsafe fn delete_all<M: GameMemory>(game: &M, state: &mut PluginState) {
+    for bullet in state.bullets.drain() {
+        let _ = Box::from_raw(*bullet);
     }
-    bullets.clear();
     // Not sure if these are necessary, but doing this won't hurt either
-    *bw::first_active_bullet = null_mut();
-    *bw::last_active_bullet = null_mut();
-    *bw::first_free_bullet = null_mut();
-    *bw::last_free_bullet = null_mut();
-}
-
-#[derive(Serialize, Deserialize)]
-struct SaveGlobals {
-    first_bullet: u32,
-    last_bullet: u32,
-    bullet_count: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct BulletSerializable {
-    entity: EntitySerializable,
-    weapon_id: u8,
-    death_timer: u8,
-    flags: u8,
-    bounces_remaining: u8,
-    parent: u16,
-    previous_bounce_target: u16,
-    spread_seed: u8,
+    *game.first_active_bullet() = null_mut();
+    *game.last_active_bullet() = null_mut();
+    *game.first_free_bullet() = null_mut();
+    *game.last_free_bullet() = null_mut();
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn save_bullet_chunk(file: *mut c_void) -> u32 {
-    if let Err(e) = save_bullets(file) {
-        error!("Couldn't save bullets: {}", e);
+    if let Err(e) = save_bullets(file).context(|| "Bullets") {
+        plugin_state().borrow_mut().save_failed();
+        error!("Couldn't save: {}", e);
         print_text(&format!("Unable to save the game: {}", e));
         return 0;
     }
+    plugin_state().borrow_mut().chunk_saved(Chunk::Bullets);
     1
 }
 
+#[cfg(feature = "game")]
 unsafe fn save_bullets(file: *mut c_void) -> Result<(), SaveError> {
-    let data = serialize_bullets()?;
+    plugin_state().borrow_mut().begin_save(Chunk::Bullets)?;
+    let data = serialize_bullets(&BwMemory)?;
     fwrite_num(file, BULLET_SAVE_MAGIC)?;
-    fwrite_num(file, 1u32)?;
-    fwrite_num(file, data.len() as u32)?;
-    fwrite(file, &data)?;
+    fwrite_num(file, BULLET_SAVE_VERSION)?;
+    fwrite_compressed(file, data)?;
     Ok(())
 }
 
-unsafe fn serialize_bullets() -> Result<Vec<u8>, SaveError> {
-    let ptr_to_id_map = bullet_pointer_to_id_map();
-    let buf = Vec::with_capacity(0x10000);
-    let mut writer = flate2::write::DeflateEncoder::new(buf, flate2::Compression::Default);
+pub unsafe fn serialize_bullets<M: GameMemory>(game: &M) -> Result<Vec<u8>, SaveError> {
+    let ptr_to_id_map = bullet_pointer_to_id_map(game)?;
+    let mut buf = Vec::with_capacity(0x10000);
 
     let size_limit = bincode::Bounded(BULLET_SAVE_MAX_SIZE as u64);
-    let globals = SaveGlobals {
-        first_bullet: ptr_to_id_map.id(*bw::first_active_bullet)?,
-        last_bullet: ptr_to_id_map.id(*bw::last_active_bullet)?,
+    let globals = BulletGlobals {
+        first_bullet: ptr_to_id_map.id(*game.first_active_bullet()).context(|| "first_bullet")?,
+        last_bullet: ptr_to_id_map.id(*game.last_active_bullet()).context(|| "last_bullet")?,
         bullet_count: ptr_to_id_map.len() as u32,
     };
-    bincode::serialize_into(&mut writer, &globals, size_limit)?;
-    let mut bullet = *bw::first_active_bullet;
+    bincode::serialize_into(&mut buf, &plugin_state().borrow().save_header(), size_limit)?;
+    bincode::serialize_into(&mut buf, &globals, size_limit)?;
+    let mut bullet = *game.first_active_bullet();
+    let mut id = 1;
     while bullet != null_mut() {
-        let serializable = bullet_serializable(bullet, &ptr_to_id_map)?;
-        bincode::serialize_into(&mut writer, &serializable, size_limit)?;
+        let serializable = bullet_serializable(game, bullet, &ptr_to_id_map)
+            .context(|| format!("Bullet {}", id))?;
+        bincode::serialize_into(&mut buf, &serializable, size_limit)?;
         bullet = (*bullet).entity.next as *mut bw::Bullet;
-        if writer.total_in() > BULLET_SAVE_MAX_SIZE as u64{
-            return Err(SaveError::SizeLimit(writer.total_in()));
+        id += 1;
+        if buf.len() > BULLET_SAVE_MAX_SIZE as usize {
+            return Err(SaveError::SizeLimit(buf.len() as u64));
         }
-        // Could also check total out but it should be lower..
     }
-    Ok(writer.finish()?)
+    Ok(buf)
 }
 
-unsafe fn bullet_serializable(
+pub unsafe fn bullet_serializable<M: GameMemory>(
+    game: &M,
     bullet: *const bw::Bullet,
     mapping: &SaveMapping<bw::Bullet>,
 ) -> Result<BulletSerializable, SaveError> {
@@ -179,18 +168,20 @@ unsafe fn bullet_serializable(
         padding6d: _,
     } = *bullet;
     Ok(BulletSerializable {
-        entity: entity_serializable(entity, mapping)?,
+        entity: entity_serializable(game, entity, mapping).context(|| "entity")?,
         weapon_id,
         death_timer,
         flags,
         bounces_remaining,
-        parent: unit_to_id(parent),
-        previous_bounce_target: unit_to_id(previous_bounce_target),
+        parent: unit_to_id(game, parent).context(|| "parent")?,
+        previous_bounce_target: unit_to_id(game, previous_bounce_target)
+            .context(|| "previous_bounce_target")?,
         spread_seed,
     })
 }
 
-fn deserialize_bullet(
+fn deserialize_bullet<M: GameMemory>(
+    game: &M,
     bullet: &BulletSerializable,
     mapping: &LoadMapping<bw::Bullet>,
 ) -> Result<bw::Bullet, LoadError> {
@@ -205,83 +196,86 @@ fn deserialize_bullet(
         spread_seed,
     } = *bullet;
     Ok(bw::Bullet {
-        entity: deserialize_entity(entity, mapping)?,
+        entity: deserialize_entity(game, entity, mapping).context(|| "entity")?,
         weapon_id,
         death_timer,
         flags,
         bounces_remaining,
-        parent: unit_from_id(parent)?,
-        previous_bounce_target: unit_from_id(previous_bounce_target)?,
+        parent: unit_from_id(game, parent).context(|| "parent")?,
+        previous_bounce_target: unit_from_id(game, previous_bounce_target)
+            .context(|| "previous_bounce_target")?,
         spread_seed,
         padding6d: [0; 3],
     })
 }
 
-unsafe fn bullet_pointer_to_id_map() -> SaveMapping<bw::Bullet> {
+pub unsafe fn bullet_pointer_to_id_map<M: GameMemory>(
+    game: &M,
+) -> Result<SaveMapping<bw::Bullet>, SaveError> {
     let mut id = 1;
-    let mut bullet = *bw::first_active_bullet;
+    let mut bullet = *game.first_active_bullet();
     let mut ret = HashMap::new();
     while bullet != null_mut() {
         let old = ret.insert(bullet.into(), id);
-        assert!(old.is_none());
+        if old.is_some() {
+            // The list loops back to an earlier bullet.
+            return Err(SaveError::InvalidPointer).context(|| format!("Bullet {}", id));
+        }
         bullet = (*bullet).entity.next as *mut bw::Bullet;
         id += 1;
     }
-    SaveMapping(ret)
+    Ok(SaveMapping(ret))
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn load_bullet_chunk(file: *mut c_void, save_version: u32) -> u32 {
     if save_version != 3 {
         error!("Unusupported save version: {}", save_version);
+        plugin_state().borrow_mut().load_failed();
         return 0;
     }
-    if let Err(e) = load_bullets(file) {
-        info!("Couldn't load a save: {}", e);
+    if let Err(e) = read_bullets(file).context(|| "Bullets") {
+        error!("Couldn't load a save: {}", e);
+        show_load_error(&e);
+        plugin_state().borrow_mut().load_failed();
         return 0;
     }
+    plugin_state().borrow_mut().chunk_loaded(Chunk::Bullets);
     1
 }
 
-unsafe fn load_bullets(file: *mut c_void) -> Result<(), LoadError> {
-    let magic = fread_num::<u16>(file)?;
-    if magic != BULLET_SAVE_MAGIC {
-        return Err(LoadError::WrongMagic(magic));
-    }
-    let version = fread_num::<u32>(file)?;
-    if version != 1 {
-        return Err(LoadError::Version(version));
-    }
-    let size = fread_num::<u32>(file)?;
-    if size > BULLET_SAVE_MAX_SIZE {
-        return Err(LoadError::Corrupted(format!("Bullet chunk size {} is too large", size)));
-    }
-    let data = fread(file, size)?;
-    let mut reader = flate2::read::DeflateDecoder::new(&data[..]);
-
+#[cfg(feature = "game")]
+unsafe fn read_bullets(file: *mut c_void) -> Result<(), LoadError> {
+    let data =
+        read_chunk(BwFile(file), BULLET_SAVE_MAGIC, BULLET_SAVE_VERSION, BULLET_SAVE_MAX_SIZE)?;
+    load_bullets(&BwMemory, &data)
+}
 
-    let size_limit = bincode::Bounded(BULLET_SAVE_MAX_SIZE as u64);
-    let globals: SaveGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
-    let (mut bullets, mapping) = allocate_bullets(globals.bullet_count);
-    for bullet in &mut bullets {
-        let serialized = bincode::deserialize_from(&mut reader, size_limit)?;
-        **bullet = deserialize_bullet(&serialized, &mapping)?;
-        if reader.total_out() > BULLET_SAVE_MAX_SIZE as u64 {
-            return Err(LoadError::SizeLimit)
-        }
+/// Loads the decompressed bullet chunk.
+pub unsafe fn load_bullets<M: GameMemory>(game: &M, data: &[u8]) -> Result<(), LoadError> {
+    let chunk = save_format::decode_bullets(data)?;
+    let globals = chunk.globals;
+    plugin_state().borrow_mut().begin_chunk(&chunk.header)?;
+    let (mut bullets, mapping) = allocate_bullets(chunk.bullets.len());
+    for (i, (bullet, serialized)) in bullets.iter_mut().zip(chunk.bullets.iter()).enumerate() {
+        **bullet = deserialize_bullet(game, serialized, &mapping)
+            .context(|| format!("Bullet {}", i + 1))?;
     }
-    let mut bullet_set = all_bullets().borrow_mut();
+    let first = mapping.pointer(globals.first_bullet).context(|| "first_bullet")?;
+    let last = mapping.pointer(globals.last_bullet).context(|| "last_bullet")?;
+    let mut state = plugin_state().borrow_mut();
     for bullet in bullets {
-        bullet_set.insert(Box::into_raw(bullet).into());
+        state.bullets.insert(Box::into_raw(bullet).into());
     }
-    *bw::first_active_bullet = mapping.pointer(globals.first_bullet)?;
-    *bw::last_active_bullet = mapping.pointer(globals.last_bullet)?;
+    *game.first_active_bullet() = first;
+    *game.last_active_bullet() = last;
     Ok(())
 }
 
 // Returning the pointer vector isn't really necessary, just simpler. Could also create a
 // vector abstraction that allows reading addresses of any Bullet while holding a &mut reference
 // to one of them.
-fn allocate_bullets(count: u32) -> (Vec<Box<bw::Bullet>>, LoadMapping<bw::Bullet>) {
+fn allocate_bullets(count: usize) -> (Vec<Box<bw::Bullet>>, LoadMapping<bw::Bullet>) {
     (0..count).map(|_| {
         let mut bullet = Box::new(unsafe { mem::zeroed() });
         let pointer: *mut bw::Bullet = &mut *bullet;
@@ -1,17 +1,23 @@
 #![allow(non_upper_case_globals)]
 
+#[cfg(feature = "game")]
 use libc::c_void;
 
 pub mod structs;
 
 pub use self::structs::*;
 
+#[cfg(feature = "game")]
 whack_hooks!(stdcall, 0x00400000,
     0x0048C260 => CreateBullet(@eax *mut Unit, @ecx u32, u32, u32, u32, u32) -> *mut Bullet;
     0x0048A560 => DeleteBullet(@eax *mut Bullet);
     0x0048AEB0 => SaveBulletChunk(*mut c_void) -> u32;
     0x0048AE40 => LoadBulletChunk(@edx *mut c_void, u32) -> u32;
+    // InitGame in 1.16.1, which sets up a new or loaded game once the map has been loaded,
+    // before the first frame is run.
+    0x004EEE00 => InitGame();
     0x004EE8C0 => GameEnd();
+    0x004D94B0 => StepObjects();
 
     0x004990F0 => CreateSprite(u32, u32, @edi u32, u32) -> *mut Sprite;
     0x00497B40 => DeleteSprite(@edi *mut Sprite);
@@ -38,6 +44,7 @@ whack_hooks!(stdcall, 0x00400000,
     0x0049B130 => LoadMapPlayerColors(*const u8, @eax u32);
 );
 
+#[cfg(feature = "game")]
 whack_funcs!(stdcall, init_funcs, 0x00400000,
     0x0048D1C0 => print_text(*const u8, u32, @eax u32);
     0x00498C50 => draw_sprite(@eax *mut Sprite);
@@ -48,11 +55,13 @@ whack_funcs!(stdcall, init_funcs, 0x00400000,
     0x0046A3A0 => add_to_pos_search(@esi *mut Unit);
 );
 
+#[cfg(feature = "game")]
 whack_funcs!(init_funcs_cdecl, 0x00400000,
     0x004117DE => fread(*mut c_void, u32, u32, *mut c_void) -> i32;
     0x00411931 => fwrite(*const c_void, u32, u32, *mut c_void) -> i32;
 );
 
+#[cfg(feature = "game")]
 whack_vars!(init_vars, 0x00400000,
     0x0064EED8 => first_free_bullet: *mut Bullet;
     0x0064EEDC => last_free_bullet: *mut Bullet;
@@ -114,7 +123,9 @@ whack_vars!(init_vars, 0x00400000,
 
     0x00581DD6 => player_minimap_color: [u8; 0xc];
     0x00581D76 => player_color_palette: [[u8; 0x8]; 0xc];
-    0x00596904 => scmain_state: u32;
+    0x00628448 => screen_x: u32;
+    0x00628470 => screen_y: u32;
+    0x0057F23C => frame_count: u32;
 );
 
 pub const TooltipSurfaceHeight: usize = 0x00481359;
@@ -122,6 +133,7 @@ pub const TooltipSurfaceBytes: usize = 0x0048133F;
 pub const TooltipTextSurfaceHeight: usize = 0x0048137C;
 pub const TooltipTextSurfaceBytes: usize = 0x0048136C;
 
+#[cfg(feature = "game")]
 pub mod storm {
     whack_funcs!(stdcall, init_funcs, 0x15000000,
         0x15027760 =>
@@ -1,6 +1,24 @@
 use libc::c_void;
 
-pub struct GrpSprite;
+// BW's image functions use fastcall, which only exists on 32-bit x86. Other targets just
+// need the fields to exist, so that the save format can be built for fuzzing.
+#[cfg(target_arch = "x86")]
+pub type ImageDrawFn =
+    unsafe extern "fastcall" fn(u32, u32, *const c_void, *const u16, *mut c_void);
+#[cfg(target_arch = "x86")]
+pub type ImageStepFrameFn = unsafe extern "fastcall" fn(*mut Image);
+#[cfg(not(target_arch = "x86"))]
+pub type ImageDrawFn =
+    unsafe extern "C" fn(u32, u32, *const c_void, *const u16, *mut c_void);
+#[cfg(not(target_arch = "x86"))]
+pub type ImageStepFrameFn = unsafe extern "C" fn(*mut Image);
+
+#[repr(C)]
+pub struct GrpSprite {
+    pub frame_count: u16,
+    pub width: u16,
+    pub height: u16,
+}
 
 #[repr(C, packed)]
 pub struct RemapPalette {
@@ -37,22 +55,22 @@ pub struct Image {
     pub grp_bounds: [i16; 4],
     pub grp: *mut GrpSprite,
     pub drawfunc_param: *mut c_void,
-    pub draw: Option<unsafe extern "fastcall" fn(u32, u32, *const c_void, *const u16, *mut c_void)>,
-    pub step_frame: Option<unsafe extern "fastcall" fn(*mut Image)>,
+    pub draw: Option<ImageDrawFn>,
+    pub step_frame: Option<ImageStepFrameFn>,
     pub parent: *mut Sprite,
 }
 
 #[repr(C)]
 pub struct ImageDraw {
     pub id: u32,
-    pub normal: Option<unsafe extern "fastcall" fn(u32, u32, *const c_void, *const u16, *mut c_void)>,
-    pub flipped: Option<unsafe extern "fastcall" fn(u32, u32, *const c_void, *const u16, *mut c_void)>,
+    pub normal: Option<ImageDrawFn>,
+    pub flipped: Option<ImageDrawFn>,
 }
 
 #[repr(C)]
 pub struct ImageStepFrame {
     pub id: u32,
-    pub func: Option<unsafe extern "fastcall" fn(*mut Image)>,
+    pub func: Option<ImageStepFrameFn>,
 }
 
 #[repr(C)]
@@ -299,7 +317,7 @@ pub struct UnitSpells {
     pub acid_spore_timers: [u8; 0x9],
 }
 
-#[cfg(test)]
+#[cfg(all(test, target_pointer_width = "32"))]
 mod test {
     use super::*;
     #[test]
@@ -0,0 +1,129 @@
+//! Settings read from `more_bullets_yay.ini` in the current directory.
+//!
+//! The file is optional, and any missing or invalid value uses the default.
+//!
+//! ```ini
+//! ; One of none, fast, default, best
+//! compression = fast
+//! ; Log a hash of the game state every 24 frames, 0 disables hashing
+//! hash_interval = 24
+//! ; Also write the hashes to a file, which gets replaced when a game starts
+//! hash_file = hashes.txt
+//! ; Directory of dat files that replace the ones BW has loaded, any file that isn't there
+//! ; is kept as it is
+//! dat_dir = dat
+//! ; Directory of grps for images past BW's grp table, named <image id>.grp
+//! grp_dir = grp
+//! ; Remap palettes to load in addition to BW's, from game\<name>.pcx in the MPQs
+//! remap_palettes = cfire, pfire
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use flate2::Compression;
+
+const CONFIG_FILE: &str = "more_bullets_yay.ini";
+
+lazy_static! {
+    static ref CONFIG: Config = Config::load();
+}
+
+pub fn config() -> &'static Config {
+    &CONFIG
+}
+
+pub struct Config {
+    /// Compression level for the chunks saved by the plugin. The game is paused until each
+    /// chunk has been compressed, so a faster level shortens the pause on large saves.
+    pub compression: Compression,
+    /// How often the game state gets hashed, in frames. 0 if it never is.
+    pub hash_interval: u32,
+    pub hash_file: Option<String>,
+    pub dat_dir: Option<String>,
+    pub grp_dir: Option<String>,
+    pub remap_palettes: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            compression: Compression::Default,
+            hash_interval: 0,
+            hash_file: None,
+            dat_dir: None,
+            grp_dir: None,
+            remap_palettes: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    fn load() -> Config {
+        let mut text = String::new();
+        let result = File::open(CONFIG_FILE).and_then(|mut f| f.read_to_string(&mut text));
+        match result {
+            Ok(_) => Config::parse(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => {
+                warn!("Couldn't read {}: {}", CONFIG_FILE, e);
+                Config::default()
+            }
+        }
+    }
+
+    fn parse(text: &str) -> Config {
+        let mut config = Config::default();
+        for line in text.lines().map(|x| x.trim()) {
+            if line.is_empty() || line.starts_with(';') || line.starts_with('[') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=').map(|x| x.trim());
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => {
+                    warn!("Invalid line in {}: {}", CONFIG_FILE, line);
+                    continue;
+                }
+            };
+            match key {
+                "compression" => match parse_compression(value) {
+                    Some(s) => {
+                        info!("Using compression level {}", value);
+                        config.compression = s;
+                    }
+                    None => warn!("Invalid compression level {}", value),
+                },
+                "hash_interval" => match value.parse() {
+                    Ok(o) => {
+                        info!("Hashing the game state every {} frames", o);
+                        config.hash_interval = o;
+                    }
+                    Err(_) => warn!("Invalid hash interval {}", value),
+                },
+                "hash_file" => config.hash_file = Some(value.into()),
+                "dat_dir" => config.dat_dir = Some(value.into()),
+                "grp_dir" => config.grp_dir = Some(value.into()),
+                "remap_palettes" => {
+                    config.remap_palettes = value.split(',')
+                        .map(|x| x.trim())
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.into())
+                        .collect();
+                }
+                _ => warn!("Unknown setting {} in {}", key, CONFIG_FILE),
+            }
+        }
+        config
+    }
+}
+
+fn parse_compression(value: &str) -> Option<Compression> {
+    match &*value.to_ascii_lowercase() {
+        "none" => Some(Compression::None),
+        "fast" => Some(Compression::Fast),
+        "default" => Some(Compression::Default),
+        "best" => Some(Compression::Best),
+        _ => None,
+    }
+}
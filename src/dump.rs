@@ -0,0 +1,192 @@
+//! Writing the game state as JSON, so that bug reports can include what was alive at the time.
+//!
+//! The state is serialized the same way as it is saved, so pointers show up as the same ids
+//! that the save chunks use.
+//!
+//! A dump can be written with Ctrl+F12, or by other plugins with `DumpGameState`. There is
+//! no chat command, as the plugin doesn't hook BW's chat input, and a hook for it would
+//! only be useful for this.
+
+#[cfg(feature = "game")]
+use std::ffi::CStr;
+#[cfg(feature = "game")]
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(feature = "game")]
+use std::io::BufWriter;
+#[cfg(feature = "game")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "game")]
+use libc::{c_char, c_void};
+use serde_json;
+
+use bullets;
+#[cfg(feature = "game")]
+use bw;
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
+use save::{LoadError, SaveError};
+#[cfg(feature = "game")]
+use save::print_text;
+use save_format::{self, BulletChunk, SpriteChunk, UnitChunk};
+use sprites;
+use units;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum DumpError {
+        Save(err: SaveError) {
+            display("{}", err)
+            from()
+        }
+        Decode(err: LoadError) {
+            display("Internal error: Couldn't decode the serialized state: {}", err)
+            from()
+        }
+        Json(err: serde_json::Error) {
+            display("JSON error: {}", err)
+            from()
+        }
+        Io(err: io::Error) {
+            display("I/O error: {}", err)
+            from()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GameDump {
+    sprites: SpriteChunk,
+    units: UnitChunk,
+    bullets: BulletChunk,
+}
+
+pub unsafe fn dump_game<M: GameMemory, W: Write>(game: &M, out: W) -> Result<(), DumpError> {
+    let dump = GameDump {
+        sprites: save_format::decode_sprites(&sprites::serialize_sprites(game)?)?,
+        units: save_format::decode_units(&units::serialize_units(game)?)?,
+        bullets: save_format::decode_bullets(&bullets::serialize_bullets(game)?)?,
+    };
+    serde_json::to_writer_pretty(out, &dump)?;
+    Ok(())
+}
+
+#[cfg(feature = "game")]
+unsafe fn dump_game_to_file(filename: &str) -> Result<(), DumpError> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    dump_game(&BwMemory, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes the game state to a file. Has to be called from the game thread while a game
+/// is running.
+///
+/// Returns 1 on success, and 0 if the state couldn't be written, in which case the reason
+/// is logged.
+#[cfg(feature = "game")]
+#[no_mangle]
+pub unsafe extern fn DumpGameState(filename: *const c_char) -> u32 {
+    if filename.is_null() {
+        error!("Dump filename is null");
+        return 0;
+    }
+    let filename = match CStr::from_ptr(filename).to_str() {
+        Ok(o) => o,
+        Err(_) => {
+            error!("Dump filename is not valid UTF-8");
+            return 0;
+        }
+    };
+    match dump_game_to_file(filename) {
+        Ok(()) => 1,
+        Err(e) => {
+            error!("Couldn't dump the game state to {}: {}", filename, e);
+            0
+        }
+    }
+}
+
+#[cfg(feature = "game")]
+#[link(name = "user32")]
+extern "system" {
+    fn GetAsyncKeyState(key: i32) -> i16;
+    fn GetForegroundWindow() -> *mut c_void;
+    fn GetWindowThreadProcessId(hwnd: *mut c_void, process_id: *mut u32) -> u32;
+}
+
+#[cfg(feature = "game")]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentProcessId() -> u32;
+}
+
+#[cfg(feature = "game")]
+const VK_CONTROL: i32 = 0x11;
+#[cfg(feature = "game")]
+const VK_F12: i32 = 0x7b;
+
+#[cfg(feature = "game")]
+static HOTKEY_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// GetAsyncKeyState sees the keys pressed in any program, so the hotkey only counts while
+/// one of BW's windows is focused.
+#[cfg(feature = "game")]
+unsafe fn is_focused() -> bool {
+    let window = GetForegroundWindow();
+    if window.is_null() {
+        return false;
+    }
+    let mut process_id = 0;
+    GetWindowThreadProcessId(window, &mut process_id);
+    process_id == GetCurrentProcessId()
+}
+
+/// Called every frame, writes the game state to `more_bullets_yay_<frame>.json` once
+/// Ctrl+F12 gets pressed.
+#[cfg(feature = "game")]
+pub unsafe fn check_hotkey() {
+    let down = GetAsyncKeyState(VK_CONTROL) < 0 && GetAsyncKeyState(VK_F12) < 0 &&
+        is_focused();
+    let was_down = HOTKEY_DOWN.swap(down, Ordering::Relaxed);
+    if !down || was_down {
+        return;
+    }
+    let filename = format!("more_bullets_yay_{}.json", *bw::frame_count);
+    match dump_game_to_file(&filename) {
+        Ok(()) => print_text(&format!("Wrote the game state to {}", filename)),
+        Err(e) => {
+            error!("Couldn't dump the game state to {}: {}", filename, e);
+            print_text(&format!("Couldn't dump the game state: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+
+    use game_memory::test::test_game;
+    use units;
+
+    use super::dump_game;
+
+    #[test]
+    fn dump_json() {
+        unsafe {
+            let test = test_game(0x1234_5678);
+            let live_units = units::free_unit_slots(&test.game).unwrap().iter()
+                .filter(|&&free| !free)
+                .count();
+            let mut json = Vec::new();
+            dump_game(&test.game, &mut json).unwrap();
+
+            let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+            assert_eq!(json["units"]["units"].as_array().unwrap().len(), live_units);
+            let sprites = json["sprites"]["sprites"].as_array().unwrap();
+            assert!(sprites.iter().all(|x| !x[1]["images"].as_array().unwrap().is_empty()));
+        }
+    }
+}
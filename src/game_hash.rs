@@ -0,0 +1,207 @@
+//! Hashing the game state, for finding the frame where two games stop being identical.
+//!
+//! The hash covers units and bullets as they would be saved, so it doesn't depend on where
+//! the objects happen to be allocated. Sprites are hashed only by their position, as each
+//! player allocates fog of war sprites of their own, which shifts the sprite ids. The same
+//! goes for the sprites that units refer to in their unit-specific data.
+
+#[cfg(feature = "game")]
+use std::fs::File;
+use std::io::{self, Write};
+use std::ptr::null_mut;
+#[cfg(feature = "game")]
+use std::sync::Mutex;
+
+use bincode::{self, Infinite};
+
+use bullets;
+use bw;
+#[cfg(feature = "game")]
+use config::config;
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
+use save::{ErrorContext, SaveError};
+use save_format::UnitSerializable;
+use units;
+
+#[cfg(feature = "game")]
+lazy_static! {
+    /// The hash file of the current game, if one was configured.
+    static ref HASH_FILE: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// 64-bit FNV-1a.
+pub struct Fnv(u64);
+
+impl Fnv {
+    pub fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Write for Fnv {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub unsafe fn hash_game_state<M: GameMemory>(game: &M) -> Result<u64, SaveError> {
+    let mut hasher = Fnv::new();
+    let free_units = units::free_unit_slots(game)?;
+    for (i, unit) in (*game.units()).iter().enumerate() {
+        if free_units[i] {
+            continue;
+        }
+        let serializable = units::unit_serializable(game, unit)
+            .context(|| format!("Unit 0x{:x}", i + 1))?;
+        hash_unit(&mut hasher, i as u16 + 1, serializable)?;
+        hash_sprite(&mut hasher, unit.entity.sprite)?;
+    }
+    let bullet_ids = bullets::bullet_pointer_to_id_map(game)?;
+    let mut bullet = *game.first_active_bullet();
+    while bullet != null_mut() {
+        let mut serializable = bullets::bullet_serializable(game, bullet, &bullet_ids)
+            .context(|| format!("Bullet {}", bullet_ids.id(bullet).unwrap_or(0)))?;
+        serializable.entity.sprite = 0;
+        bincode::serialize_into(&mut hasher, &serializable, Infinite)?;
+        hash_sprite(&mut hasher, (*bullet).entity.sprite)?;
+        bullet = (*bullet).entity.next as *mut bw::Bullet;
+    }
+    Ok(hasher.finish())
+}
+
+/// Hashes a unit without the sprite ids, which are hashed separately.
+pub unsafe fn hash_unit(
+    hasher: &mut Fnv,
+    id: u16,
+    mut unit: UnitSerializable,
+) -> Result<(), SaveError> {
+    unit.entity.sprite = 0;
+    unit.unit_specific2.clear_local_sprite_ids(unit.unit_id);
+    bincode::serialize_into(hasher, &(id, unit), Infinite)?;
+    Ok(())
+}
+
+unsafe fn hash_sprite(hasher: &mut Fnv, sprite: *mut bw::Sprite) -> Result<(), SaveError> {
+    if sprite == null_mut() {
+        bincode::serialize_into(hasher, &None::<(u16, bw::Point)>, Infinite)?;
+    } else {
+        let value = Some(((*sprite).sprite_id, (*sprite).position));
+        bincode::serialize_into(hasher, &value, Infinite)?;
+    }
+    Ok(())
+}
+
+/// Truncates the hash file, so it only contains hashes of the game that is starting.
+#[cfg(feature = "game")]
+pub fn init_game() {
+    let mut hash_file = HASH_FILE.lock().unwrap();
+    *hash_file = config().hash_file.as_ref().and_then(|path| {
+        File::create(path)
+            .map_err(|e| error!("Couldn't create hash file {}: {}", path, e))
+            .ok()
+    });
+}
+
+#[cfg(feature = "game")]
+pub fn end_game() {
+    *HASH_FILE.lock().unwrap() = None;
+}
+
+/// Called at the start of each frame, hashes the state every `hash_interval` frames.
+#[cfg(feature = "game")]
+pub unsafe fn step_frame() {
+    let interval = config().hash_interval;
+    let frame = *bw::frame_count;
+    if interval == 0 || frame % interval != 0 {
+        return;
+    }
+    let hash = match hash_game_state(&BwMemory) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Couldn't hash the game state on frame {}: {}", frame, e);
+            return;
+        }
+    };
+    info!("Frame {} state hash {:016x}", frame, hash);
+    let mut hash_file = HASH_FILE.lock().unwrap();
+    let result = match *hash_file {
+        Some(ref mut file) => writeln!(file, "{} {:016x}", frame, hash),
+        None => Ok(()),
+    };
+    if let Err(e) = result {
+        error!("Couldn't write to the hash file: {}", e);
+        *hash_file = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use bw;
+    use game_memory::GameMemory;
+    use game_memory::test::test_game;
+    use units::{self, GHOST, PYLON};
+
+    use super::{hash_unit, Fnv};
+
+    #[test]
+    fn fnv() {
+        let hash = |data: &[u8]| {
+            let mut hasher = Fnv::new();
+            hasher.write_all(data).unwrap();
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn hash_without_local_sprite_ids() {
+        unsafe {
+            let test = test_game(0x5eed_1234);
+            let game = &test.game;
+            // The generated units don't have unit-specific pointers, so the ids are
+            // changed after converting the units.
+            let hash = |unit: &bw::Unit, sprite_id: u32| {
+                let mut serializable = units::unit_serializable(game, unit).unwrap();
+                serializable.unit_specific2.0[..4].copy_from_slice(&sprite_id.to_le_bytes());
+                let mut hasher = Fnv::new();
+                hash_unit(&mut hasher, 1, serializable).unwrap();
+                hasher.finish()
+            };
+            let mut found = (false, false);
+            let free_units = units::free_unit_slots(game).unwrap();
+            let units = (*game.units()).iter().zip(free_units).filter(|x| !x.1).map(|x| x.0);
+            for unit in units {
+                match unit.unit_id {
+                    GHOST => {
+                        assert_eq!(hash(unit, 3), hash(unit, 7));
+                        assert_ne!(hash(unit, 0), hash(unit, 7));
+                        found.0 = true;
+                    }
+                    PYLON => {
+                        assert_eq!(hash(unit, 0), hash(unit, 7));
+                        found.1 = true;
+                    }
+                    _ => (),
+                }
+            }
+            assert_eq!(found, (true, true));
+        }
+    }
+}
@@ -0,0 +1,643 @@
+//! Access to BW's globals that saving and loading use.
+//!
+//! The save code is generic over `GameMemory`, so that it can run both inside BW, where
+//! `BwMemory` points to the `whack_vars!` globals, and in tests, where `TestMemory` has
+//! its own copy of them.
+
+#[cfg(test)]
+use std::alloc::{alloc_zeroed, Layout};
+#[cfg(test)]
+use std::cell::{RefCell, UnsafeCell};
+
+use bw;
+#[cfg(feature = "game")]
+use dat;
+#[cfg(feature = "game")]
+use grp_table;
+#[cfg(feature = "game")]
+use remap_palettes;
+use save_format::DatPatch;
+
+macro_rules! game_memory {
+    ($($name:ident: $ty:ty;)*) => {
+        /// Each function returns a pointer to the global of same name in `bw`.
+        pub trait GameMemory {
+            $(fn $name(&self) -> *mut $ty;)*
+            /// Amount of unit types in units.dat.
+            fn unit_type_count(&self) -> u32;
+            /// Flags of an unit type from units.dat. Only valid for unit ids below
+            /// `unit_type_count()`.
+            fn unit_flags(&self, unit_id: u16) -> u32;
+            /// Changes made to the dat files during the game, in the order they were made.
+            fn dat_patches(&self) -> Vec<DatPatch>;
+            /// Redoes a change from a save, returning the reason if it doesn't fit the
+            /// dat files that are currently loaded.
+            fn apply_dat_patch(&self, patch: &DatPatch) -> Result<(), String>;
+            /// Undoes the changes returned by `dat_patches`, except for the first `count`.
+            fn revert_dat_patches_since(&self, count: usize);
+            /// The plugin's grp table, which continues from `image_grps`.
+            fn extended_grps(&self) -> &[*mut bw::GrpSprite];
+            /// The remap palettes loaded by the plugin, which come after `remap_palettes`.
+            fn extra_remap_palettes(&self) -> &[bw::RemapPalette];
+        }
+
+        #[cfg(feature = "game")]
+        impl GameMemory for BwMemory {
+            $(fn $name(&self) -> *mut $ty {
+                unsafe { &mut *bw::$name }
+            })*
+
+            fn unit_type_count(&self) -> u32 {
+                dat::units::amount()
+            }
+
+            fn unit_flags(&self, unit_id: u16) -> u32 {
+                dat::units::flags(dat::UnitId(unit_id)).unwrap_or(0)
+            }
+
+            fn dat_patches(&self) -> Vec<DatPatch> {
+                dat::patches().into_iter().map(|x| DatPatch {
+                    dat: x.dat as u8,
+                    column: x.column,
+                    id: x.id,
+                    value: x.value,
+                }).collect()
+            }
+
+            fn apply_dat_patch(&self, patch: &DatPatch) -> Result<(), String> {
+                let dat = dat::Dat::from_u8(patch.dat)
+                    .ok_or_else(|| format!("Invalid dat file {}", patch.dat))?;
+                dat::apply(dat::Patch {
+                    dat,
+                    column: patch.column,
+                    id: patch.id,
+                    value: patch.value,
+                }).map_err(|e| e.to_string())
+            }
+
+            fn revert_dat_patches_since(&self, count: usize) {
+                dat::revert_patches_since(count);
+            }
+
+            fn extended_grps(&self) -> &[*mut bw::GrpSprite] {
+                grp_table::extended_grps()
+            }
+
+            fn extra_remap_palettes(&self) -> &[bw::RemapPalette] {
+                remap_palettes::extra_palettes()
+            }
+        }
+
+        #[cfg(test)]
+        struct TestGlobals {
+            $($name: $ty,)*
+        }
+
+        #[cfg(test)]
+        impl GameMemory for TestMemory {
+            $(fn $name(&self) -> *mut $ty {
+                unsafe { &mut (*self.globals.get()).$name }
+            })*
+
+            fn unit_type_count(&self) -> u32 {
+                self.unit_flags.len() as u32
+            }
+
+            fn unit_flags(&self, unit_id: u16) -> u32 {
+                self.unit_flags[unit_id as usize]
+            }
+
+            fn dat_patches(&self) -> Vec<DatPatch> {
+                self.dat_patches.borrow().clone()
+            }
+
+            fn apply_dat_patch(&self, patch: &DatPatch) -> Result<(), String> {
+                if patch.dat >= 8 {
+                    return Err(format!("Invalid dat file {}", patch.dat));
+                }
+                self.dat_patches.borrow_mut().push(*patch);
+                Ok(())
+            }
+
+            fn revert_dat_patches_since(&self, count: usize) {
+                self.dat_patches.borrow_mut().truncate(count);
+            }
+
+            fn extended_grps(&self) -> &[*mut bw::GrpSprite] {
+                &self.extended_grps
+            }
+
+            fn extra_remap_palettes(&self) -> &[bw::RemapPalette] {
+                &self.extra_remap_palettes
+            }
+        }
+    };
+}
+
+game_memory! {
+    first_free_bullet: *mut bw::Bullet;
+    last_free_bullet: *mut bw::Bullet;
+    first_active_bullet: *mut bw::Bullet;
+    last_active_bullet: *mut bw::Bullet;
+
+    first_free_sprite: *mut bw::Sprite;
+    last_free_sprite: *mut bw::Sprite;
+    first_free_image: *mut bw::Image;
+    last_free_image: *mut bw::Image;
+
+    units: [bw::Unit; 0x6a4];
+
+    map_height_tiles: u16;
+    horizontal_sprite_lines_end: [*mut bw::Sprite; 0x100];
+    horizontal_sprite_lines_begin: [*mut bw::Sprite; 0x100];
+    image_grps: *mut *mut bw::GrpSprite;
+    image_count_part1: u32;
+    image_count_part2: u32;
+    remap_palettes: [bw::RemapPalette; 0x7];
+
+    image_updatefuncs: [bw::ImageStepFrame; 0x12];
+    image_drawfuncs: [bw::ImageDraw; 0x12];
+
+    first_hidden_unit: *mut bw::Unit;
+    last_hidden_unit: *mut bw::Unit;
+    first_active_unit: *mut bw::Unit;
+    last_active_unit: *mut bw::Unit;
+    first_dying_unit: *mut bw::Unit;
+    last_dying_unit: *mut bw::Unit;
+    first_invisible_unit: *mut bw::Unit;
+    first_revealer: *mut bw::Unit;
+    last_revealer: *mut bw::Unit;
+    first_free_unit: *mut bw::Unit;
+    last_free_unit: *mut bw::Unit;
+    first_player_unit: [*mut bw::Unit; 0xc];
+
+    guard_ais: [bw::GuardAi; 0x3e8];
+    worker_ais: [bw::WorkerAi; 0x3e8];
+    building_ais: [bw::BuildingAi; 0x3e8];
+    military_ais: [bw::MilitaryAi; 0x3e8];
+    orders: [bw::Order; 0x7d0];
+    path_array_start: *mut bw::Path;
+
+    first_active_lone_sprite: *mut bw::LoneSprite;
+    first_active_fow_sprite: *mut bw::LoneSprite;
+    last_active_lone_sprite: *mut bw::LoneSprite;
+    last_active_fow_sprite: *mut bw::LoneSprite;
+    cursor_marker: *mut bw::LoneSprite;
+}
+
+/// The globals of the BW process the plugin has been loaded to.
+#[cfg(feature = "game")]
+pub struct BwMemory;
+
+/// Zero-initialized globals, which tests can fill with the game state they need.
+#[cfg(test)]
+pub struct TestMemory {
+    globals: Box<UnsafeCell<TestGlobals>>,
+    pub unit_flags: Vec<u32>,
+    pub dat_patches: RefCell<Vec<DatPatch>>,
+    pub extended_grps: Vec<*mut bw::GrpSprite>,
+    pub extra_remap_palettes: Vec<bw::RemapPalette>,
+}
+
+#[cfg(test)]
+impl TestMemory {
+    pub fn new(unit_flags: Vec<u32>) -> TestMemory {
+        // The unit and ai arrays are too large to be zeroed on stack.
+        unsafe {
+            let globals = alloc_zeroed(Layout::new::<UnsafeCell<TestGlobals>>());
+            TestMemory {
+                globals: Box::from_raw(globals as *mut UnsafeCell<TestGlobals>),
+                unit_flags,
+                dat_patches: RefCell::new(Vec::new()),
+                extended_grps: Vec::new(),
+                extra_remap_palettes: Vec::new(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::mem;
+    use std::ptr::{self, null_mut};
+    use std::slice;
+
+    use bullets;
+    use bw;
+    use game_hash;
+    use remap_palettes;
+    use save_format::DatPatch;
+    use send_pointer::SendPtr;
+    use sprites::{self, DrawfuncParam};
+    use state::{plugin_state, PluginState};
+    use units::{self, GHOST, PYLON};
+    use super::{GameMemory, TestMemory};
+
+    const UNIT_TYPES: usize = 0xe4;
+    const SCV: u16 = 0x7;
+    const MAP_HEIGHT_TILES: u16 = 0x40;
+    /// Amount of grps in BW's table, out of the 0x20 that the tests have.
+    const BW_GRPS: usize = 0x18;
+
+    /// Xorshift, so that the generated states are same on every run.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, max: u32) -> u32 {
+            self.next() % max
+        }
+
+        fn chance(&mut self, percent: u32) -> bool {
+            self.below(100) < percent
+        }
+
+        fn choose<T: Copy>(&mut self, values: &[T]) -> Option<T> {
+            match values.len() {
+                0 => None,
+                len => Some(values[self.below(len as u32) as usize]),
+            }
+        }
+    }
+
+    /// Tables that BW keeps around for the entire process, and which don't get saved.
+    struct StaticTables {
+        /// Only accessed through `grp_pointers`.
+        _grps: Vec<bw::GrpSprite>,
+        grp_pointers: Vec<*mut bw::GrpSprite>,
+        remap_palettes: Vec<u8>,
+        paths: Vec<bw::Path>,
+    }
+
+    impl StaticTables {
+        fn new() -> StaticTables {
+            let mut grps = (0..0x20).map(|i| bw::GrpSprite {
+                frame_count: 0x11,
+                width: 0x20 + i,
+                height: 0x20 + i,
+            }).collect::<Vec<_>>();
+            let grp_pointers = grps.iter_mut().map(|x| x as *mut bw::GrpSprite).collect();
+            StaticTables {
+                _grps: grps,
+                grp_pointers,
+                remap_palettes: vec![0; 0x100 * 8],
+                paths: (0..0x40).map(|_| unsafe { mem::zeroed() }).collect(),
+            }
+        }
+
+        unsafe fn memory(&mut self) -> TestMemory {
+            let mut unit_flags = vec![0; UNIT_TYPES];
+            unit_flags[SCV as usize] = 0x8;
+            let mut game = TestMemory::new(unit_flags);
+            // The last grps are in the plugin's table, with a gap for an image without grp.
+            *game.image_grps() = self.grp_pointers.as_mut_ptr();
+            *game.image_count_part1() = BW_GRPS as u32;
+            game.extended_grps = self.grp_pointers[BW_GRPS..].to_vec();
+            game.extended_grps[1] = null_mut();
+            let names = ["ofire", "gfire", "bfire", "bexpl", "trans50", "red", "green"];
+            for (i, palette) in (*game.remap_palettes()).iter_mut().enumerate() {
+                let data = self.remap_palettes.as_ptr().add(i * 0x100);
+                *palette = remap_palettes::remap_palette(names[i], i as u32, data).unwrap();
+            }
+            // One palette loaded by the plugin.
+            let data = self.remap_palettes.as_ptr().add(7 * 0x100);
+            let cfire = remap_palettes::remap_palette("cfire", 7, data).unwrap();
+            game.extra_remap_palettes = vec![cfire];
+            *game.path_array_start() = self.paths.as_mut_ptr();
+            *game.map_height_tiles() = MAP_HEIGHT_TILES;
+            game
+        }
+    }
+
+    unsafe fn generate_sprites(game: &TestMemory, state: &mut PluginState, rng: &mut Rng) {
+        let remap_palettes = (*game.remap_palettes()).iter()
+            .chain(game.extra_remap_palettes())
+            .map(|x| x.data)
+            .collect::<Vec<_>>();
+        let bw_grps = slice::from_raw_parts(*game.image_grps(), BW_GRPS);
+        let grps = bw_grps.iter().chain(game.extended_grps()).cloned().collect::<Vec<_>>();
+        let count = rng.below(300);
+        for i in 0..count {
+            let sprite = state.sprite_array.push();
+            state.sprites.insert(SendPtr(sprite));
+            *sprite = bw::Sprite {
+                sprite_id: rng.below(0x205) as u16,
+                player: rng.below(12) as u8,
+                elevation: rng.below(20) as u8,
+                flags: rng.next() as u8,
+                index: i as u16,
+                position: bw::Point {
+                    x: rng.below(0x1000) as i16,
+                    y: rng.below(MAP_HEIGHT_TILES as u32 * 32) as i16,
+                },
+                extra: bw::SpriteExtension {
+                    spawn_order: (i, 0),
+                },
+                ..mem::zeroed()
+            };
+            let mut prev: *mut bw::Image = null_mut();
+            for _ in 0..rng.below(4) + 1 {
+                let image = state.image_array.push();
+                // Health bars and selection circles aren't saved, as they get recreated
+                // for selected units.
+                let drawfunc = match rng.below(0x12) as u8 {
+                    x if sprites::drawfunc_info(x).saved => x,
+                    _ => 0,
+                };
+                *image = bw::Image {
+                    prev,
+                    image_id: rng.below(0x200) as u16,
+                    drawfunc,
+                    direction: rng.below(32) as u8,
+                    flags: rng.next() as u16,
+                    x_offset: rng.next() as i8,
+                    iscript: bw::Iscript {
+                        header: rng.next() as u16,
+                        pos: rng.next() as u16,
+                        return_pos: 0,
+                        animation_id: rng.below(0x1c) as u8,
+                        wait: rng.below(4) as u8,
+                    },
+                    frame: rng.below(0x11) as u16 * 0x11,
+                    grp: rng.choose(&grps).unwrap(),
+                    drawfunc_param: match sprites::drawfunc_info(drawfunc).param {
+                        DrawfuncParam::RemapPalette => {
+                            rng.choose(&remap_palettes).unwrap() as *mut _
+                        }
+                        _ => rng.below(0x100) as usize as *mut _,
+                    },
+                    parent: sprite,
+                    ..mem::zeroed()
+                };
+                if prev == null_mut() {
+                    (*sprite).first_overlay = image;
+                } else {
+                    (*prev).next = image;
+                }
+                if rng.chance(50) || (*sprite).main_image == null_mut() {
+                    (*sprite).main_image = image;
+                }
+                prev = image;
+            }
+            if rng.chance(10) {
+                // Selected sprites have a selection circle, which doesn't get saved.
+                let image = state.image_array.push();
+                *image = bw::Image {
+                    prev,
+                    image_id: 0x231 + rng.below(10) as u16,
+                    drawfunc: 0xd,
+                    parent: sprite,
+                    ..mem::zeroed()
+                };
+                (*prev).next = image;
+                prev = image;
+            }
+            (*sprite).last_overlay = prev;
+
+            // Each sprite is in the list of its y tile.
+            let line = ((*sprite).position.y / 32) as usize;
+            let lines_end = &mut *game.horizontal_sprite_lines_end();
+            let last = lines_end[line];
+            if last == null_mut() {
+                (*game.horizontal_sprite_lines_begin())[line] = sprite;
+            } else {
+                (*last).next = sprite;
+                (*sprite).prev = last;
+            }
+            lines_end[line] = sprite;
+        }
+        sprites::refill_sprite_image_list(game, state);
+
+        let lone_count = rng.below(50);
+        let fow_count = rng.below(50);
+        let live_sprites = state.sprite_array.iter().take(count as usize).collect::<Vec<_>>();
+        let mut prev: *mut bw::LoneSprite = null_mut();
+        for i in 0..lone_count + fow_count {
+            let sprite = Box::into_raw(Box::new(bw::LoneSprite {
+                prev: null_mut(),
+                next: null_mut(),
+                value: rng.next(),
+                sprite: rng.choose(&live_sprites).unwrap_or(null_mut()),
+            }));
+            state.lone_sprites.insert(sprite.into());
+            if i == 0 {
+                *game.first_active_lone_sprite() = sprite;
+            } else if i == lone_count {
+                *game.first_active_fow_sprite() = sprite;
+            } else {
+                (*prev).next = sprite;
+                (*sprite).prev = prev;
+            }
+            if i < lone_count {
+                *game.last_active_lone_sprite() = sprite;
+                if rng.chance(10) {
+                    *game.cursor_marker() = sprite;
+                }
+            } else {
+                *game.last_active_fow_sprite() = sprite;
+            }
+            prev = sprite;
+        }
+    }
+
+    unsafe fn generate_units(game: &TestMemory, rng: &mut Rng) {
+        let live_sprites = (*game.horizontal_sprite_lines_begin()).iter()
+            .flat_map(|&first| {
+                let mut sprite = first;
+                let mut result = Vec::new();
+                while sprite != null_mut() {
+                    result.push(sprite);
+                    sprite = (*sprite).next;
+                }
+                result
+            })
+            .collect::<Vec<_>>();
+        let live = (0..0x6a4).map(|_| rng.chance(5)).collect::<Vec<_>>();
+        units::rebuild_free_units(game, &live);
+        let units = (*game.units()).iter_mut()
+            .zip(live.iter())
+            .filter(|x| *x.1)
+            .map(|x| x.0 as *mut bw::Unit)
+            .collect::<Vec<_>>();
+        let orders = (*game.orders()).iter_mut()
+            .map(|x| x as *mut bw::Order)
+            .collect::<Vec<_>>();
+        let paths = (0..0x40).map(|i| (*game.path_array_start()).add(i)).collect::<Vec<_>>();
+        let mut prev: *mut bw::Unit = null_mut();
+        let mut player_last: [*mut bw::Unit; 0xc] = [null_mut(); 0xc];
+        for (i, &unit) in units.iter().enumerate() {
+            let unit_id = rng.choose(&[0, 0x25, SCV, GHOST, PYLON]).unwrap();
+            let player = rng.below(12) as u8;
+            *unit = mem::zeroed();
+            (*unit).entity = bw::Entity {
+                prev: prev as *mut bw::Entity,
+                hitpoints: rng.below(0x10000) as i32,
+                sprite: live_sprites.get(i).cloned().unwrap_or(null_mut()),
+                move_target_unit: rng.choose(&units).filter(|_| rng.chance(30))
+                    .unwrap_or(null_mut()),
+                position: bw::Point {
+                    x: rng.below(0x1000) as i16,
+                    y: rng.below(0x1000) as i16,
+                },
+                player,
+                order: rng.below(0xbd) as u8,
+                target: rng.choose(&units).filter(|_| rng.chance(30)).unwrap_or(null_mut()),
+                ..mem::zeroed()
+            };
+            (*unit).unit_id = unit_id;
+            (*unit).shields = rng.below(0x10000) as i32;
+            (*unit).energy = rng.below(0x10000) as u16;
+            (*unit).flags = rng.next() & !0x2;
+            (*unit).kills = rng.next() as u8;
+            if rng.chance(40) {
+                (*unit).order_queue_begin = rng.choose(&orders).unwrap();
+                (*unit).order_queue_end = rng.choose(&orders).unwrap();
+            }
+            (*unit).path = rng.choose(&paths).filter(|_| rng.chance(50)).unwrap_or(null_mut());
+            if unit_id != PYLON {
+                (&mut (*unit).rally_pylon)[..4].copy_from_slice(&rng.next().to_le_bytes());
+            }
+            if rng.chance(20) {
+                let ai = &mut (*game.guard_ais())[i] as *mut bw::GuardAi as *mut bw::UnitAi;
+                (*ai).ty = 1;
+                (*unit).ai = ai;
+            }
+            (*unit).spells.irradiated_by =
+                rng.choose(&units).filter(|_| rng.chance(10)).unwrap_or(null_mut());
+
+            if prev == null_mut() {
+                *game.first_active_unit() = unit;
+            } else {
+                (*prev).entity.next = unit as *mut bw::Entity;
+            }
+            prev = unit;
+            let player_prev = player_last[player as usize];
+            if player_prev == null_mut() {
+                (*game.first_player_unit())[player as usize] = unit;
+            } else {
+                (*player_prev).next_player_unit = unit;
+                (*unit).prev_player_unit = player_prev;
+            }
+            player_last[player as usize] = unit;
+        }
+        *game.last_active_unit() = prev;
+    }
+
+    unsafe fn generate_bullets(game: &TestMemory, state: &mut PluginState, rng: &mut Rng) {
+        let sprites = state.sprite_array.iter().collect::<Vec<_>>();
+        let units = (*game.units()).iter_mut()
+            .map(|x| x as *mut bw::Unit)
+            .collect::<Vec<_>>();
+        let mut prev: *mut bw::Bullet = null_mut();
+        for _ in 0..rng.below(100) {
+            let bullet = Box::into_raw(Box::new(bw::Bullet {
+                entity: bw::Entity {
+                    prev: prev as *mut bw::Entity,
+                    sprite: rng.choose(&sprites).unwrap_or(null_mut()),
+                    target: rng.choose(&units).filter(|_| rng.chance(50)).unwrap_or(null_mut()),
+                    ..mem::zeroed()
+                },
+                weapon_id: rng.below(0x82) as u8,
+                death_timer: rng.next() as u8,
+                parent: rng.choose(&units).filter(|_| rng.chance(80)).unwrap_or(null_mut()),
+                ..mem::zeroed()
+            }));
+            state.bullets.insert(bullet.into());
+            if prev == null_mut() {
+                *game.first_active_bullet() = bullet;
+            } else {
+                (*prev).entity.next = bullet as *mut bw::Entity;
+            }
+            prev = bullet;
+        }
+        *game.last_active_bullet() = prev;
+    }
+
+    unsafe fn generate_game(game: &TestMemory, rng: &mut Rng) {
+        // The state may have been left over from an earlier test in this thread.
+        let mut state = plugin_state().borrow_mut();
+        state.reset_objects(game);
+        generate_sprites(game, &mut state, rng);
+        generate_units(game, rng);
+        generate_bullets(game, &mut state, rng);
+        for _ in 0..rng.below(4) {
+            let patch = DatPatch {
+                dat: rng.below(8) as u8,
+                column: rng.below(0x18) as u8,
+                id: rng.below(0x100),
+                value: rng.below(0x10000),
+            };
+            game.apply_dat_patch(&patch).unwrap();
+        }
+    }
+
+    /// A generated game, which frees the objects it added to the plugin state once dropped.
+    pub struct TestGame {
+        tables: StaticTables,
+        pub game: TestMemory,
+    }
+
+    impl TestGame {
+        /// Empty globals which share the static tables of the game, for loading it to.
+        pub unsafe fn empty_memory(&mut self) -> TestMemory {
+            self.tables.memory()
+        }
+    }
+
+    impl Drop for TestGame {
+        fn drop(&mut self) {
+            // A failed test may have left the state borrowed.
+            if let Ok(mut state) = plugin_state().try_borrow_mut() {
+                unsafe { state.reset_objects(&self.game) }
+            }
+        }
+    }
+
+    pub unsafe fn test_game(seed: u32) -> TestGame {
+        let mut tables = StaticTables::new();
+        let game = tables.memory();
+        generate_game(&game, &mut Rng(seed));
+        TestGame {
+            tables,
+            game,
+        }
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        unsafe {
+            for seed in 1..21u32 {
+                let mut test = test_game(seed.wrapping_mul(0x9e3779b9));
+                let game = &test.game;
+                let sprites = sprites::serialize_sprites(game).unwrap();
+                let units = units::serialize_units(game).unwrap();
+                let bullets = bullets::serialize_bullets(game).unwrap();
+                let hash = game_hash::hash_game_state(game).unwrap();
+                plugin_state().borrow_mut().reset_objects(game);
+
+                let loaded = test.empty_memory();
+                // BW saves the ai arrays itself.
+                ptr::copy_nonoverlapping(test.game.guard_ais(), loaded.guard_ais(), 1);
+                // BW may load the sprites or the units first.
+                if seed % 2 == 0 {
+                    sprites::load_sprites(&loaded, &sprites).unwrap();
+                    units::load_units(&loaded, &units).unwrap();
+                } else {
+                    units::load_units(&loaded, &units).unwrap();
+                    sprites::load_sprites(&loaded, &sprites).unwrap();
+                }
+                bullets::load_bullets(&loaded, &bullets).unwrap();
+                assert!(sprites::serialize_sprites(&loaded).unwrap() == sprites, "Seed {}", seed);
+                assert!(units::serialize_units(&loaded).unwrap() == units, "Seed {}", seed);
+                assert!(bullets::serialize_bullets(&loaded).unwrap() == bullets, "Seed {}", seed);
+                assert_eq!(game_hash::hash_game_state(&loaded).unwrap(), hash, "Seed {}", seed);
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Grps for images past the end of BW's grp table.
+//!
+//! BW's table only has entries for the images that images.dat had when BW started, so
+//! images added with an extended images.dat get their grps from a table the plugin owns.
+//! The grps are read from `<grp_dir>/<image id>.grp` and kept for the rest of the process.
+//!
+//! Saves refer to grps by 1-based indices, which continue from BW's table to this one, so
+//! that an image id past BW's table still has its grp id as `image id + 1`.
+//!
+//! The plugin doesn't hook BW's image initialization, which only reads BW's own table, so
+//! images past it are only created by other plugins. They get the grps with `GetImageGrp`.
+
+#[cfg(feature = "game")]
+use std::fs;
+#[cfg(feature = "game")]
+use std::path::Path;
+#[cfg(feature = "game")]
+use std::ptr::null_mut;
+
+use bw;
+#[cfg(feature = "game")]
+use config::config;
+
+pub struct ExtendedGrps {
+    /// Indexed by image id - BW's image count, null for images that don't have a grp.
+    pub grps: Vec<*mut bw::GrpSprite>,
+}
+
+// The grps are never modified or freed once loaded.
+unsafe impl Send for ExtendedGrps {}
+unsafe impl Sync for ExtendedGrps {}
+
+#[cfg(feature = "game")]
+lazy_static! {
+    static ref EXTENDED_GRPS: ExtendedGrps = unsafe { ExtendedGrps::load() };
+}
+
+/// The grps of images past BW's table. Has to be first called once BW has loaded
+/// its own grps.
+#[cfg(feature = "game")]
+pub fn extended_grps() -> &'static [*mut bw::GrpSprite] {
+    &EXTENDED_GRPS.grps
+}
+
+impl ExtendedGrps {
+    #[cfg(feature = "game")]
+    unsafe fn load() -> ExtendedGrps {
+        let mut result = ExtendedGrps {
+            grps: Vec::new(),
+        };
+        let dir = match config().grp_dir {
+            Some(ref s) => Path::new(s),
+            None => return result,
+        };
+        let first_image = (*bw::image_count_part1).saturating_add(*bw::image_count_part2);
+        let entries = match fs::read_dir(dir) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("Couldn't read grp directory {}: {}", dir.display(), e);
+                return result;
+            }
+        };
+        for entry in entries.filter_map(|x| x.ok()) {
+            let path = entry.path();
+            let image_id = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(s) if path.extension().map(|x| x == "grp").unwrap_or(false) => {
+                    s.parse::<u32>().ok()
+                }
+                _ => continue,
+            };
+            let index = match image_id.and_then(|x| x.checked_sub(first_image)) {
+                Some(s) => s as usize,
+                None => {
+                    warn!("{} isn't named after an image past {}", path.display(), first_image);
+                    continue;
+                }
+            };
+            let grp = match fs::read(&path) {
+                Ok(data) => match leak_grp(data) {
+                    Some(s) => s,
+                    None => {
+                        error!("{} is not a valid grp", path.display());
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    error!("Couldn't read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if result.grps.len() <= index {
+                result.grps.resize(index + 1, null_mut());
+            }
+            result.grps[index] = grp;
+        }
+        let count = result.grps.iter().filter(|x| !x.is_null()).count();
+        info!("Loaded {} grps for images starting from {}", count, first_image);
+        result
+    }
+}
+
+/// Returns the grp of an image from BW's table or the plugin's, or null if the image
+/// doesn't have one. For other plugins that create images past BW's table.
+#[cfg(feature = "game")]
+#[no_mangle]
+pub unsafe extern fn GetImageGrp(image_id: u32) -> *mut bw::GrpSprite {
+    let bw_count = (*bw::image_count_part1).saturating_add(*bw::image_count_part2);
+    if image_id < bw_count {
+        *(*bw::image_grps).offset(image_id as isize)
+    } else {
+        extended_grps().get((image_id - bw_count) as usize).cloned().unwrap_or(null_mut())
+    }
+}
+
+/// Checks that the frame headers are within the file, as BW trusts them when drawing,
+/// and leaks the file so that it can be used as a grp for the rest of the process.
+pub fn leak_grp(data: Vec<u8>) -> Option<*mut bw::GrpSprite> {
+    let frame_count = read_u16(&data, 0)? as usize;
+    let width = read_u16(&data, 2)?;
+    let height = read_u16(&data, 4)?;
+    if frame_count == 0 {
+        return None;
+    }
+    for i in 0..frame_count {
+        let header = data.get(6 + i * 8..6 + (i + 1) * 8)?;
+        if !valid_frame(&data, header, width, height) {
+            return None;
+        }
+    }
+    Some(Box::leak(data.into_boxed_slice()).as_mut_ptr() as *mut bw::GrpSprite)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2).map(|x| x[0] as u16 | (x[1] as u16) << 8)
+}
+
+/// A frame header is x, y, width, height, and the offset of a table with offsets to each
+/// line of the frame, relative to the table.
+fn valid_frame(data: &[u8], header: &[u8], grp_width: u16, grp_height: u16) -> bool {
+    let (x, y) = (header[0] as u16, header[1] as u16);
+    let (width, height) = (header[2] as u16, header[3] as u16);
+    if x + width > grp_width || y + height > grp_height {
+        return false;
+    }
+    let table = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    (0..height as usize).all(|line| {
+        let line_offset = table.checked_add(line * 2).and_then(|pos| read_u16(data, pos));
+        match line_offset.and_then(|x| table.checked_add(x as usize)) {
+            Some(pos) => valid_line(data, pos, width),
+            None => false,
+        }
+    })
+}
+
+/// Lines are runs of transparent pixels (0x80 | count), a repeated color
+/// (0x40 | count, color) or colors copied as is (count, colors). The runs have to stay
+/// within the file and the frame's width.
+fn valid_line(data: &[u8], mut pos: usize, width: u16) -> bool {
+    let mut x = 0;
+    while x < width {
+        let op = match data.get(pos) {
+            Some(&s) => s,
+            None => return false,
+        };
+        let (pixels, size) = if op & 0x80 != 0 {
+            (op & 0x7f, 1)
+        } else if op & 0x40 != 0 {
+            (op & 0x3f, 2)
+        } else {
+            (op, 1 + op as usize)
+        };
+        pos += size;
+        x += pixels as u16;
+        if pos > data.len() || x > width {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::leak_grp;
+
+    #[test]
+    fn grp_headers() {
+        assert!(leak_grp(vec![]).is_none());
+        assert!(leak_grp(vec![0, 0, 0x20, 0, 0x20, 0]).is_none());
+        // Two frames need 2 * 8 bytes of frame headers.
+        let mut grp = vec![2, 0, 0x20, 0, 0x18, 0];
+        grp.extend((0..15).map(|_| 0));
+        assert!(leak_grp(grp.clone()).is_none());
+        grp.push(0);
+        let grp = leak_grp(grp).unwrap();
+        let (frame_count, width, height) =
+            unsafe { ((*grp).frame_count, (*grp).width, (*grp).height) };
+        assert_eq!((frame_count, width, height), (2, 0x20, 0x18));
+    }
+
+    #[test]
+    fn grp_frames() {
+        // A 4x2 frame, with a transparent line and a line of color 1.
+        let grp = vec![
+            1, 0, 4, 0, 2, 0,
+            0, 0, 4, 2, 0xe, 0, 0, 0,
+            4, 0, 5, 0,
+            0x84,
+            0x44, 1,
+        ];
+        assert!(leak_grp(grp.clone()).is_some());
+        let mut out_of_range = grp.clone();
+        out_of_range[0xa] = 0xff;
+        assert!(leak_grp(out_of_range).is_none());
+        let mut overflowing = grp.clone();
+        overflowing[0xa..0xe].copy_from_slice(&[0xff; 4]);
+        assert!(leak_grp(overflowing).is_none());
+        let mut too_wide = grp.clone();
+        too_wide[6] = 1;
+        assert!(leak_grp(too_wide).is_none());
+        let mut too_tall = grp.clone();
+        too_tall[9] = 3;
+        assert!(leak_grp(too_tall).is_none());
+        let mut line_out_of_range = grp.clone();
+        line_out_of_range[0x10] = 0x20;
+        assert!(leak_grp(line_out_of_range).is_none());
+        let mut truncated = grp.clone();
+        truncated.pop();
+        assert!(leak_grp(truncated).is_none());
+        let mut long_run = grp;
+        long_run[0x13] = 0x45;
+        assert!(leak_grp(long_run).is_none());
+    }
+}
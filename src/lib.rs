@@ -1,5 +1,9 @@
 #![feature(vec_into_raw_parts)]
+#![cfg_attr(test, feature(test))]
+// Without the hooks, only the parts of BW's structures that the save format uses are needed.
+#![cfg_attr(not(feature = "game"), allow(dead_code))]
 
+#[cfg(feature = "game")]
 #[macro_use]
 extern crate whack;
 
@@ -13,25 +17,73 @@ extern crate chrono;
 extern crate bincode;
 extern crate flate2;
 extern crate serde;
+extern crate serde_json;
 #[macro_use] extern crate serde_derive;
 extern crate thread_local;
 
+#[cfg(test)] extern crate test;
+
+#[cfg(feature = "game")]
 extern crate bw_dat as dat;
 
 #[macro_use] mod macros;
+#[cfg(feature = "game")]
 pub mod mpqdraft;
 
 mod bullets;
 mod bw;
+#[cfg(feature = "game")]
+mod config;
+mod dump;
 mod entity_serialize;
+mod game_hash;
+mod game_memory;
+mod grp_table;
+mod remap_palettes;
 mod save;
+mod save_format;
 mod send_pointer;
 mod sprites;
+mod state;
 mod units;
 
+#[cfg(feature = "game")]
 use std::ptr::null_mut;
+#[cfg(feature = "game")]
 use std::sync::Mutex;
 
+/// Entry points for the fuzz targets in `fuzz/`, which can't reach the private modules.
+#[doc(hidden)]
+pub mod fuzz {
+    use save::read_chunk;
+    use save_format;
+
+    /// Reads a compressed chunk, as it is in the save file.
+    pub fn chunk(data: &[u8]) {
+        let magic = save_format::SPRITE_SAVE_MAGIC;
+        let version = save_format::SPRITE_SAVE_VERSION;
+        if let Ok(data) = read_chunk(data, magic, version, save_format::SPRITE_SAVE_MAX_SIZE) {
+            let _ = save_format::decode_sprites(&data);
+        }
+    }
+
+    // The decoders are also fuzzed with uncompressed data, as mutating the compressed data
+    // rarely produces anything that decompresses.
+
+    pub fn sprites(data: &[u8]) {
+        let _ = save_format::decode_sprites(data);
+    }
+
+    pub fn units(data: &[u8]) {
+        let _ = save_format::decode_units(data);
+    }
+
+    pub fn bullets(data: &[u8]) {
+        let _ = save_format::decode_bullets(data);
+    }
+}
+
+#[cfg(feature = "game")]
 fn init() {
     let _ = fern::Dispatch::new()
         .format(|out, message, record| {
@@ -56,20 +108,25 @@ fn init() {
             None => error!("Unknown panic payload"),
         }
     }));
+    // Read the config now so that any problems with it get logged before a game starts.
+    config::config();
 
     patch();
 }
 
+#[cfg(feature = "game")]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern fn Initialize() {
     init();
 }
 
+#[cfg(feature = "game")]
 lazy_static! {
     static ref PATCHER: Mutex<whack::Patcher> = Mutex::new(whack::Patcher::new());
 }
 
+#[cfg(feature = "game")]
 fn patch() {
     unsafe {
         let mut active_patcher = PATCHER.lock().unwrap();
@@ -109,8 +166,9 @@ fn patch() {
             exe.hook(bw::SaveUnitChunk, units::save_unit_chunk);
             exe.hook(bw::LoadUnitChunk, units::load_unit_chunk);
 
-            exe.call_hook(bw::GameEnd, bullets::delete_all);
-            exe.call_hook(bw::GameEnd, sprites::delete_all);
+            exe.call_hook(bw::InitGame, state::init_game);
+            exe.call_hook(bw::GameEnd, state::end_game);
+            exe.call_hook(bw::StepObjects, step_objects);
 
             exe.replace_val(bw::TooltipSurfaceBytes, 0xa0u32 * 480);
             exe.replace_val(bw::TooltipSurfaceHeight, 480u16);
@@ -127,6 +185,13 @@ fn patch() {
     }
 }
 
+#[cfg(feature = "game")]
+unsafe fn step_objects() {
+    game_hash::step_frame();
+    dump::check_hotkey();
+}
+
+#[cfg(feature = "game")]
 unsafe fn load_map_player_colors(buf: *const u8, length: u32) {
     let tminimap_pcx = match storm_load_pcx("game\\tminimap.pcx") {
         Some(s) => s.0,
@@ -150,6 +215,7 @@ unsafe fn load_map_player_colors(buf: *const u8, length: u32) {
     }
 }
 
+#[cfg(feature = "game")]
 unsafe fn storm_load_pcx(filename: &str) -> Option<(Vec<u8>, u32, u32)> {
     std::ffi::CString::new(filename).ok().and_then(|filename| {
         let mut width = 0u32;
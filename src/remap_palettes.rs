@@ -0,0 +1,105 @@
+//! Remap palettes that the plugin loads in addition to BW's seven.
+//!
+//! The palettes are listed in the `remap_palettes` setting, and each one is read from
+//! `game\<name>.pcx` in the MPQs. Images with the remapping draw function point to the
+//! palette data, which saves store as the name of the palette, so that they can still be
+//! loaded after the list of palettes changes.
+
+#[cfg(feature = "game")]
+use std::ffi::CStr;
+#[cfg(feature = "game")]
+use std::ptr::null;
+
+#[cfg(feature = "game")]
+use libc::c_char;
+
+use bw;
+#[cfg(feature = "game")]
+use config::config;
+
+pub struct ExtraPalettes {
+    pub palettes: Vec<bw::RemapPalette>,
+}
+
+// The palette data is never modified or freed once loaded.
+unsafe impl Send for ExtraPalettes {}
+unsafe impl Sync for ExtraPalettes {}
+
+#[cfg(feature = "game")]
+lazy_static! {
+    static ref EXTRA_PALETTES: ExtraPalettes = unsafe { ExtraPalettes::load() };
+}
+
+/// The palettes loaded by the plugin. Has to be first called once storm has been patched.
+#[cfg(feature = "game")]
+pub fn extra_palettes() -> &'static [bw::RemapPalette] {
+    &EXTRA_PALETTES.palettes
+}
+
+impl ExtraPalettes {
+    #[cfg(feature = "game")]
+    unsafe fn load() -> ExtraPalettes {
+        let mut palettes = Vec::new();
+        for name in &config().remap_palettes {
+            let filename = format!("game\\{}.pcx", name);
+            let data = match ::storm_load_pcx(&filename) {
+                Some((data, _, _)) if data.len() >= 0x100 => data,
+                Some(_) => {
+                    error!("{} is too small to be a remap palette", filename);
+                    continue;
+                }
+                None => {
+                    error!("Couldn't load remap palette {}", filename);
+                    continue;
+                }
+            };
+            let id = (*bw::remap_palettes).len() + palettes.len();
+            let data = Box::leak(data.into_boxed_slice()).as_ptr();
+            match remap_palette(name, id as u32, data) {
+                Some(s) => palettes.push(s),
+                None => error!("Remap palette name {} is too long", name),
+            }
+        }
+        ExtraPalettes {
+            palettes,
+        }
+    }
+}
+
+/// Creates a palette entry like the ones in `bw::remap_palettes`. The name has to fit
+/// in the entry with a terminating zero.
+pub fn remap_palette(name: &str, id: u32, data: *const u8) -> Option<bw::RemapPalette> {
+    let mut name_buf = [0u8; 0xc];
+    if name.len() >= name_buf.len() {
+        return None;
+    }
+    name_buf[..name.len()].copy_from_slice(name.as_bytes());
+    Some(bw::RemapPalette {
+        id,
+        data,
+        name: name_buf,
+    })
+}
+
+pub fn name(palette: &bw::RemapPalette) -> String {
+    let name = palette.name;
+    let len = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+/// Returns the data of a palette with the given name, from BW's palettes and the ones
+/// loaded by the plugin, or null if there isn't one. For other plugins that create images
+/// with the remapping draw function.
+#[cfg(feature = "game")]
+#[no_mangle]
+pub unsafe extern fn GetRemapPalette(name: *const c_char) -> *const u8 {
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(o) => o,
+        Err(_) => return null(),
+    };
+    (*bw::remap_palettes).iter()
+        .chain(extra_palettes())
+        .find(|x| self::name(x) == name)
+        .map(|x| x.data)
+        .unwrap_or(null())
+}
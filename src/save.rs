@@ -1,15 +1,23 @@
+use std::cmp::{max, min};
 use std::collections::HashMap;
-use std::io;
+use std::io::{self, Read, Write};
 use std::iter::{Extend, FromIterator};
-use std::mem;
+use std::ops::Range;
 use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
 
 use bincode;
-use libc::c_void;
+use byteorder::{LittleEndian, ReadBytesExt};
+use flate2;
+use flate2::Compression;
 
-use bw;
 use send_pointer::SendPtr;
 
+#[cfg(feature = "game")]
+pub use self::bw_io::*;
+
 quick_error! {
     #[derive(Debug)]
     pub enum SaveError {
@@ -39,78 +47,192 @@ quick_error! {
         InvalidUnitAi(ai: u8) {
             display("Internal error: Invalid unit ai type {}", ai)
         }
+        CompressionThread {
+            display("Internal error: Compression thread panicked")
+        }
+        NotInGame {
+            display("Internal error: Saving while not in a game")
+        }
+        Context(context: String, err: Box<SaveError>) {
+            display("{}: {}", context, err)
+        }
     }
 }
 
 quick_error! {
     #[derive(Debug)]
     pub enum LoadError {
-        BwIo {
-            display("Broodwar I/O error")
-        }
         Serialize(err: bincode::Error) {
             display("Deserialization error: {}", err)
             from()
         }
+        Io(err: io::Error) {
+            display("I/O error: {}", err)
+            from()
+        }
         SizeLimit {
             display("Too large chunk")
         }
         WrongMagic(m: u16) {
             display("Incorrect magic: 0x{:x}", m)
         }
-        Version(ver: u32) {
-            display("Unsupported (newer?) version {}", ver)
+        Version(ver: u32, supported: u32) {
+            display("Unsupported version {} (expected {})", ver, supported)
         }
         Corrupted(info: String) {
             display("Invalid save data ({})", info)
         }
+        LimitExceeded(what: &'static str, needed: u32, max: u32) {
+            display("The save needs space for {} {}, but only {} are supported", needed, what, max)
+        }
+        Context(context: String, err: Box<LoadError>) {
+            display("{}: {}", context, err)
+        }
     }
 }
 
-pub unsafe fn fread_num<T>(file: *mut c_void) -> Result<T, LoadError> {
-    let mut val = mem::MaybeUninit::<T>::uninit();
-    let ok = bw::fread(val.as_mut_ptr() as *mut c_void, mem::size_of::<T>() as u32, 1, file);
-    if ok != 1 {
-        Err(LoadError::BwIo)
-    } else {
-        Ok(val.assume_init())
-    }
+/// Describes which object or field was being saved or loaded when an error happened.
+///
+/// The contexts nest, so an error ends up displayed as e.g.
+/// `Unit chunk: Unit 0x12: entity: sprite: Invalid sprite id 0x1234`.
+pub trait ErrorContext {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Self;
 }
 
-pub unsafe fn fread(file: *mut c_void, size: u32) -> Result<Vec<u8>, LoadError> {
-    let mut buf = Vec::with_capacity(size as usize);
-    let ok = bw::fread(buf.as_mut_ptr() as *mut c_void, size, 1, file);
-    if ok != 1 {
-        Err(LoadError::BwIo)
-    } else {
-        buf.set_len(size as usize);
-        Ok(buf)
+impl<T> ErrorContext for Result<T, SaveError> {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Self {
+        self.map_err(|e| SaveError::Context(context().into(), Box::new(e)))
     }
 }
 
-pub unsafe fn fwrite_num<T>(file: *mut c_void, value: T) -> Result<(), SaveError> {
-    let amount =
-        bw::fwrite(&value as *const T as *const c_void, mem::size_of::<T>() as u32, 1, file);
-    if amount != 1 {
-        Err(SaveError::BwIo)
-    } else {
-        Ok(())
+impl<T> ErrorContext for Result<T, LoadError> {
+    fn context<C: Into<String>, F: FnOnce() -> C>(self, context: F) -> Self {
+        self.map_err(|e| LoadError::Context(context().into(), Box::new(e)))
     }
 }
 
-pub unsafe fn fwrite(file: *mut c_void, buf: &[u8]) -> Result<(), SaveError> {
-    let amount = bw::fwrite(buf.as_ptr() as *const c_void, buf.len() as u32, 1, file);
-    if amount != 1 {
-        Err(SaveError::BwIo)
-    } else {
-        Ok(())
+/// Chunks are split to at most this many blocks, which get compressed in parallel.
+const COMPRESSION_THREADS: usize = 4;
+/// Smaller chunks aren't worth splitting.
+const MIN_COMPRESSION_BLOCK_SIZE: usize = 0x10000;
+
+struct CompressionJob {
+    data: Arc<Vec<u8>>,
+    range: Range<usize>,
+    compression: Compression,
+    result: Sender<io::Result<Vec<u8>>>,
+}
+
+lazy_static! {
+    /// Worker threads which are kept for the rest of the process, so that saving doesn't
+    /// have to start new ones. Each worker has a queue of its own, so an idle worker
+    /// doesn't keep the others from receiving jobs.
+    static ref COMPRESSION_POOL: Mutex<Vec<Sender<CompressionJob>>> = {
+        let workers = (0..COMPRESSION_THREADS).map(|_| {
+            let (send, recv) = mpsc::channel::<CompressionJob>();
+            thread::spawn(move || {
+                for job in recv {
+                    let mut writer =
+                        flate2::write::DeflateEncoder::new(Vec::new(), job.compression);
+                    let result =
+                        writer.write_all(&job.data[job.range]).and_then(|()| writer.finish());
+                    // The chunk may have been abandoned already.
+                    let _ = job.result.send(result);
+                }
+            });
+            send
+        }).collect();
+        Mutex::new(workers)
+    };
+}
+
+/// Splits a serialized chunk to blocks, and compresses them on the worker threads.
+///
+/// Returns the blocks in order, so that each one can be written while the later ones
+/// are still being compressed. A block fails to be received if its worker panicked.
+pub fn compress_blocks(
+    data: Vec<u8>,
+    compression: Compression,
+) -> Vec<Receiver<io::Result<Vec<u8>>>> {
+    let block_size = max(
+        MIN_COMPRESSION_BLOCK_SIZE,
+        data.len().div_ceil(COMPRESSION_THREADS),
+    );
+    let data = Arc::new(data);
+    let pool = COMPRESSION_POOL.lock().unwrap();
+    // There are at most as many blocks as workers, so each block gets a worker of its own.
+    (0..data.len()).step_by(block_size).enumerate().map(|(i, start)| {
+        let (send, recv) = mpsc::channel();
+        let job = CompressionJob {
+            data: data.clone(),
+            range: start..min(start + block_size, data.len()),
+            compression,
+            result: send,
+        };
+        // If the worker has panicked, the job gets dropped and the receiver fails.
+        let _ = pool[i % pool.len()].send(job);
+        recv
+    }).collect()
+}
+
+/// Reads the header of a chunk, and decompresses the data that was written with
+/// `fwrite_compressed`, failing if it would be larger than `max_size` bytes.
+pub fn read_chunk<R: Read>(
+    mut file: R,
+    magic: u16,
+    version: u32,
+    max_size: u32,
+) -> Result<Vec<u8>, LoadError> {
+    let saved_magic = file.read_u16::<LittleEndian>()?;
+    if saved_magic != magic {
+        return Err(LoadError::WrongMagic(saved_magic));
+    }
+    let saved_version = file.read_u32::<LittleEndian>()?;
+    if saved_version != version {
+        return Err(LoadError::Version(saved_version, version));
+    }
+    let block_count = file.read_u32::<LittleEndian>()?;
+    let mut out = Vec::new();
+    for _ in 0..block_count {
+        let size = file.read_u32::<LittleEndian>()?;
+        if size > max_size {
+            return Err(LoadError::Corrupted(format!("Block size {} is too large", size)));
+        }
+        let mut data = vec![0; size as usize];
+        file.read_exact(&mut data)?;
+        let limit = (max_size as usize + 1).saturating_sub(out.len()) as u64;
+        flate2::read::DeflateDecoder::new(&data[..]).take(limit).read_to_end(&mut out)?;
+        if out.len() > max_size as usize {
+            return Err(LoadError::SizeLimit);
+        }
     }
+    Ok(out)
 }
 
-pub unsafe fn print_text(msg: &str) {
-    let mut buf: Vec<u8> = msg.as_bytes().into();
-    buf.push(0);
-    bw::print_text(buf.as_ptr(), 0, 8);
+impl LoadError {
+    /// A guess at what the player could do about the error.
+    pub fn hint(&self) -> &'static str {
+        match *self {
+            LoadError::Context(_, ref err) => err.hint(),
+            LoadError::Version(ver, supported) if ver > supported => {
+                "The save was made with a newer version of more_bullets_yay."
+            }
+            LoadError::Version(..) => {
+                "The save was made with an older version of more_bullets_yay, \
+                which is no longer supported."
+            }
+            LoadError::WrongMagic(_) => {
+                "The save was made without more_bullets_yay, or with a different plugin \
+                that changes the save format."
+            }
+            LoadError::LimitExceeded(..) => {
+                "The save was made with a build of more_bullets_yay that has higher limits."
+            }
+            LoadError::Io(_) => "The save file could not be read.",
+            LoadError::Serialize(_) | LoadError::SizeLimit | LoadError::Corrupted(_) =>
+                "The save file may be corrupted.",
+        }
+    }
 }
 
 pub struct SaveMapping<T>(pub HashMap<SendPtr<T>, u32>);
@@ -168,3 +290,146 @@ impl<T> Default for LoadMapping<T> {
         LoadMapping(vec![])
     }
 }
+
+/// Reading and writing through BW's file functions.
+#[cfg(feature = "game")]
+mod bw_io {
+    use std::io::{self, Read};
+    use std::mem;
+    use std::ptr::null_mut;
+
+    use libc::c_void;
+
+    use bw;
+    use config::config;
+    use super::{compress_blocks, LoadError, SaveError};
+
+    /// The save file BW passes to chunk loading hooks.
+    pub struct BwFile(pub *mut c_void);
+
+    impl Read for BwFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            // BW's fread either reads everything that was asked for or fails.
+            let ok = unsafe {
+                bw::fread(buf.as_mut_ptr() as *mut c_void, buf.len() as u32, 1, self.0)
+            };
+            if ok != 1 {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Broodwar I/O error"))
+            } else {
+                Ok(buf.len())
+            }
+        }
+    }
+
+    pub unsafe fn fwrite_num<T>(file: *mut c_void, value: T) -> Result<(), SaveError> {
+        let amount =
+            bw::fwrite(&value as *const T as *const c_void, mem::size_of::<T>() as u32, 1, file);
+        if amount != 1 {
+            Err(SaveError::BwIo)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub unsafe fn fwrite(file: *mut c_void, buf: &[u8]) -> Result<(), SaveError> {
+        let amount = bw::fwrite(buf.as_ptr() as *const c_void, buf.len() as u32, 1, file);
+        if amount != 1 {
+            Err(SaveError::BwIo)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Compresses a serialized chunk on the worker threads, and writes it as a block count
+    /// followed by size and data of each compressed block.
+    ///
+    /// BW writes the rest of the save file after the hook returns, so the chunk has to be
+    /// written before that, and the game still waits for the compression. Only writing
+    /// the blocks overlaps with compressing the later ones.
+    pub unsafe fn fwrite_compressed(file: *mut c_void, data: Vec<u8>) -> Result<(), SaveError> {
+        let blocks = compress_blocks(data, config().compression);
+        fwrite_num(file, blocks.len() as u32)?;
+        for block in blocks {
+            let block = block.recv().map_err(|_| SaveError::CompressionThread)??;
+            fwrite_num(file, block.len() as u32)?;
+            fwrite(file, &block)?;
+        }
+        Ok(())
+    }
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn MessageBoxA(hwnd: *mut c_void, text: *const u8, caption: *const u8, flags: u32) -> i32;
+    }
+
+    const MB_ICONERROR: u32 = 0x10;
+
+    /// BW only tells the player that the save couldn't be loaded, so show the actual reason
+    /// in a message box.
+    pub unsafe fn show_load_error(err: &LoadError) {
+        let mut text: Vec<u8> =
+            format!("Unable to load the game: {}\n\n{}", err, err.hint()).into();
+        // Corrupted save data may end up in the message, and a nul byte would cut it short.
+        text.retain(|&x| x != 0);
+        text.push(0);
+        MessageBoxA(null_mut(), text.as_ptr(), b"more_bullets_yay\0".as_ptr(), MB_ICONERROR);
+    }
+
+    pub unsafe fn print_text(msg: &str) {
+        let mut buf: Vec<u8> = msg.as_bytes().into();
+        buf.push(0);
+        bw::print_text(buf.as_ptr(), 0, 8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use flate2::Compression;
+    use test::Bencher;
+
+    use super::{compress_blocks, read_chunk};
+
+    /// Something that compresses about as well as the serialized chunks.
+    fn chunk_data(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len).map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if i % 4 == 0 { state as u8 } else { 0 }
+        }).collect()
+    }
+
+    fn write_chunk(data: Vec<u8>) -> Vec<u8> {
+        let blocks = compress_blocks(data, Compression::Default);
+        let mut out = Vec::new();
+        out.write_u16::<LittleEndian>(0xffee).unwrap();
+        out.write_u32::<LittleEndian>(1).unwrap();
+        out.write_u32::<LittleEndian>(blocks.len() as u32).unwrap();
+        for block in blocks {
+            let block = block.recv().unwrap().unwrap();
+            out.write_u32::<LittleEndian>(block.len() as u32).unwrap();
+            out.extend_from_slice(&block);
+        }
+        out
+    }
+
+    #[test]
+    fn compressed_chunk() {
+        for &len in &[0, 0x100, 0x10000, 0x123457] {
+            let data = chunk_data(len);
+            let chunk = write_chunk(data.clone());
+            assert_eq!(read_chunk(&chunk[..], 0xffee, 1, 0x200000).unwrap(), data);
+        }
+    }
+
+    #[bench]
+    fn compress_large_chunk(b: &mut Bencher) {
+        let data = chunk_data(0x800000);
+        b.iter(|| write_chunk(data.clone()).len());
+    }
+}
@@ -0,0 +1,488 @@
+//! Layout of the chunks that the plugin writes to save files.
+//!
+//! Decoding a chunk only checks that its contents are consistent with each other, and doesn't
+//! touch any game state. Ids referring to things outside the chunk are checked once they get
+//! resolved to pointers.
+
+use std::collections::HashSet;
+use std::mem;
+
+use bincode;
+
+use bw;
+use save::{ErrorContext, LoadError};
+
+pub const SPRITE_SAVE_MAGIC: u16 = 0xffee;
+pub const SPRITE_SAVE_VERSION: u32 = 8;
+// 16 megabytes, should be more than enough, both compressed and without.
+pub const SPRITE_SAVE_MAX_SIZE: u32 = 0x1000000;
+
+pub const UNIT_SAVE_MAGIC: u16 = 0xffed;
+pub const UNIT_SAVE_VERSION: u32 = 4;
+// 16 megabytes, should be more than enough, both compressed and without.
+pub const UNIT_SAVE_MAX_SIZE: u32 = 0x1_000_000;
+
+pub const BULLET_SAVE_MAGIC: u16 = 0xffed;
+pub const BULLET_SAVE_VERSION: u32 = 3;
+// 8 megabytes, should be more than enough, both compressed and without.
+pub const BULLET_SAVE_MAX_SIZE: u32 = 0x800000;
+
+/// Length of BW's unit array.
+pub const UNIT_COUNT: u16 = 0x6a4;
+/// Length of each of BW's unit ai arrays.
+pub const UNIT_AI_COUNT: u16 = 1000;
+/// Length of BW's horizontal sprite line arrays.
+pub const HORIZONTAL_LINE_COUNT: usize = 0x100;
+
+/// Sizes of the arrays when a save was made, so that it can be checked to fit before loading.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Limits {
+    pub sprites: u32,
+    pub images: u32,
+}
+
+/// Written at the start of every chunk, as BW may load any of them first, and the arrays
+/// have to be prepared for the save before anything gets loaded to them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SaveHeader {
+    pub plugin_version: String,
+    pub limits: Limits,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EntitySerializable {
+    pub prev: u32,
+    pub next: u32,
+    pub hitpoints: i32,
+    pub sprite: u32,
+    pub move_target: bw::Point,
+    pub move_target_unit: u16,
+    pub next_move_waypoint: bw::Point,
+    pub unk_move_waypoint: bw::Point,
+    pub flingy_flags: u8,
+    pub facing_direction: u8,
+    pub flingy_turn_speed: u8,
+    pub movement_direction: u8,
+    pub flingy_id: u16,
+    pub unk_26: u8,
+    pub flingy_movement_type: u8,
+    pub position: bw::Point,
+    pub exact_position: bw::Point32,
+    pub flingy_top_speed: u32,
+    pub current_speed: i32,
+    pub next_speed: i32,
+    pub speed: i32,
+    pub speed2: i32,
+    pub acceleration: u16,
+    pub new_direction: u8,
+    pub target_direction: u8,
+    pub player: u8,
+    pub order: u8,
+    pub order_state: u8,
+    pub order_signal: u8,
+    pub order_fow_unit: u16,
+    pub unused52: u16,
+    pub order_timer: u8,
+    pub ground_cooldown: u8,
+    pub air_cooldown: u8,
+    pub spell_cooldown: u8,
+    pub order_target_pos: bw::Point,
+    pub target: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BulletGlobals {
+    pub first_bullet: u32,
+    pub last_bullet: u32,
+    pub bullet_count: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BulletSerializable {
+    pub entity: EntitySerializable,
+    /// Same as BW's field. An extended weapons.dat can have more weapons, but bullets
+    /// can't use the ones past 0xff, so there is nothing more to save.
+    pub weapon_id: u8,
+    pub death_timer: u8,
+    pub flags: u8,
+    pub bounces_remaining: u8,
+    pub parent: u16,
+    pub previous_bounce_target: u16,
+    pub spread_seed: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SpriteGlobals {
+    pub horizontal_lines: Vec<(u32, u32)>,
+    /// Size of the sprite array, including the free sprites which are not saved.
+    pub sprite_count: u32,
+    pub live_sprite_count: u32,
+    pub image_count: u32,
+    pub lone_count: u32,
+    pub fow_count: u32,
+    pub cursor_marker: u32,
+    /// Dat changes made during the game, in the order they were made.
+    pub dat_patches: Vec<DatPatch>,
+    /// Names of the remap palettes that drawfunc 9 params refer to with 1-based indices.
+    pub remap_palettes: Vec<String>,
+}
+
+/// A `bw_dat::Patch`, with the dat file as its number.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DatPatch {
+    pub dat: u8,
+    pub column: u8,
+    pub id: u32,
+    pub value: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SpriteSerializable {
+    pub prev: u32,
+    pub next: u32,
+    pub sprite_id: u16,
+    pub player: u8,
+    pub selection_index: u8,
+    pub visibility_mask: u8,
+    pub elevation: u8,
+    pub flags: u8,
+    pub selection_flash_timer: u8,
+    pub index: u16,
+    pub width: u8,
+    pub height: u8,
+    pub position: bw::Point,
+    pub main_image_id: u32,
+    pub images: Vec<ImageSerializable>,
+    pub extra: bw::SpriteExtension,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImageSerializable {
+    pub offset: usize,
+    pub image_id: u16,
+    pub drawfunc: u8,
+    pub direction: u8,
+    pub flags: u16,
+    pub x_offset: i8,
+    pub y_offset: i8,
+    pub iscript: bw::Iscript,
+    pub frameset: u16,
+    pub frame: u16,
+    pub map_position: bw::Point,
+    pub screen_position: [i16; 2],
+    pub grp_bounds: [i16; 4],
+    /// 1-based index to BW's grp table followed by the plugin's, see `grp_table`. With
+    /// extended images.dat there can be more than fit in an u16.
+    pub grp: u32,
+    pub drawfunc_param: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LoneSpriteSerializable {
+    pub sprite: u32,
+    pub value: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnitGlobals {
+    pub first_active: u16,
+    pub last_active: u16,
+    pub first_hidden: u16,
+    pub last_hidden: u16,
+    pub first_dying: u16,
+    pub last_dying: u16,
+    pub first_revealer: u16,
+    pub last_revealer: u16,
+    pub first_invisible: u16,
+    /// Units in the free list are not saved.
+    pub live_unit_count: u16,
+    pub player_units: [u16; 0xc],
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum UnitAiSerializable {
+    NoAi,
+    Guard(u16),
+    Worker(u16),
+    Building(u16),
+    Military(u16),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnitSpecificSerializable(pub [u8; 0x10]);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnitSpecificSerializable2(pub [u8; 0xc]);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RallyPylonSerializable {
+    pub val1: u16,
+    pub val2: u16,
+    pub val3: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnitSerializable {
+    pub entity: EntitySerializable,
+    pub shields: i32,
+    pub unit_id: u16,
+    pub unused66: u16,
+    pub next_player_unit: u16,
+    pub prev_player_unit: u16,
+    pub subunit: u16,
+    pub order_queue_begin: u16,
+    pub order_queue_end: u16,
+    pub previous_attacker: u16,
+    pub related: u16,
+    pub highlight_order_count: u8,
+    pub order_wait: u8,
+    pub unk86: u8,
+    pub attack_notify_timer: u8,
+    pub previous_unit_id: u16,
+    pub minimap_draw_counter: u8,
+    pub minimap_draw_color: u8,
+    pub unused8c: u16,
+    pub rank: u8,
+    pub kills: u8,
+    pub last_attacking_player: u8,
+    pub secondary_order_wait: u8,
+    pub ai_spell_flags: u8,
+    pub order_flags: u8,
+    pub buttons: u16,
+    pub invisibility_effects: u8,
+    pub movement_state: u8,
+    pub build_queue: [u16; 5],
+    pub energy: u16,
+    pub current_build_slot: u8,
+    pub minor_unique_index: u8,
+    pub secondary_order: u8,
+    pub building_overlay_state: u8,
+    pub build_hp_gain: u16,
+    pub build_shield_gain: u16,
+    pub remaining_build_time: u16,
+    pub previous_hp: u16,
+    pub loaded_units: [u16; 8],
+    pub unit_specific: UnitSpecificSerializable,
+    pub unit_specific2: UnitSpecificSerializable2,
+    pub flags: u32,
+    pub carried_powerup_flags: u8,
+    pub wireframe_seed: u8,
+    pub secondary_order_state: u8,
+    pub move_target_update_timer: u8,
+    pub detection_status: u32,
+    pub unke8: u16,
+    pub unkea: u16,
+    pub currently_building: u16,
+    pub next_invisible: u16,
+    pub prev_invisible: u16,
+    pub rally_pylon: RallyPylonSerializable,
+    pub path: u16,
+    pub path_frame: u8,
+    pub pathing_flags: u8,
+    pub _unk106: u8,
+    pub _unk107: u8,
+    pub collision_points: [u16; 0x4],
+    pub spells: UnitSpellsSerializable,
+    pub bullet_spread_seed: u16,
+    pub _padding132: [u8; 2],
+    pub ai: UnitAiSerializable,
+    pub air_strength: u16,
+    pub ground_strength: u16,
+    pub pos_search_left: u32,
+    pub pos_search_right: u32,
+    pub pos_search_top: u32,
+    pub pos_search_bottom: u32,
+    pub repulse: bw::Repulse,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnitSpellsSerializable {
+    pub death_timer: u16,
+    pub defensive_matrix_dmg: u16,
+    pub matrix_timer: u8,
+    pub stim_timer: u8,
+    pub ensnare_timer: u8,
+    pub lockdown_timer: u8,
+    pub irradiate_timer: u8,
+    pub stasis_timer: u8,
+    pub plague_timer: u8,
+    pub is_under_storm: u8,
+    pub irradiated_by: u16,
+    pub irradiate_player: u8,
+    pub parasited_by_players: u8,
+    pub master_spell_timer: u8,
+    pub is_blind: u8,
+    pub maelstrom_timer: u8,
+    pub _unk125: u8,
+    pub acid_spore_count: u8,
+    pub acid_spore_timers: [u8; 0x9],
+}
+
+#[derive(Serialize)]
+pub struct BulletChunk {
+    pub header: SaveHeader,
+    pub globals: BulletGlobals,
+    pub bullets: Vec<BulletSerializable>,
+}
+
+#[derive(Serialize)]
+pub struct SpriteChunk {
+    pub header: SaveHeader,
+    pub globals: SpriteGlobals,
+    /// Live sprites with their 1-based slot in the sprite array.
+    pub sprites: Vec<(u32, SpriteSerializable)>,
+    /// Lone sprites followed by fow sprites.
+    pub lone_sprites: Vec<LoneSpriteSerializable>,
+}
+
+#[derive(Serialize)]
+pub struct UnitChunk {
+    pub header: SaveHeader,
+    pub globals: UnitGlobals,
+    /// Live units with their 1-based slot in the unit array.
+    pub units: Vec<(u16, UnitSerializable)>,
+}
+
+fn check_id(id: u32, count: u32, what: &str) -> Result<(), LoadError> {
+    if id > count {
+        Err(LoadError::Corrupted(format!("Invalid {} id 0x{:x}", what, id)))
+    } else {
+        Ok(())
+    }
+}
+
+// The counts in the chunks can't be trusted, so the vectors are never preallocated
+// based on them. A corrupted count just makes deserialization fail once the data runs out.
+
+pub fn decode_bullets(data: &[u8]) -> Result<BulletChunk, LoadError> {
+    let mut reader = data;
+    let size_limit = bincode::Bounded(BULLET_SAVE_MAX_SIZE as u64);
+    let header: SaveHeader = bincode::deserialize_from(&mut reader, size_limit)?;
+    let globals: BulletGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
+    let count = globals.bullet_count;
+    check_id(globals.first_bullet, count, "bullet").context(|| "first_bullet")?;
+    check_id(globals.last_bullet, count, "bullet").context(|| "last_bullet")?;
+    let mut bullets = Vec::new();
+    for i in 0..count {
+        let bullet: BulletSerializable = bincode::deserialize_from(&mut reader, size_limit)?;
+        check_id(bullet.entity.prev, count, "bullet").context(|| "prev")
+            .and_then(|()| check_id(bullet.entity.next, count, "bullet").context(|| "next"))
+            .context(|| format!("Bullet {}", i + 1))?;
+        bullets.push(bullet);
+    }
+    Ok(BulletChunk {
+        header,
+        globals,
+        bullets,
+    })
+}
+
+pub fn decode_sprites(data: &[u8]) -> Result<SpriteChunk, LoadError> {
+    let mut reader = data;
+    let size_limit = bincode::Bounded(SPRITE_SAVE_MAX_SIZE as u64);
+    let header: SaveHeader = bincode::deserialize_from(&mut reader, size_limit)?;
+    let globals: SpriteGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
+    let sprite_count = globals.sprite_count;
+    if globals.horizontal_lines.len() > HORIZONTAL_LINE_COUNT {
+        return Err(LoadError::Corrupted(
+            format!("{} horizontal sprite lines", globals.horizontal_lines.len())
+        ));
+    }
+    for (i, &(begin, end)) in globals.horizontal_lines.iter().enumerate() {
+        check_id(begin, sprite_count, "sprite")
+            .and_then(|()| check_id(end, sprite_count, "sprite"))
+            .context(|| format!("Horizontal line {}", i))?;
+    }
+    if globals.live_sprite_count > sprite_count {
+        return Err(LoadError::Corrupted(
+            format!("{} live sprites out of {}", globals.live_sprite_count, sprite_count)
+        ));
+    }
+    let lone_total = globals.lone_count.checked_add(globals.fow_count).ok_or_else(|| {
+        LoadError::Corrupted(
+            format!("{} lone and {} fow sprites", globals.lone_count, globals.fow_count)
+        )
+    })?;
+    check_id(globals.cursor_marker, globals.lone_count, "lone sprite")
+        .context(|| "cursor_marker")?;
+
+    let mut sprite_ids = HashSet::new();
+    let mut image_offsets = HashSet::new();
+    let mut sprites = Vec::new();
+    for _ in 0..globals.live_sprite_count {
+        let (id, sprite): (u32, SpriteSerializable) =
+            bincode::deserialize_from(&mut reader, size_limit)?;
+        if id == 0 || id > sprite_count || !sprite_ids.insert(id) {
+            return Err(LoadError::Corrupted(format!("Invalid sprite id 0x{:x}", id)));
+        }
+        check_sprite(&sprite, &globals, &mut image_offsets)
+            .context(|| format!("Sprite 0x{:x} ({:x})", id, sprite.sprite_id))?;
+        sprites.push((id, sprite));
+    }
+    let mut lone_sprites = Vec::new();
+    for i in 0..lone_total {
+        let sprite: LoneSpriteSerializable = bincode::deserialize_from(&mut reader, size_limit)?;
+        check_id(sprite.sprite, sprite_count, "sprite")
+            .context(|| format!("Lone/fow sprite {}", i + 1))?;
+        lone_sprites.push(sprite);
+    }
+    Ok(SpriteChunk {
+        header,
+        globals,
+        sprites,
+        lone_sprites,
+    })
+}
+
+/// Checks the ids of a sprite, and that none of its images are used by other sprites.
+fn check_sprite(
+    sprite: &SpriteSerializable,
+    globals: &SpriteGlobals,
+    image_offsets: &mut HashSet<usize>,
+) -> Result<(), LoadError> {
+    check_id(sprite.prev, globals.sprite_count, "sprite").context(|| "prev")?;
+    check_id(sprite.next, globals.sprite_count, "sprite").context(|| "next")?;
+    if sprite.main_image_id as usize > sprite.images.len() {
+        return Err(LoadError::Corrupted(
+            format!("Invalid main image 0x{:x}", sprite.main_image_id)
+        ));
+    }
+    let image_size = mem::size_of::<bw::Image>();
+    for image in &sprite.images {
+        let offset = image.offset;
+        if offset % image_size != 0 ||
+            offset / image_size >= globals.image_count as usize ||
+            !image_offsets.insert(offset)
+        {
+            return Err(LoadError::Corrupted(format!("Invalid image offset 0x{:x}", offset)));
+        }
+    }
+    Ok(())
+}
+
+pub fn decode_units(data: &[u8]) -> Result<UnitChunk, LoadError> {
+    let mut reader = data;
+    let size_limit = bincode::Bounded(UNIT_SAVE_MAX_SIZE as u64);
+    let header: SaveHeader = bincode::deserialize_from(&mut reader, size_limit)?;
+    let globals: UnitGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
+    if globals.live_unit_count > UNIT_COUNT {
+        return Err(LoadError::Corrupted(format!("{} live units", globals.live_unit_count)));
+    }
+    let mut live_units = [false; UNIT_COUNT as usize];
+    let mut units = Vec::new();
+    for _ in 0..globals.live_unit_count {
+        let (id, unit): (u16, UnitSerializable) =
+            bincode::deserialize_from(&mut reader, size_limit)?;
+        let index = (id as usize).wrapping_sub(1);
+        if index >= live_units.len() || live_units[index] {
+            return Err(LoadError::Corrupted(format!("Invalid unit id 0x{:x}", id)));
+        }
+        live_units[index] = true;
+        units.push((id, unit));
+    }
+    Ok(UnitChunk {
+        header,
+        globals,
+        units,
+    })
+}
@@ -1,199 +1,84 @@
-use std::cell::{Cell, RefCell};
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::mem;
 use std::ptr::null_mut;
+use std::slice;
 
 use bincode;
-use flate2;
 use libc::c_void;
 
 use bw;
-use save::{fread_num, fread, fwrite, fwrite_num, SaveError, LoadError, print_text};
-use save::{SaveMapping, LoadMapping};
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
+use remap_palettes;
+use save::{ErrorContext, SaveError, LoadError};
+#[cfg(feature = "game")]
+use save::{fwrite_compressed, fwrite_num, read_chunk, print_text, show_load_error, BwFile};
+use save::LoadMapping;
+use save_format::{self, DatPatch, ImageSerializable, LoneSpriteSerializable, SpriteGlobals};
+use save_format::SpriteSerializable;
+use save_format::SPRITE_SAVE_MAX_SIZE;
+#[cfg(feature = "game")]
+use save_format::{SPRITE_SAVE_MAGIC, SPRITE_SAVE_VERSION};
 use send_pointer::SendPtr;
-use units::{unit_to_id, unit_from_id};
-
-const SPRITE_LIMIT: usize = 200000;
-const IMAGE_LIMIT: usize = 400000;
-
-ome2_thread_local! {
-    SPRITE_ARRAY: RefCell<RawVec<bw::Sprite>> =
-        sprite_array(RefCell::new(RawVec::with_capacity(SPRITE_LIMIT)));
-    IMAGE_ARRAY: RefCell<RawVec<bw::Image>> =
-        image_array(RefCell::new(RawVec::with_capacity(IMAGE_LIMIT)));
-    SPRITES: RefCell<HashSet<SendPtr<bw::Sprite>>> = all_sprites(RefCell::new(HashSet::new()));
-    // Both lone and fow
-    LONE_SPRITES: RefCell<HashSet<SendPtr<bw::LoneSprite>>> =
-        all_lone_sprites(RefCell::new(HashSet::new()));
-    NEXT_SPRITE_ID: Cell<u64> = next_sprite_id(Cell::new(0));
-    SPRITE_DRAW_BUFFER: RefCell<Vec<SendPtr<bw::Sprite>>> =
-        sprite_draw_buffer(RefCell::new(Vec::with_capacity(0x800)));
-    SPRITE_SAVE_MAPPING: RefCell<SaveMapping<bw::Sprite>> =
-        sprite_save_mapping(RefCell::new(SaveMapping::new()));
-    SPRITE_LOAD_MAPPING: RefCell<LoadMapping<bw::Sprite>> =
-        sprite_load_mapping(RefCell::new(LoadMapping::new()));
-    LONE_SPRITE_SAVE_MAPPING: RefCell<SaveMapping<bw::LoneSprite>> =
-        lone_sprite_save_mapping(RefCell::new(SaveMapping::new()));
-    LONE_SPRITE_LOAD_MAPPING: RefCell<LoadMapping<bw::LoneSprite>> =
-        lone_sprite_load_mapping(RefCell::new(LoadMapping::new()));
-}
-
-struct RawVec<T> {
-    ptr: *mut T,
-    size: usize,
-    capacity: usize,
-}
-
-unsafe impl<T> Send for RawVec<T> {}
-unsafe impl<T> Sync for RawVec<T> {}
-
-impl<T> RawVec<T> {
-    fn with_capacity(cap: usize) -> RawVec<T> {
-        let (ptr, size, capacity) = Vec::with_capacity(cap).into_raw_parts();
-        RawVec {
-            ptr,
-            size,
-            capacity,
-        }
-    }
-
-    fn push(&mut self) -> *mut T {
-        unsafe {
-            if self.size == self.capacity {
-                null_mut()
-            } else {
-                self.size += 1;
-                self.ptr.add(self.size - 1)
-            }
-        }
-    }
-
-    fn iter(&self) -> impl Iterator<Item = *mut T> {
-        unsafe {
-            let ptr = self.ptr;
-            (0..self.size).map(move |x| ptr.add(x))
-        }
-    }
-}
-
-const SPRITE_SAVE_MAGIC: u16 = 0xffee;
-// 16 megabytes, should be more than enough, both compressed and without.
-const SPRITE_SAVE_MAX_SIZE: u32 = 0x1000000;
-
-#[derive(Serialize, Deserialize)]
-struct SaveGlobals {
-    horizontal_lines: Vec<(u32, u32)>,
-    sprite_count: u32,
-    lone_count: u32,
-    fow_count: u32,
-    cursor_marker: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct SpriteSerializable {
-    prev: u32,
-    next: u32,
-    sprite_id: u16,
-    player: u8,
-    selection_index: u8,
-    visibility_mask: u8,
-    elevation: u8,
-    flags: u8,
-    selection_flash_timer: u8,
-    index: u16,
-    width: u8,
-    height: u8,
-    position: bw::Point,
-    main_image_id: u32,
-    images: Vec<ImageSerializable>,
-    extra: bw::SpriteExtension,
-}
-
-#[derive(Serialize, Deserialize)]
-struct ImageSerializable {
-    offset: usize,
-    image_id: u16,
-    drawfunc: u8,
-    direction: u8,
-    flags: u16,
-    x_offset: i8,
-    y_offset: i8,
-    iscript: bw::Iscript,
-    frameset: u16,
-    frame: u16,
-    map_position: bw::Point,
-    screen_position: [i16; 2],
-    grp_bounds: [i16; 4],
-    grp: u16,
-    drawfunc_param: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct LoneSpriteSerializable {
-    sprite: u32,
-    value: u32,
-}
-
-fn allocate_sprite() -> *mut bw::Sprite {
-    let mut sprites = sprite_array().borrow_mut();
-    sprites.push()
-}
-
-fn allocate_image() -> *mut bw::Image {
-    let mut array = image_array().borrow_mut();
-    array.push()
-}
-
-unsafe fn refill_sprite_image_list() {
-    let mut sprites = all_sprites().borrow_mut();
-    if sprites.len() == 0 {
+#[cfg(feature = "game")]
+use state::Chunk;
+use state::{plugin_state, LoneSpriteStats, PluginState, RawVec};
+use units::{self, unit_to_id, unit_from_id};
+
+pub unsafe fn refill_sprite_image_list<M: GameMemory>(game: &M, state: &mut PluginState) {
+    let first_free_sprite = game.first_free_sprite();
+    let first_free_image = game.first_free_image();
+    if state.sprites.len() == 0 {
         // Init
-        *bw::first_free_sprite = null_mut();
-        *bw::last_free_sprite = null_mut();
-        *bw::first_free_image = null_mut();
-        *bw::last_free_image = null_mut();
+        *first_free_sprite = null_mut();
+        *game.last_free_sprite() = null_mut();
+        *first_free_image = null_mut();
+        *game.last_free_image() = null_mut();
     }
-    let sprite_count = BwLinkedListIter(*bw::first_free_sprite).count();
+    let sprite_count = BwLinkedListIter(*first_free_sprite).count();
     if sprite_count < 500 {
         for _ in 0..(500 - sprite_count) {
-            let sprite = allocate_sprite();
+            let sprite = state.sprite_array.push();
             if sprite.is_null() {
                 break;
             }
             *sprite = bw::Sprite {
-                next: *bw::first_free_sprite,
+                next: *first_free_sprite,
                 ..mem::zeroed()
             };
-            if *bw::first_free_sprite != null_mut() {
-                (**bw::first_free_sprite).prev = sprite;
+            if *first_free_sprite != null_mut() {
+                (**first_free_sprite).prev = sprite;
             } else {
-                *bw::last_free_sprite = sprite;
+                *game.last_free_sprite() = sprite;
             }
-            *bw::first_free_sprite = sprite;
-            sprites.insert(sprite.into());
+            *first_free_sprite = sprite;
+            state.sprites.insert(SendPtr(sprite));
         }
     }
-    let image_count = BwLinkedListIter(*bw::first_free_image).count();
+    let image_count = BwLinkedListIter(*first_free_image).count();
     if image_count < 1500 {
         for _ in 0..(1500 - image_count) {
-            let image = allocate_image();
+            let image = state.image_array.push();
             if image.is_null() {
                 break;
             }
             *image = bw::Image {
-                next: *bw::first_free_image,
+                next: *first_free_image,
                 ..mem::zeroed()
             };
-            if *bw::first_free_image != null_mut() {
-                (**bw::first_free_image).prev = image;
+            if *first_free_image != null_mut() {
+                (**first_free_image).prev = image;
             } else {
-                *bw::last_free_image = image;
+                *game.last_free_image() = image;
             }
-            *bw::first_free_image = image;
+            *first_free_image = image;
         }
     }
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn create_sprite(
     sprite_id: u32,
     x: u32,
@@ -201,22 +86,24 @@ pub unsafe fn create_sprite(
     player: u32,
     orig: unsafe extern fn(u32, u32, u32, u32) -> *mut bw::Sprite,
 ) -> *mut bw::Sprite {
-    if *bw::scmain_state != 3 {
-        // Lobby minimap preview, since this plugin doesn't reset sprites aren
-        // on game init (only end), but BW does, calling the hook is problematic in lobby.
+    let in_game = plugin_state().borrow().is_in_game();
+    if !in_game {
+        // Lobby minimap preview, BW uses its own sprite arrays for it.
         return orig(sprite_id, x, y, player);
     }
-    refill_sprite_image_list();
+    refill_sprite_image_list(&BwMemory, &mut plugin_state().borrow_mut());
     let actual_sprite = orig(sprite_id, x, y, player);
 
     if actual_sprite != null_mut() {
-        let cell = next_sprite_id();
-        (*actual_sprite).extra.spawn_order = (cell.get() as u32, (cell.get() >> 32) as u32);
-        cell.set(cell.get().checked_add(1).unwrap());
+        let mut state = plugin_state().borrow_mut();
+        let id = state.next_sprite_id;
+        (*actual_sprite).extra.spawn_order = (id as u32, (id >> 32) as u32);
+        state.next_sprite_id = id.saturating_add(1);
     }
     actual_sprite
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn create_lone(
     sprite_id: u32,
     x: u32,
@@ -245,11 +132,13 @@ pub unsafe fn create_lone(
         );
     }
 
-    let mut sprites = all_lone_sprites().borrow_mut();
-    sprites.insert(sprite.into());
+    let mut state = plugin_state().borrow_mut();
+    state.lone_sprites.insert(sprite.into());
+    state.lone_sprite_stats.created += 1;
     sprite
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn create_fow(
     unit_id: u32,
     base: *mut bw::Sprite,
@@ -276,15 +165,18 @@ pub unsafe fn create_fow(
         );
     }
 
-    let mut sprites = all_lone_sprites().borrow_mut();
-    sprites.insert(sprite.into());
+    let mut state = plugin_state().borrow_mut();
+    state.lone_sprites.insert(sprite.into());
+    state.lone_sprite_stats.created += 1;
     sprite
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn delete_sprite(sprite: *mut bw::Sprite, orig: unsafe extern fn(*mut bw::Sprite)) {
     orig(sprite);
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn step_lone_frame(
     sprite: *mut bw::LoneSprite,
     orig: unsafe extern fn(*mut bw::LoneSprite),
@@ -294,13 +186,15 @@ pub unsafe fn step_lone_frame(
     orig(sprite);
     if *bw::first_free_lone_sprite == sprite {
         let _ = Box::from_raw(sprite);
-        let mut sprites = all_lone_sprites().borrow_mut();
-        sprites.remove(&sprite.into());
+        let mut state = plugin_state().borrow_mut();
+        state.lone_sprites.remove(&sprite.into());
+        state.lone_sprite_stats.freed += 1;
     }
     *bw::first_free_lone_sprite = null_mut();
     *bw::last_free_lone_sprite = null_mut();
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn step_fow_frame(
     sprite: *mut bw::LoneSprite,
     orig: unsafe extern fn(*mut bw::LoneSprite),
@@ -310,38 +204,129 @@ pub unsafe fn step_fow_frame(
     orig(sprite);
     if *bw::first_free_fow_sprite == sprite {
         let _ = Box::from_raw(sprite);
-        let mut sprites = all_lone_sprites().borrow_mut();
-        sprites.remove(&sprite.into());
+        let mut state = plugin_state().borrow_mut();
+        state.lone_sprites.remove(&sprite.into());
+        state.lone_sprite_stats.freed += 1;
     }
     *bw::first_free_fow_sprite = null_mut();
     *bw::last_free_fow_sprite = null_mut();
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn create_image(orig: unsafe extern fn() -> *mut bw::Image) -> *mut bw::Image {
     orig()
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn delete_image(image: *mut bw::Image, orig: unsafe extern fn(*mut bw::Image)) {
     orig(image);
 }
 
-pub unsafe fn delete_all() {
-    let mut sprites = all_sprites().borrow_mut();
-    sprites.clear();
-    sprite_array().borrow_mut().size = 0;
-    image_array().borrow_mut().size = 0;
+pub unsafe fn delete_all<M: GameMemory>(game: &M, state: &mut PluginState) {
+    state.sprites.clear();
+    state.sprite_array.size = 0;
+    state.image_array.size = 0;
+    state.next_sprite_id = 0;
+    state.sprite_draw_buffer.clear();
+    state.sprite_draw_screen = None;
+    let reclaimed = free_lone_sprites(game, state);
+    let stats = mem::take(&mut state.lone_sprite_stats);
+    let leaked = stats.created.saturating_sub(stats.freed).saturating_sub(reclaimed as u64);
+    info!(
+        "Lone/fow sprites: {} created, {} freed during game, {} reclaimed at game end",
+        stats.created, stats.freed, reclaimed,
+    );
+    if leaked != 0 {
+        warn!("{} lone/fow sprites were lost track of", leaked);
+    }
 }
 
-unsafe fn is_selection_image(image: *mut bw::Image) -> bool {
-    ((*image).image_id >= 0x231 && (*image).image_id <= 0x23a) || (*image).drawfunc == 0xb
+/// Frees every lone and fow sprite allocated by the plugin, and clears BW's lists which
+/// would otherwise point to the freed sprites. Returns the amount of sprites freed.
+unsafe fn free_lone_sprites<M: GameMemory>(game: &M, state: &mut PluginState) -> usize {
+    let count = state.lone_sprites.len();
+    for sprite in state.lone_sprites.drain() {
+        let _ = Box::from_raw(*sprite);
+    }
+    *game.first_active_lone_sprite() = null_mut();
+    *game.last_active_lone_sprite() = null_mut();
+    *game.first_active_fow_sprite() = null_mut();
+    *game.last_active_fow_sprite() = null_mut();
+    *game.cursor_marker() = null_mut();
+    count
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn add_to_drawn_sprites(sprite: *mut bw::Sprite) {
-    let mut buf = sprite_draw_buffer().borrow_mut();
-    buf.push(sprite.into());
+    let mut state = plugin_state().borrow_mut();
+    let screen = *state.sprite_draw_screen.get_or_insert_with(|| ScreenRect::current());
+    if is_potentially_visible(sprite, &screen) {
+        state.sprite_draw_buffer.push(sprite.into());
+    }
     sprite_vision_sync(sprite);
 }
 
+// The entire game screen, including the area behind console, as images can be drawn
+// there as well.
+const SCREEN_WIDTH: i32 = 640;
+const SCREEN_HEIGHT: i32 = 480;
+// Extra slack for anything that is drawn slightly outside the grp frame.
+const CULL_MARGIN: i32 = 32;
+
+#[derive(Copy, Clone)]
+pub struct ScreenRect {
+    left: i32,
+    top: i32,
+    right: i32,
+    bottom: i32,
+}
+
+impl ScreenRect {
+    #[cfg(feature = "game")]
+    unsafe fn current() -> ScreenRect {
+        let x = *bw::screen_x as i32;
+        let y = *bw::screen_y as i32;
+        ScreenRect {
+            left: x - CULL_MARGIN,
+            top: y - CULL_MARGIN,
+            right: x + SCREEN_WIDTH + CULL_MARGIN,
+            bottom: y + SCREEN_HEIGHT + CULL_MARGIN,
+        }
+    }
+}
+
+/// Returns false only if none of the sprite's images can end up on the screen.
+///
+/// BW draws the images centered at sprite position + image offset, so half of grp's
+/// dimensions is the furthest any frame can extend. `grp_bounds` is only used as a fallback,
+/// as it is updated once the image gets drawn, and would be stale for sprites that just
+/// scrolled into view.
+unsafe fn is_potentially_visible(sprite: *mut bw::Sprite, screen: &ScreenRect) -> bool {
+    let x = (*sprite).position.x as i32;
+    let y = (*sprite).position.y as i32;
+    let mut image = (*sprite).first_overlay;
+    while image != null_mut() {
+        let grp = (*image).grp;
+        let (width, height) = if grp != null_mut() {
+            ((*grp).width as i32, (*grp).height as i32)
+        } else {
+            ((*image).grp_bounds[2] as i32, (*image).grp_bounds[3] as i32)
+        };
+        let image_x = x + (*image).x_offset as i32;
+        let image_y = y + (*image).y_offset as i32;
+        let visible = image_x + width / 2 >= screen.left &&
+            image_x - width / 2 <= screen.right &&
+            image_y + height / 2 >= screen.top &&
+            image_y - height / 2 <= screen.bottom;
+        if visible {
+            return true;
+        }
+        image = (*image).next;
+    }
+    false
+}
+
+#[cfg(feature = "game")]
 unsafe fn sprite_vision_sync(sprite: *mut bw::Sprite) {
     use std::cmp::{max, min};
     let sync = *(*bw::sprite_include_in_vision_sync).offset((*sprite).sprite_id as isize);
@@ -351,8 +336,10 @@ unsafe fn sprite_vision_sync(sprite: *mut bw::Sprite) {
     }
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn draw_sprites() {
-    let mut buf = sprite_draw_buffer().borrow_mut();
+    // Not keeping the state borrowed while BW draws the sprites.
+    let mut buf = mem::replace(&mut plugin_state().borrow_mut().sprite_draw_buffer, Vec::new());
     buf.sort_by(|&SendPtr(a), &SendPtr(b)| {
         use std::cmp::Ordering;
         match (*a).elevation.cmp(&(*b).elevation) {
@@ -375,88 +362,150 @@ pub unsafe fn draw_sprites() {
     for &SendPtr(sprite) in buf.iter() {
         bw::draw_sprite(sprite);
     }
+    plugin_state().borrow_mut().sprite_draw_buffer = buf;
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn redraw_screen_hook(orig: unsafe extern fn()) {
     orig();
     // The buffer may be filled but not used if the screen doesn't need to be redrawn.
-    let mut buf = sprite_draw_buffer().borrow_mut();
-    buf.clear();
+    let mut state = plugin_state().borrow_mut();
+    state.sprite_draw_buffer.clear();
+    // The screen may have moved by the next frame.
+    state.sprite_draw_screen = None;
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn save_sprite_chunk(file: *mut c_void) -> u32 {
-    if let Err(e) = save_sprites(file) {
-        error!("Couldn't save sprites: {}", e);
+    if let Err(e) = save_sprites(file).context(|| "Sprites") {
+        plugin_state().borrow_mut().save_failed();
+        error!("Couldn't save: {}", e);
         print_text(&format!("Unable to save the game: {}", e));
         return 0;
     }
+    plugin_state().borrow_mut().chunk_saved(Chunk::Sprites);
     1
 }
 
+#[cfg(feature = "game")]
 unsafe fn save_sprites(file: *mut c_void) -> Result<(), SaveError> {
-    let data = serialize_sprites()?;
+    plugin_state().borrow_mut().begin_save(Chunk::Sprites)?;
+    let data = serialize_sprites(&BwMemory)?;
     fwrite_num(file, SPRITE_SAVE_MAGIC)?;
-    fwrite_num(file, 1u32)?;
-    fwrite_num(file, data.len() as u32)?;
-    fwrite(file, &data)?;
+    fwrite_num(file, SPRITE_SAVE_VERSION)?;
+    fwrite_compressed(file, data)?;
     Ok(())
 }
 
-unsafe fn serialize_sprites() -> Result<Vec<u8>, SaveError> {
-    let ptr_to_id_map = sprite_pointer_to_id_map();
-    let lone_ptr_to_id_map = lone_sprite_pointer_to_id_map();
+pub unsafe fn serialize_sprites<M: GameMemory>(game: &M) -> Result<Vec<u8>, SaveError> {
+    let state = plugin_state().borrow();
+    let sprites = &state.sprite_array;
 
-    let buf = Vec::with_capacity(0x10000);
-    let mut writer = flate2::write::DeflateEncoder::new(buf, flate2::Compression::Default);
+    let mut buf = Vec::with_capacity(0x10000);
 
     let size_limit = bincode::Bounded(SPRITE_SAVE_MAX_SIZE as u64);
-    let horizontal_lines = (0..*bw::map_height_tiles as usize).map(|i| {
-        Ok((ptr_to_id_map.id(bw::horizontal_sprite_lines_begin[i])?,
-            ptr_to_id_map.id(bw::horizontal_sprite_lines_end[i])?))
-    }).collect::<Result<Vec<_>, SaveError>>()?;
-    let globals = SaveGlobals {
+    let lines_begin = &*game.horizontal_sprite_lines_begin();
+    let lines_end = &*game.horizontal_sprite_lines_end();
+    let horizontal_lines = (0..*game.map_height_tiles() as usize).map(|i| {
+        Ok((sprite_array_id(sprites, lines_begin[i])?, sprite_array_id(sprites, lines_end[i])?))
+    }).collect::<Result<Vec<_>, SaveError>>().context(|| "horizontal_lines")?;
+    let free_sprites =
+        free_slots(sprites, *game.first_free_sprite()).context(|| "Free sprites")?;
+    let globals = SpriteGlobals {
         horizontal_lines,
-        sprite_count: ptr_to_id_map.len() as u32,
-        lone_count: lone_ptr_to_id_map.len() as u32,
-        fow_count: lone_sprites(*bw::first_active_fow_sprite).count() as u32,
-        cursor_marker: lone_ptr_to_id_map.id(*bw::cursor_marker)?,
+        sprite_count: sprites.size as u32,
+        live_sprite_count: free_sprites.iter().filter(|&&free| !free).count() as u32,
+        image_count: state.image_array.size as u32,
+        lone_count: lone_sprites(*game.first_active_lone_sprite()).count() as u32,
+        fow_count: lone_sprites(*game.first_active_fow_sprite()).count() as u32,
+        cursor_marker: lone_sprite_to_id(game, *game.cursor_marker())
+            .context(|| "cursor_marker")?,
+        dat_patches: game.dat_patches(),
+        remap_palettes: all_remap_palettes(game).into_iter().map(|x| x.0).collect(),
     };
-    bincode::serialize_into(&mut writer, &globals, size_limit)?;
-    {
-        let sprites = sprite_array().borrow_mut();
-        for sprite in sprites.iter() {
-            let serializable = sprite_serializable(sprite, &ptr_to_id_map)?;
-            bincode::serialize_into(&mut writer, &serializable, size_limit)?;
-            if writer.total_in() > SPRITE_SAVE_MAX_SIZE as u64 {
-                return Err(SaveError::SizeLimit(writer.total_in()));
-            }
+    bincode::serialize_into(&mut buf, &state.save_header(), size_limit)?;
+    bincode::serialize_into(&mut buf, &globals, size_limit)?;
+    let image_array = &state.image_array;
+    let pointer_ids = ImagePointerIds::current(game);
+    for (i, sprite) in sprites.iter().enumerate() {
+        if free_sprites[i] {
+            continue;
+        }
+        let serializable = sprite_serializable(game, sprite, sprites, image_array, &pointer_ids)
+            .context(|| format!("Sprite 0x{:x} ({:x})", i + 1, (*sprite).sprite_id))?;
+        bincode::serialize_into(&mut buf, &(i as u32 + 1, serializable), size_limit)?;
+        if buf.len() > SPRITE_SAVE_MAX_SIZE as usize {
+            return Err(SaveError::SizeLimit(buf.len() as u64));
+        }
+    }
+    for (i, sprite) in lone_sprites(*game.first_active_lone_sprite()).enumerate() {
+        let serializable = lone_sprite_serializable(sprite, sprites)
+            .context(|| format!("Lone sprite {}", i + 1))?;
+        bincode::serialize_into(&mut buf, &serializable, size_limit)?;
+        if buf.len() > SPRITE_SAVE_MAX_SIZE as usize {
+            return Err(SaveError::SizeLimit(buf.len() as u64));
+        }
+    }
+    for (i, sprite) in lone_sprites(*game.first_active_fow_sprite()).enumerate() {
+        let serializable = lone_sprite_serializable(sprite, sprites)
+            .context(|| format!("Fow sprite {}", i + 1))?;
+        bincode::serialize_into(&mut buf, &serializable, size_limit)?;
+        if buf.len() > SPRITE_SAVE_MAX_SIZE as usize {
+            return Err(SaveError::SizeLimit(buf.len() as u64));
         }
     }
-    for sprite in lone_sprites(*bw::first_active_lone_sprite) {
-        let serializable = lone_sprite_serializable(sprite, &ptr_to_id_map)?;
-        bincode::serialize_into(&mut writer, &serializable, size_limit)?;
-        if writer.total_in() > SPRITE_SAVE_MAX_SIZE as u64 {
-            return Err(SaveError::SizeLimit(writer.total_in()));
+    Ok(buf)
+}
+
+/// Marks the slots of `array` which are in BW's free list, those don't have to be saved.
+unsafe fn free_slots<T: BwLinkedListObject>(
+    array: &RawVec<T>,
+    first_free: *mut T,
+) -> Result<Vec<bool>, SaveError> {
+    let mut free = vec![false; array.size];
+    for ptr in BwLinkedListIter(first_free) {
+        match array.id(ptr) {
+            Some(id) => free[id as usize - 1] = true,
+            None => return Err(SaveError::InvalidPointer),
         }
     }
-    for sprite in lone_sprites(*bw::first_active_fow_sprite) {
-        let serializable = lone_sprite_serializable(sprite, &ptr_to_id_map)?;
-        bincode::serialize_into(&mut writer, &serializable, size_limit)?;
-        if writer.total_in() > SPRITE_SAVE_MAX_SIZE as u64 {
-            return Err(SaveError::SizeLimit(writer.total_in()));
+    Ok(free)
+}
+
+/// Zeroes the slots of `array` which are not marked as live, and links them to a free list
+/// in index order.
+unsafe fn rebuild_free_list<T: BwLinkedListObject>(
+    array: &RawVec<T>,
+    live: &[bool],
+    first_free: *mut *mut T,
+    last_free: *mut *mut T,
+) {
+    let mut prev = null_mut();
+    *first_free = null_mut();
+    for (ptr, _) in array.iter().zip(live.iter()).filter(|&(_, &live)| !live) {
+        *ptr = mem::zeroed();
+        T::set_links(ptr, prev, null_mut());
+        if prev == null_mut() {
+            *first_free = ptr;
+        } else {
+            T::set_links(prev, T::prev(prev), ptr);
         }
+        prev = ptr;
     }
+    *last_free = prev;
+}
 
-    let mut global_mapping = sprite_save_mapping().borrow_mut();
-    *global_mapping = ptr_to_id_map;
-    let mut lone_global = lone_sprite_save_mapping().borrow_mut();
-    *lone_global = lone_ptr_to_id_map;
-    Ok(writer.finish()?)
+fn sprite_array_id(
+    sprites: &RawVec<bw::Sprite>,
+    sprite: *mut bw::Sprite,
+) -> Result<u32, SaveError> {
+    sprites.id(sprite).ok_or(SaveError::InvalidPointer)
 }
 
-unsafe fn sprite_pointer_to_id_map() -> SaveMapping<bw::Sprite> {
-    let sprites = sprite_array().borrow_mut();
-    sprites.iter().enumerate().map(|(x, y)| (y.into(), x as u32 + 1)).collect()
+fn sprite_pointer(sprites: &RawVec<bw::Sprite>, id: u32) -> Result<*mut bw::Sprite, LoadError> {
+    sprites.pointer(id).ok_or_else(|| {
+        LoadError::Corrupted(format!("Invalid sprite id 0x{:x}", id))
+    })
 }
 
 unsafe fn lone_sprites(ptr: *mut bw::LoneSprite) -> BwLinkedListIter<bw::LoneSprite> {
@@ -467,24 +516,57 @@ struct BwLinkedListIter<T: BwLinkedListObject>(*mut T);
 
 trait BwLinkedListObject {
     unsafe fn next(val: *mut Self) -> *mut Self;
+    unsafe fn prev(val: *mut Self) -> *mut Self;
+    unsafe fn set_links(val: *mut Self, prev: *mut Self, next: *mut Self);
 }
 
 impl BwLinkedListObject for bw::Sprite {
     unsafe fn next(val: *mut bw::Sprite) -> *mut bw::Sprite {
         (*val).next
     }
+
+    unsafe fn prev(val: *mut bw::Sprite) -> *mut bw::Sprite {
+        (*val).prev
+    }
+
+    unsafe fn set_links(val: *mut bw::Sprite, prev: *mut bw::Sprite, next: *mut bw::Sprite) {
+        (*val).prev = prev;
+        (*val).next = next;
+    }
 }
 
 impl BwLinkedListObject for bw::LoneSprite {
     unsafe fn next(val: *mut bw::LoneSprite) -> *mut bw::LoneSprite {
         (*val).next
     }
+
+    unsafe fn prev(val: *mut bw::LoneSprite) -> *mut bw::LoneSprite {
+        (*val).prev
+    }
+
+    unsafe fn set_links(
+        val: *mut bw::LoneSprite,
+        prev: *mut bw::LoneSprite,
+        next: *mut bw::LoneSprite,
+    ) {
+        (*val).prev = prev;
+        (*val).next = next;
+    }
 }
 
 impl BwLinkedListObject for bw::Image {
     unsafe fn next(val: *mut bw::Image) -> *mut bw::Image {
         (*val).next
     }
+
+    unsafe fn prev(val: *mut bw::Image) -> *mut bw::Image {
+        (*val).prev
+    }
+
+    unsafe fn set_links(val: *mut bw::Image, prev: *mut bw::Image, next: *mut bw::Image) {
+        (*val).prev = prev;
+        (*val).next = next;
+    }
 }
 
 impl<T: BwLinkedListObject> Iterator for BwLinkedListIter<T> {
@@ -502,35 +584,39 @@ impl<T: BwLinkedListObject> Iterator for BwLinkedListIter<T> {
     }
 }
 
-unsafe fn lone_sprite_pointer_to_id_map() -> SaveMapping<bw::LoneSprite> {
-    lone_sprites(*bw::first_active_lone_sprite)
-        .enumerate()
-        .map(|(x, y)| (y.into(), x as u32 + 1))
-        .collect()
-}
-pub fn sprite_to_id_current_mapping(sprite: *mut bw::Sprite) -> Result<u32, SaveError> {
-    let mapping = sprite_save_mapping().borrow();
-    mapping.id(sprite)
-}
-
-pub fn sprite_from_id_current_mapping(id: u32) -> Result<*mut bw::Sprite, LoadError> {
-    let mapping = sprite_load_mapping().borrow();
-    mapping.pointer(id)
+/// Sprites are identified by their slot in the sprite array, so the ids can be resolved
+/// regardless of the order BW saves and loads the chunks in.
+pub fn sprite_to_id(sprite: *mut bw::Sprite) -> Result<u32, SaveError> {
+    let state = plugin_state().borrow();
+    sprite_array_id(&state.sprite_array, sprite)
 }
 
-pub fn lone_sprite_to_id_current_mapping(sprite: *mut bw::LoneSprite) -> Result<u32, SaveError> {
-    let mapping = lone_sprite_save_mapping().borrow();
-    mapping.id(sprite)
+pub fn sprite_from_id(id: u32) -> Result<*mut bw::Sprite, LoadError> {
+    let state = plugin_state().borrow();
+    sprite_pointer(&state.sprite_array, id)
 }
 
-pub fn lone_sprite_from_id_current_mapping(id: u32) -> Result<*mut bw::LoneSprite, LoadError> {
-    let mapping = lone_sprite_load_mapping().borrow();
-    mapping.pointer(id)
+/// Lone sprites are identified by their position in the active lone sprite list, which is
+/// also the order the sprite chunk saves them in.
+pub unsafe fn lone_sprite_to_id<M: GameMemory>(
+    game: &M,
+    sprite: *mut bw::LoneSprite,
+) -> Result<u32, SaveError> {
+    if sprite == null_mut() {
+        return Ok(0);
+    }
+    lone_sprites(*game.first_active_lone_sprite())
+        .position(|x| x == sprite)
+        .map(|x| x as u32 + 1)
+        .ok_or(SaveError::InvalidPointer)
 }
 
-unsafe fn sprite_serializable(
+unsafe fn sprite_serializable<M: GameMemory>(
+    game: &M,
     sprite: *const bw::Sprite,
-    mapping: &SaveMapping<bw::Sprite>,
+    sprites: &RawVec<bw::Sprite>,
+    image_array: &RawVec<bw::Image>,
+    pointer_ids: &ImagePointerIds,
 ) -> Result<SpriteSerializable, SaveError> {
     let bw::Sprite {
         prev,
@@ -551,10 +637,12 @@ unsafe fn sprite_serializable(
         last_overlay: _,
         extra,
     } = *sprite;
-    let (images, main_image_id) = images_serializable(first_overlay, main_image)?;
+    let (images, main_image_id) =
+        images_serializable(game, first_overlay, main_image, image_array, pointer_ids)
+            .context(|| "images")?;
     Ok(SpriteSerializable {
-        prev: mapping.id(prev)?,
-        next: mapping.id(next)?,
+        prev: sprite_array_id(sprites, prev).context(|| "prev")?,
+        next: sprite_array_id(sprites, next).context(|| "next")?,
         sprite_id,
         player,
         selection_index,
@@ -573,9 +661,12 @@ unsafe fn sprite_serializable(
     })
 }
 
-unsafe fn images_serializable(
+unsafe fn images_serializable<M: GameMemory>(
+    game: &M,
     first: *mut bw::Image,
-    main_image: *mut bw::Image
+    main_image: *mut bw::Image,
+    image_array: &RawVec<bw::Image>,
+    pointer_ids: &ImagePointerIds,
 ) -> Result<(Vec<ImageSerializable>, u32), SaveError> {
     let mut out = Vec::new();
     let mut main_index = 0;
@@ -583,7 +674,7 @@ unsafe fn images_serializable(
     // It's possible for the main image to be unreachable and dead value.
     let mut index = 0;
     while image != null_mut() {
-        if !is_selection_image(image) {
+        if drawfunc_info((*image).drawfunc).saved {
             index += 1;
             let bw::Image {
                 prev: _,
@@ -609,7 +700,10 @@ unsafe fn images_serializable(
             if image == main_image {
                 main_index = index;
             }
-            let offset = image as usize - image_array().borrow().ptr as usize;
+            let offset = (image as usize).checked_sub(image_array.ptr as usize)
+                .filter(|&x| x < image_array.size * mem::size_of::<bw::Image>())
+                .ok_or(SaveError::InvalidPointer)
+                .context(|| format!("Image {} ({:x})", index, image_id))?;
             out.push(ImageSerializable {
                 offset,
                 image_id,
@@ -624,8 +718,11 @@ unsafe fn images_serializable(
                 map_position,
                 screen_position,
                 grp_bounds,
-                grp: grp_to_id(grp)?,
-                drawfunc_param: drawfunc_param_serializable(drawfunc, drawfunc_param)?,
+                grp: pointer_ids.grp_id(grp)
+                    .context(|| format!("Image {} ({:x}): grp", index, image_id))?,
+                drawfunc_param:
+                    drawfunc_param_serializable(game, drawfunc, drawfunc_param, pointer_ids)
+                    .context(|| format!("Image {} ({:x}): drawfunc_param", index, image_id))?,
             });
         }
         image = (*image).next;
@@ -635,194 +732,390 @@ unsafe fn images_serializable(
 
 unsafe fn lone_sprite_serializable(
     sprite: *const bw::LoneSprite,
-    mapping: &SaveMapping<bw::Sprite>
+    sprites: &RawVec<bw::Sprite>,
 ) -> Result<LoneSpriteSerializable, SaveError> {
     Ok(LoneSpriteSerializable {
-        sprite: mapping.id((*sprite).sprite)?,
+        sprite: sprite_array_id(sprites, (*sprite).sprite).context(|| "sprite")?,
         value: (*sprite).value,
     })
 }
 
-unsafe fn grp_to_id(grp: *mut bw::GrpSprite) -> Result<u16, SaveError> {
-    if grp == null_mut() {
-        Ok(0)
-    } else {
-        (0..image_count()).position(|i| *(*bw::image_grps).offset(i as isize) == grp)
-            .map(|x| x as u16 + 1)
-            .ok_or(SaveError::InvalidGrpPointer)
+/// Reverse lookups for the image pointers which are saved as indices to BW's tables.
+/// Built once per save, as searching the tables for every image gets slow with large
+/// amounts of images.
+struct ImagePointerIds {
+    grps: HashMap<usize, u32>,
+    remap_palettes: HashMap<usize, u32>,
+}
+
+impl ImagePointerIds {
+    unsafe fn current<M: GameMemory>(game: &M) -> ImagePointerIds {
+        let bw_grps = slice::from_raw_parts(*game.image_grps(), image_count(game) as usize);
+        let grps = bw_grps.iter().chain(game.extended_grps()).cloned().collect::<Vec<_>>();
+        ImagePointerIds::new(&grps, all_remap_palettes(game).into_iter().map(|x| x.1))
+    }
+
+    fn new<I>(grps: &[*mut bw::GrpSprite], remap_palettes: I) -> ImagePointerIds
+    where I: IntoIterator<Item = *const u8>,
+    {
+        // If a pointer is in a table multiple times, the first index is used.
+        let mut grp_ids = HashMap::with_capacity(grps.len());
+        for (i, &grp) in grps.iter().enumerate() {
+            grp_ids.entry(grp as usize).or_insert(i as u32 + 1);
+        }
+        let mut palette_ids = HashMap::new();
+        for (i, palette) in remap_palettes.into_iter().enumerate() {
+            palette_ids.entry(palette as usize).or_insert(i as u32 + 1);
+        }
+        ImagePointerIds {
+            grps: grp_ids,
+            remap_palettes: palette_ids,
+        }
+    }
+
+    fn grp_id(&self, grp: *mut bw::GrpSprite) -> Result<u32, SaveError> {
+        if grp == null_mut() {
+            Ok(0)
+        } else {
+            self.grps.get(&(grp as usize)).cloned().ok_or(SaveError::InvalidGrpPointer)
+        }
+    }
+
+    fn remap_palette_id(&self, palette: *const u8) -> Result<u32, SaveError> {
+        if palette.is_null() {
+            Ok(0)
+        } else {
+            self.remap_palettes.get(&(palette as usize))
+                .cloned()
+                .ok_or(SaveError::InvalidRemapPalette)
+        }
     }
 }
 
-unsafe fn image_count() -> u32 {
-    (*bw::image_count_part1).checked_add(*bw::image_count_part2).unwrap()
+unsafe fn image_count<M: GameMemory>(game: &M) -> u32 {
+    (*game.image_count_part1()).saturating_add(*game.image_count_part2())
 }
 
-unsafe fn grp_from_id(grp: u16) -> Result<*mut bw::GrpSprite, LoadError> {
-    if grp == 0 {
-        Ok(null_mut())
-    } else if grp as u32 - 1 < image_count() {
-        Ok(*(*bw::image_grps).offset(grp as isize - 1))
-    } else {
+/// Ids past BW's table refer to the plugin's table.
+unsafe fn grp_from_id<M: GameMemory>(game: &M, grp: u32) -> Result<*mut bw::GrpSprite, LoadError> {
+    let bw_count = image_count(game);
+    let result = match grp.checked_sub(1) {
+        None => return Ok(null_mut()),
+        Some(index) if index < bw_count => *(*game.image_grps()).offset(index as isize),
+        Some(index) => {
+            let extended = game.extended_grps();
+            extended.get((index - bw_count) as usize).cloned().unwrap_or(null_mut())
+        }
+    };
+    if result == null_mut() {
         Err(LoadError::Corrupted(format!("Invalid grp {}", grp)))
+    } else {
+        Ok(result)
     }
 }
 
-unsafe fn drawfunc_param_serializable(func: u8, param: *mut c_void) -> Result<u32, SaveError> {
-    match func {
-        0x9 => {
-            let param = param as *const u8;
-            bw::remap_palettes.iter()
-                .position(|palette| palette.data == param)
-                .map(|x| x as u32 + 1)
-                .ok_or(SaveError::InvalidRemapPalette)
+/// What `drawfunc_param` of an image holds, which depends on its draw function.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DrawfuncParam {
+    /// A number, or nothing if the draw function doesn't use the param.
+    Value,
+    /// Data of a remap palette.
+    RemapPalette,
+    /// The unit whose health is drawn.
+    Unit,
+}
+
+/// How the plugin handles images using a draw function in `bw::image_drawfuncs`.
+pub struct DrawfuncInfo {
+    pub param: DrawfuncParam,
+    /// Selection circles and health bars get recreated for selected units, so they
+    /// aren't saved.
+    pub saved: bool,
+}
+
+const DRAWFUNCS: [DrawfuncInfo; 0x12] = [
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Normal
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Normal, unused
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Enemy cloak, the cloak state
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Own cloak
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Allied cloak
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Own cloak
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Own cloak, draw only
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Decloak
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Emp shockwave
+    DrawfuncInfo { param: DrawfuncParam::RemapPalette, saved: true }, // Remapping
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Shadow
+    DrawfuncInfo { param: DrawfuncParam::Unit, saved: false }, // Health bar
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Warp texture, the texture frame
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: false }, // Selection circle
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Player color override
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Update rect
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Hallucination
+    DrawfuncInfo { param: DrawfuncParam::Value, saved: true }, // Warp flash
+];
+
+/// Images with an invalid draw function are drawn with the first one.
+pub fn drawfunc_info(func: u8) -> &'static DrawfuncInfo {
+    DRAWFUNCS.get(func as usize).unwrap_or(&DRAWFUNCS[0])
+}
+
+unsafe fn drawfunc_param_serializable<M: GameMemory>(
+    game: &M,
+    func: u8,
+    param: *mut c_void,
+    pointer_ids: &ImagePointerIds,
+) -> Result<u32, SaveError> {
+    match drawfunc_info(func).param {
+        DrawfuncParam::RemapPalette => pointer_ids.remap_palette_id(param as *const u8),
+        DrawfuncParam::Unit => Ok(unit_to_id(game, param as *mut bw::Unit)? as u32),
+        DrawfuncParam::Value => Ok(param as u32),
+    }
+}
+
+/// BW's remap palettes followed by the ones loaded by the plugin, as (name, data).
+unsafe fn all_remap_palettes<M: GameMemory>(game: &M) -> Vec<(String, *const u8)> {
+    (*game.remap_palettes()).iter()
+        .chain(game.extra_remap_palettes())
+        .map(|x| (remap_palettes::name(x), x.data))
+        .collect()
+}
+
+/// Finds the palettes listed in a save from the current ones, so that palettes can be
+/// added or reordered without breaking saves. Palettes that aren't loaded anymore are
+/// `None`, which is only an error if an image uses them.
+fn remap_palettes_by_name(
+    saved: &[String],
+    current: &[(String, *const u8)],
+) -> Vec<Option<*const u8>> {
+    saved.iter().map(|name| {
+        current.iter().find(|x| x.0 == *name).map(|x| x.1)
+    }).collect()
+}
+
+/// Redoes the dat changes of a save. Is done once everything else in the chunk has been
+/// checked. If one of them fails, reverts the ones it already applied, but not the
+/// changes that were made before the load.
+fn apply_dat_patches<M: GameMemory>(game: &M, patches: &[DatPatch]) -> Result<(), LoadError> {
+    let previous = game.dat_patches().len();
+    for (i, patch) in patches.iter().enumerate() {
+        if let Err(e) = game.apply_dat_patch(patch) {
+            game.revert_dat_patches_since(previous);
+            return Err(LoadError::Corrupted(e)).context(|| format!("Dat patch {}", i));
         }
-        0xb => Ok(unit_to_id(param as *mut bw::Unit) as u32),
-        _ => Ok(param as u32),
     }
+    Ok(())
 }
 
-unsafe fn deserialize_drawfunc_param(func: u8, param: u32) -> Result<*mut c_void, LoadError> {
-    match func {
-        0x9 => {
+unsafe fn deserialize_drawfunc_param<M: GameMemory>(
+    game: &M,
+    func: u8,
+    param: u32,
+    remap_palettes: &[Option<*const u8>],
+) -> Result<*mut c_void, LoadError> {
+    match drawfunc_info(func).param {
+        DrawfuncParam::RemapPalette => {
             if param == 0 {
-                Ok(null_mut())
-            } else if param as usize - 1 < bw::remap_palettes.len() {
-                let pointer = bw::remap_palettes[param as usize - 1].data;
-                Ok(pointer as *mut c_void)
-            } else {
-                Err(LoadError::Corrupted(format!("Invalid remap palette {}", param)))
+                return Ok(null_mut());
+            }
+            match remap_palettes.get(param as usize - 1) {
+                Some(&Some(pointer)) => Ok(pointer as *mut c_void),
+                Some(&None) => {
+                    Err(LoadError::Corrupted(format!("Remap palette {} is not loaded", param)))
+                }
+                None => Err(LoadError::Corrupted(format!("Invalid remap palette {}", param))),
             }
         }
-        0xb => Ok(unit_from_id(param as u16)? as *mut c_void),
-        _ => Ok(param as *mut c_void),
+        DrawfuncParam::Unit => {
+            let unit = u16::try_from(param).map_err(|_| {
+                LoadError::Corrupted(format!("Invalid unit id 0x{:x}", param))
+            })?;
+            Ok(unit_from_id(game, unit)? as *mut c_void)
+        }
+        DrawfuncParam::Value => Ok(param as *mut c_void),
     }
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn load_sprite_chunk(file: *mut c_void) -> u32 {
-    if let Err(e) = load_sprites(file) {
-        info!("Couldn't load a save: {}", e);
+    if let Err(e) = read_sprites(file).context(|| "Sprites") {
+        error!("Couldn't load a save: {}", e);
+        show_load_error(&e);
+        plugin_state().borrow_mut().load_failed();
         return 0;
     }
+    plugin_state().borrow_mut().chunk_loaded(Chunk::Sprites);
     1
 }
 
-unsafe fn load_sprites(file: *mut c_void) -> Result<(), LoadError> {
-    let magic = fread_num::<u16>(file)?;
-    if magic != SPRITE_SAVE_MAGIC {
-        return Err(LoadError::WrongMagic(magic));
-    }
-    let version = fread_num::<u32>(file)?;
-    if version != 1 {
-        return Err(LoadError::Version(version));
+#[cfg(feature = "game")]
+unsafe fn read_sprites(file: *mut c_void) -> Result<(), LoadError> {
+    let data =
+        read_chunk(BwFile(file), SPRITE_SAVE_MAGIC, SPRITE_SAVE_VERSION, SPRITE_SAVE_MAX_SIZE)?;
+    load_sprites(&BwMemory, &data)
+}
+
+/// Loads the decompressed sprite chunk, which has to be done before the unit chunk
+/// can be loaded.
+///
+/// Everything is resolved before any of it gets written, so that a corrupted save is
+/// rejected without leaving a partially loaded game behind.
+pub unsafe fn load_sprites<M: GameMemory>(game: &M, data: &[u8]) -> Result<(), LoadError> {
+    let chunk = save_format::decode_sprites(data)?;
+    let globals = chunk.globals;
+    let mut state = plugin_state().borrow_mut();
+    let state = &mut *state;
+    state.begin_chunk(&chunk.header)?;
+    // The arrays were reserved for the limits of the save, which the counts shouldn't
+    // be above.
+    let limits = state.limits();
+    if globals.sprite_count > limits.sprites || globals.image_count > limits.images {
+        return Err(LoadError::Corrupted(format!(
+            "{} sprites and {} images with limits of {} and {}",
+            globals.sprite_count, globals.image_count, limits.sprites, limits.images,
+        )));
     }
-    let size = fread_num::<u32>(file)?;
-    if size > SPRITE_SAVE_MAX_SIZE {
-        return Err(LoadError::Corrupted(format!("Sprite chunk size {} is too large", size)));
+    let remap_palettes =
+        remap_palettes_by_name(&globals.remap_palettes, &all_remap_palettes(game));
+
+    let sprites = chunk.sprites.iter().map(|&(id, ref serialized)| {
+        // The ids were checked to be valid and unique when decoding.
+        let pointer = state.sprite_array.ptr.add(id as usize - 1);
+        let sprite = deserialize_sprite(
+            game,
+            serialized,
+            &state.sprite_array,
+            pointer,
+            &state.image_array,
+            globals.image_count,
+            &remap_palettes,
+        ).context(|| format!("Sprite 0x{:x} ({:x})", id, serialized.sprite_id))?;
+        Ok((pointer, sprite))
+    }).collect::<Result<Vec<_>, LoadError>>()?;
+
+    let (mut lone_sprites, lone_mapping) = allocate_lone_sprites(chunk.lone_sprites.len());
+    let iter = lone_sprites.iter_mut().zip(chunk.lone_sprites.iter()).enumerate();
+    for (i, (lone_sprite_result, serialized)) in iter {
+        let sprite = deserialize_lone_sprite(serialized, &state.sprite_array)
+            .context(|| format!("Lone/fow sprite {}", i + 1))?;
+        **lone_sprite_result = sprite;
     }
-    let data = fread(file, size)?;
-    let mut reader = flate2::read::DeflateDecoder::new(&data[..]);
-
-    let size_limit = bincode::Bounded(SPRITE_SAVE_MAX_SIZE as u64);
-    let globals: SaveGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
-    let mapping;
-    let lone_mapping;
-    let mut lone_sprites;
-    {
-        let mut sprites = sprite_array().borrow_mut();
-        let mut images = image_array().borrow_mut();
-        mapping = allocate_sprites(&mut sprites, globals.sprite_count);
-        let (lone_sprites_, lone_mapping_) =
-            allocate_lone_sprites(globals.lone_count + globals.fow_count);
-        lone_sprites = lone_sprites_;
-        lone_mapping = lone_mapping_;
-        for sprite_result in sprites.iter() {
-            let serialized = bincode::deserialize_from(&mut reader, size_limit)?;
-            let sprite = deserialize_sprite(&serialized, &mapping, sprite_result, &mut images)?;
-            *sprite_result = sprite;
-            if reader.total_out() > SPRITE_SAVE_MAX_SIZE as u64 {
-                return Err(LoadError::SizeLimit)
-            }
+    // Lone sprites are followed by fow sprites, which are a separate list.
+    let lone_count = globals.lone_count as usize;
+    for i in 1..lone_sprites.len() {
+        if i != lone_count {
+            let prev: *mut bw::LoneSprite = &mut *lone_sprites[i - 1];
+            lone_sprites[i].prev = prev;
+            lone_sprites[i - 1].next = &mut *lone_sprites[i];
         }
-
-        for lone_sprite_result in &mut lone_sprites {
-            let serialized = bincode::deserialize_from(&mut reader, size_limit)?;
-            let sprite = deserialize_lone_sprite(&serialized, &mapping)?;
-            **lone_sprite_result = sprite;
-            if reader.total_out() > SPRITE_SAVE_MAX_SIZE as u64 {
-                return Err(LoadError::SizeLimit)
-            }
+    }
+    let horizontal_lines = globals.horizontal_lines.iter().enumerate().map(|(i, &(begin, end))| {
+        let context = || format!("Horizontal line {}", i);
+        let begin = sprite_pointer(&state.sprite_array, begin).context(context)?;
+        let end = sprite_pointer(&state.sprite_array, end).context(context)?;
+        Ok((begin, end))
+    }).collect::<Result<Vec<_>, LoadError>>()?;
+    let cursor_marker =
+        lone_mapping.pointer(globals.cursor_marker).context(|| "cursor_marker")?;
+    let nuke_dots = units::resolve_nuke_dots(state.deferred_nuke_dots(), &lone_mapping)?;
+
+    apply_dat_patches(game, &globals.dat_patches)?;
+
+    // Any lone sprites from a game that was running before are not reachable anymore.
+    let reclaimed = free_lone_sprites(game, state);
+    if reclaimed != 0 {
+        info!("Freed {} lone/fow sprites before loading", reclaimed);
+    }
+    state.lone_sprite_stats = LoneSpriteStats::default();
+    state.sprite_array.size = globals.sprite_count as usize;
+    state.image_array.size = globals.image_count as usize;
+    let mut live_sprites = vec![false; state.sprite_array.size];
+    for (&(id, _), (pointer, (sprite, images))) in chunk.sprites.iter().zip(sprites) {
+        for (image_pointer, image) in images {
+            *image_pointer = image;
         }
-        for i in 0..lone_sprites.len() {
-            if i != 0 && i != globals.lone_count as usize {
-                lone_sprites[i - 1].next = &mut *lone_sprites[i];
-            }
-            if i != globals.lone_count as usize - 1 && i != lone_sprites.len() - 1 {
-                lone_sprites[i + 1].prev = &mut *lone_sprites[i];
+        *pointer = sprite;
+        live_sprites[id as usize - 1] = true;
+    }
+    for sprite in state.sprite_array.iter() {
+        state.sprites.insert(sprite.into());
+    }
+    // Only the loaded sprites, as the free slots still have whatever was in them before.
+    state.next_sprite_id = chunk.sprites.iter()
+        .map(|(_, sprite)| {
+            let (low, high) = sprite.extra.spawn_order;
+            (low as u64 | (high as u64) << 32).saturating_add(1)
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut live_images = vec![false; state.image_array.size];
+    for (sprite, _) in state.sprite_array.iter().zip(live_sprites.iter()).filter(|x| *x.1) {
+        for image in BwLinkedListIter((*sprite).first_overlay) {
+            if let Some(id) = state.image_array.id(image) {
+                live_images[id as usize - 1] = true;
             }
         }
-
-        let mut sprite_set = all_sprites().borrow_mut();
-        for sprite in sprites.iter() {
-            sprite_set.insert(SendPtr(sprite));
-        }
     }
-
-    *bw::first_free_sprite = null_mut();
-    *bw::last_free_sprite = null_mut();
-    *bw::first_free_image = null_mut();
-    *bw::last_free_image = null_mut();
-
-    *bw::first_active_lone_sprite = match globals.lone_count {
-        0 => null_mut(),
-        _ => &mut *lone_sprites[0],
-    };
-    *bw::last_active_lone_sprite = match globals.lone_count {
-        0 => null_mut(),
-        _ => &mut *lone_sprites[globals.lone_count as usize - 1],
-    };
-    *bw::first_active_fow_sprite = match globals.fow_count {
-        0 => null_mut(),
-        _ => &mut *lone_sprites[globals.lone_count as usize],
-    };
-    *bw::last_active_fow_sprite = match globals.fow_count {
-        0 => null_mut(),
-        _ => &mut **lone_sprites.last_mut().unwrap(),
-    };
+    rebuild_free_list(
+        &state.sprite_array,
+        &live_sprites,
+        game.first_free_sprite(),
+        game.last_free_sprite(),
+    );
+    rebuild_free_list(
+        &state.image_array,
+        &live_images,
+        game.first_free_image(),
+        game.last_free_image(),
+    );
 
     {
-        let mut lone_sprite_set = all_lone_sprites().borrow_mut();
-        for sprite in lone_sprites {
-            lone_sprite_set.insert(Box::into_raw(sprite).into());
-        }
+        let (lone, fow) = lone_sprites.split_at_mut(lone_count);
+        let pointer = |sprite: Option<&mut Box<bw::LoneSprite>>| match sprite {
+            Some(sprite) => &mut **sprite as *mut bw::LoneSprite,
+            None => null_mut(),
+        };
+        *game.first_active_lone_sprite() = pointer(lone.first_mut());
+        *game.last_active_lone_sprite() = pointer(lone.last_mut());
+        *game.first_active_fow_sprite() = pointer(fow.first_mut());
+        *game.last_active_fow_sprite() = pointer(fow.last_mut());
     }
 
-    for (i, (begin, end)) in globals.horizontal_lines.into_iter().enumerate() {
-        bw::horizontal_sprite_lines_begin[i] = mapping.pointer(begin)?;
-        bw::horizontal_sprite_lines_end[i] = mapping.pointer(end)?;
+    state.lone_sprite_stats.created += lone_sprites.len() as u64;
+    for sprite in lone_sprites {
+        state.lone_sprites.insert(Box::into_raw(sprite).into());
     }
-    *bw::cursor_marker = lone_mapping.pointer(globals.cursor_marker)?;
 
-    {
-        let mut global_mapping = sprite_load_mapping().borrow_mut();
-        *global_mapping = mapping;
-        let mut lone_global_mapping = lone_sprite_load_mapping().borrow_mut();
-        *lone_global_mapping = lone_mapping;
+    for (i, (begin, end)) in horizontal_lines.into_iter().enumerate() {
+        (*game.horizontal_sprite_lines_begin())[i] = begin;
+        (*game.horizontal_sprite_lines_end())[i] = end;
     }
+    *game.cursor_marker() = cursor_marker;
+    units::set_nuke_dots(game, &nuke_dots);
+
+    state.set_lone_sprite_load_mapping(lone_mapping);
     // Refill sprite / image list for GPTP which allocates images from reading through
     // first_free_image during hooks.
     // Most of the time the refill_sprite_image_list at create_sprite_hook is good enough,
     // but loading a save may cause something else that allocates images to run before
     // any sprites are created.
-    refill_sprite_image_list();
+    refill_sprite_image_list(game, state);
     Ok(())
 }
 
-unsafe fn deserialize_sprite(
+/// An image with the pointer it gets written to once the chunk has been validated.
+type LoadedImage = (*mut bw::Image, bw::Image);
+
+/// Returns the sprite and its images with the pointers they will be written to.
+unsafe fn deserialize_sprite<M: GameMemory>(
+    game: &M,
     sprite: &SpriteSerializable,
-    mapping: &LoadMapping<bw::Sprite>,
+    sprites: &RawVec<bw::Sprite>,
     pointer: *mut bw::Sprite,
-    image_array: &mut RawVec<bw::Image>,
-) -> Result<bw::Sprite, LoadError> {
+    image_array: &RawVec<bw::Image>,
+    image_count: u32,
+    remap_palettes: &[Option<*const u8>],
+) -> Result<(bw::Sprite, Vec<LoadedImage>), LoadError> {
     let SpriteSerializable {
         prev,
         next,
@@ -841,10 +1134,12 @@ unsafe fn deserialize_sprite(
         main_image_id,
         ref extra,
     } = *sprite;
-    let mut image_ptrs = deserialize_images(images, pointer, image_array)?;
-    Ok(bw::Sprite {
-        prev: mapping.pointer(prev)?,
-        next: mapping.pointer(next)?,
+    let images =
+        deserialize_images(game, images, pointer, image_array, image_count, remap_palettes)
+            .context(|| "images")?;
+    let sprite = bw::Sprite {
+        prev: sprite_pointer(sprites, prev).context(|| "prev")?,
+        next: sprite_pointer(sprites, next).context(|| "next")?,
         sprite_id,
         player,
         selection_index,
@@ -856,28 +1151,30 @@ unsafe fn deserialize_sprite(
         width,
         height,
         position,
-        first_overlay: image_ptrs.first_mut()
-            .map(|x| &mut **x as *mut bw::Image).unwrap_or(null_mut()),
-        last_overlay: image_ptrs.last_mut()
-            .map(|x| &mut **x as *mut bw::Image).unwrap_or(null_mut()),
+        first_overlay: images.first().map(|x| x.0).unwrap_or(null_mut()),
+        last_overlay: images.last().map(|x| x.0).unwrap_or(null_mut()),
         main_image: if main_image_id == 0 {
             null_mut()
         } else {
-            image_ptrs.get_mut(main_image_id as usize - 1).map(|x| &mut **x).ok_or_else(|| {
+            images.get(main_image_id as usize - 1).map(|x| x.0).ok_or_else(|| {
                 LoadError::Corrupted(format!("Invalid main image 0x{:x}", main_image_id))
             })?
         },
         extra: extra.clone(),
-    })
+    };
+    Ok((sprite, images))
 }
 
-unsafe fn deserialize_images(
+unsafe fn deserialize_images<M: GameMemory>(
+    game: &M,
     images: &[ImageSerializable],
     parent: *mut bw::Sprite,
-    image_array: &mut RawVec<bw::Image>,
-) -> Result<Vec<*mut bw::Image>, LoadError> {
-    let mut result: Vec<*mut bw::Image> = Vec::with_capacity(images.len());
-    for img in images {
+    image_array: &RawVec<bw::Image>,
+    image_count: u32,
+    remap_palettes: &[Option<*const u8>],
+) -> Result<Vec<LoadedImage>, LoadError> {
+    let mut result: Vec<LoadedImage> = Vec::with_capacity(images.len());
+    for (i, img) in images.iter().enumerate() {
         let ImageSerializable {
             offset,
             image_id,
@@ -895,13 +1192,13 @@ unsafe fn deserialize_images(
             grp,
             drawfunc_param,
         } = *img;
-        let ptr = (image_array.ptr as usize + offset) as *mut bw::Image;
         let index = offset / mem::size_of::<bw::Image>();
-        if index >= image_array.size {
-            image_array.size = index + 1;
+        if index >= image_count as usize {
+            return Err(LoadError::Corrupted(format!("Invalid image offset 0x{:x}", offset)));
         }
-        *ptr = bw::Image {
-            prev: result.last().copied().unwrap_or(null_mut()),
+        let ptr = image_array.ptr.add(index);
+        let image = bw::Image {
+            prev: result.last().map(|x| x.0).unwrap_or(null_mut()),
             next: null_mut(),
             image_id,
             drawfunc,
@@ -915,57 +1212,229 @@ unsafe fn deserialize_images(
             map_position,
             screen_position,
             grp_bounds,
-            grp: grp_from_id(grp)?,
-            drawfunc_param: deserialize_drawfunc_param(drawfunc, drawfunc_param)?,
+            grp: grp_from_id(game, grp)
+                .context(|| format!("Image {} ({:x}): grp", i + 1, image_id))?,
+            drawfunc_param:
+                deserialize_drawfunc_param(game, drawfunc, drawfunc_param, remap_palettes)
+                .context(|| format!("Image {} ({:x}): drawfunc_param", i + 1, image_id))?,
             parent,
             draw: {
-                let drawfunc = bw::image_drawfuncs.get(drawfunc as usize)
-                    .unwrap_or_else(|| &bw::image_drawfuncs[0]);
+                let drawfuncs = &*game.image_drawfuncs();
+                let drawfunc = drawfuncs.get(drawfunc as usize).unwrap_or_else(|| &drawfuncs[0]);
                 match flags & 0x2 == 0 {
                     true => drawfunc.normal,
                     false => drawfunc.flipped,
                 }
             },
             step_frame: {
-                let func = bw::image_updatefuncs.get(drawfunc as usize)
-                    .unwrap_or_else(|| &bw::image_updatefuncs[0]);
+                let funcs = &*game.image_updatefuncs();
+                let func = funcs.get(drawfunc as usize).unwrap_or_else(|| &funcs[0]);
                 func.func
             }
         };
-        if let Some(prev) = result.last() {
-            (**prev).next = ptr;
+        if let Some(prev) = result.last_mut() {
+            prev.1.next = ptr;
         }
-        result.push(ptr);
+        result.push((ptr, image));
     }
     Ok(result)
 }
 
 unsafe fn deserialize_lone_sprite(
     sprite: &LoneSpriteSerializable,
-    mapping: &LoadMapping<bw::Sprite>,
+    sprites: &RawVec<bw::Sprite>,
 ) -> Result<bw::LoneSprite, LoadError> {
     Ok(bw::LoneSprite {
         prev: null_mut(),
         next: null_mut(),
         value: sprite.value,
-        sprite: mapping.pointer(sprite.sprite)?,
+        sprite: sprite_pointer(sprites, sprite.sprite).context(|| "sprite")?,
     })
 }
 
-// Returning the pointer vector isn't really necessary, just simpler. Could also create a
-// vector abstraction that allows reading addresses of any Bullet while holding a &mut reference
-// to one of them.
-fn allocate_sprites(sprites: &mut RawVec<bw::Sprite>, count: u32) -> LoadMapping<bw::Sprite> {
-    sprites.size = count as usize;
-    LoadMapping((0..count).map(|i| {
-        unsafe { SendPtr(sprites.ptr.add(i as usize)) }
-    }).collect())
-}
-
-fn allocate_lone_sprites(count: u32) -> (Vec<Box<bw::LoneSprite>>, LoadMapping<bw::LoneSprite>) {
+fn allocate_lone_sprites(count: usize) -> (Vec<Box<bw::LoneSprite>>, LoadMapping<bw::LoneSprite>) {
     (0..count).map(|_| {
         let mut sprite = Box::new(unsafe { mem::zeroed() });
         let pointer: *mut bw::LoneSprite = &mut *sprite;
         (sprite, pointer)
     }).unzip()
 }
+
+#[cfg(test)]
+mod test {
+    use std::iter;
+    use std::mem;
+    use std::ptr::{self, null_mut};
+
+    use ::test::Bencher;
+
+    use bw;
+    use game_memory::{GameMemory, TestMemory};
+    use game_memory::test::test_game;
+    use libc::c_void;
+    use save_format::DatPatch;
+    use state::{plugin_state, RawVec};
+
+    use super::{
+        deserialize_drawfunc_param, drawfunc_info, drawfunc_param_serializable,
+        images_serializable, load_sprites, remap_palettes_by_name, serialize_sprites,
+        DrawfuncParam, ImagePointerIds,
+    };
+
+    fn fake_grps(count: usize) -> Vec<*mut bw::GrpSprite> {
+        // Never dereferenced
+        (1..count + 1).map(|i| (i * 0x40) as *mut bw::GrpSprite).collect()
+    }
+
+    #[test]
+    fn grp_ids() {
+        let mut grps = fake_grps(10);
+        grps[7] = grps[3];
+        let ids = ImagePointerIds::new(&grps, iter::empty());
+        assert_eq!(ids.grp_id(null_mut()).unwrap(), 0);
+        assert_eq!(ids.grp_id(grps[0]).unwrap(), 1);
+        assert_eq!(ids.grp_id(grps[3]).unwrap(), 4);
+        assert_eq!(ids.grp_id(grps[9]).unwrap(), 10);
+        assert!(ids.grp_id(0x12345 as *mut bw::GrpSprite).is_err());
+    }
+
+    #[test]
+    fn remap_palettes_from_names() {
+        let current = ["ofire", "cfire", "red"].iter().enumerate()
+            .map(|(i, &name)| (name.to_string(), (i * 0x100) as *const u8))
+            .collect::<Vec<_>>();
+        let saved = ["red", "pfire", "ofire"].iter().map(|&x| x.into()).collect::<Vec<_>>();
+        let palettes = remap_palettes_by_name(&saved, &current);
+        assert_eq!(palettes, vec![Some(0x200 as *const u8), None, Some(ptr::null())]);
+    }
+
+    #[test]
+    fn drawfunc_params() {
+        let game = TestMemory::new(vec![]);
+        // Never dereferenced
+        let palettes = [0x1000 as *const u8, 0x1100 as *const u8];
+        let ids = ImagePointerIds::new(&[], palettes.iter().cloned());
+        let loaded_palettes = palettes.iter().map(|&x| Some(x)).collect::<Vec<_>>();
+        let unit = unsafe { &mut (*game.units())[5] as *mut bw::Unit };
+        assert_eq!(super::DRAWFUNCS.len(), unsafe { (*game.image_drawfuncs()).len() });
+        // Also the invalid draw functions, which don't use the param.
+        for func in 0..=0xff {
+            let kind = drawfunc_info(func).param;
+            if func >= 0x12 {
+                assert_eq!(kind, DrawfuncParam::Value);
+            }
+            let params = match kind {
+                DrawfuncParam::Value => vec![0, 0x23, 0x1234],
+                DrawfuncParam::RemapPalette => vec![0, palettes[0] as usize, palettes[1] as usize],
+                DrawfuncParam::Unit => vec![0, unit as usize],
+            };
+            for param in params {
+                let param = param as *mut c_void;
+                unsafe {
+                    let saved = drawfunc_param_serializable(&game, func, param, &ids).unwrap();
+                    let loaded =
+                        deserialize_drawfunc_param(&game, func, saved, &loaded_palettes).unwrap();
+                    assert_eq!(loaded, param, "Drawfunc {:x}", func);
+                    if kind != DrawfuncParam::Value {
+                        // The pointers are saved as ids.
+                        assert!(saved < 0x1000, "Drawfunc {:x}", func);
+                        let invalid = 0x12345 as *mut c_void;
+                        assert!(drawfunc_param_serializable(&game, func, invalid, &ids).is_err());
+                        assert!(deserialize_drawfunc_param(&game, func, 0x12345, &[]).is_err());
+                    }
+                }
+            }
+        }
+        assert_eq!(drawfunc_info(0x9).param, DrawfuncParam::RemapPalette);
+        assert_eq!(drawfunc_info(0xb).param, DrawfuncParam::Unit);
+        let unsaved = (0..=0xff).filter(|&x| !drawfunc_info(x).saved).collect::<Vec<u8>>();
+        assert_eq!(unsaved, vec![0xb, 0xd]);
+    }
+
+    #[test]
+    fn next_sprite_id_from_loaded_sprites() {
+        unsafe {
+            let mut test = test_game(0x0bad_5eed);
+            let sprites = serialize_sprites(&test.game).unwrap();
+            plugin_state().borrow_mut().reset_objects(&test.game);
+            load_sprites(&test.empty_memory(), &sprites).unwrap();
+            let next_sprite_id = plugin_state().borrow().next_sprite_id;
+            assert_ne!(next_sprite_id, 0);
+
+            // The free slots that the save skips still have the sprites of an earlier game.
+            {
+                let mut state = plugin_state().borrow_mut();
+                state.reset_objects(&test.game);
+                let array = &state.sprite_array;
+                for i in 0..array.capacity {
+                    (*array.ptr.add(i)).extra.spawn_order = (!0, !0);
+                }
+            }
+            load_sprites(&test.empty_memory(), &sprites).unwrap();
+            assert_eq!(plugin_state().borrow().next_sprite_id, next_sprite_id);
+        }
+    }
+
+    #[test]
+    fn failed_dat_patch() {
+        unsafe {
+            let mut test = test_game(0xdead_beef);
+            // A dat file that the loading game doesn't have, after ones that it has.
+            test.game.apply_dat_patch(&DatPatch {
+                dat: 4,
+                column: 1,
+                id: 0x10,
+                value: 0x20,
+            }).unwrap();
+            test.game.dat_patches.borrow_mut().push(DatPatch {
+                dat: 8,
+                column: 0,
+                id: 0,
+                value: 0,
+            });
+            let sprites = serialize_sprites(&test.game).unwrap();
+            plugin_state().borrow_mut().reset_objects(&test.game);
+
+            let loaded = test.empty_memory();
+            // Made before the load, which doesn't own it.
+            let earlier = DatPatch {
+                dat: 0,
+                column: 2,
+                id: 3,
+                value: 4,
+            };
+            loaded.apply_dat_patch(&earlier).unwrap();
+            assert!(load_sprites(&loaded, &sprites).is_err());
+            assert!(*loaded.dat_patches.borrow() == [earlier]);
+            assert!(plugin_state().borrow().sprites.is_empty());
+            assert!((*loaded.first_active_lone_sprite()).is_null());
+        }
+    }
+
+    #[bench]
+    fn serialize_large_image_array(b: &mut Bencher) {
+        const IMAGE_COUNT: usize = 400000;
+        let grps = fake_grps(999);
+        let mut images: Vec<bw::Image> = (0..IMAGE_COUNT).map(|i| bw::Image {
+            grp: grps[(i * 7) % grps.len()],
+            ..unsafe { mem::zeroed() }
+        }).collect();
+        let base = images.as_mut_ptr();
+        let image_array = RawVec {
+            ptr: base,
+            size: IMAGE_COUNT,
+            capacity: IMAGE_COUNT,
+        };
+        let game = TestMemory::new(vec![]);
+        unsafe {
+            for i in 0..IMAGE_COUNT - 1 {
+                (*base.add(i)).next = base.add(i + 1);
+                (*base.add(i + 1)).prev = base.add(i);
+            }
+        }
+        b.iter(|| unsafe {
+            let ids = ImagePointerIds::new(&grps, iter::empty());
+            images_serializable(&game, base, base, &image_array, &ids).unwrap().0.len()
+        });
+    }
+}
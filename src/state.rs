@@ -0,0 +1,494 @@
+//! Everything the plugin keeps track of during a game, and which part of the game's lifecycle
+//! it is in.
+//!
+//! The hook patcher in lib.rs is not a part of this, as it stays the same for the entire
+//! process.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem;
+#[cfg(feature = "game")]
+use std::path::Path;
+use std::ptr::null_mut;
+#[cfg(feature = "game")]
+use std::sync::Once;
+
+use bullets;
+use bw;
+#[cfg(feature = "game")]
+use config::config;
+#[cfg(feature = "game")]
+use dat;
+#[cfg(feature = "game")]
+use game_hash;
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
+use save::{LoadError, LoadMapping, SaveError};
+use save_format::{Limits, SaveHeader};
+use send_pointer::SendPtr;
+use sprites;
+
+pub const SPRITE_LIMIT: usize = 200000;
+pub const IMAGE_LIMIT: usize = 400000;
+/// The arrays may be grown past the limits above when loading a save made with a build
+/// that had higher limits, but not past these.
+const SPRITE_LIMIT_MAX: usize = 1000000;
+const IMAGE_LIMIT_MAX: usize = 2000000;
+
+ome2_thread_local! {
+    PLUGIN_STATE: RefCell<PluginState> = plugin_state_cell(RefCell::new(PluginState::new()));
+}
+
+/// The state should not be kept borrowed while calling BW functions, as they may end up
+/// calling other hooks.
+pub fn plugin_state() -> &'static RefCell<PluginState> {
+    plugin_state_cell()
+}
+
+pub struct PluginState {
+    phase: Phase,
+    pub sprite_array: RawVec<bw::Sprite>,
+    pub image_array: RawVec<bw::Image>,
+    pub sprites: HashSet<SendPtr<bw::Sprite>>,
+    // Both lone and fow
+    pub lone_sprites: HashSet<SendPtr<bw::LoneSprite>>,
+    pub lone_sprite_stats: LoneSpriteStats,
+    pub next_sprite_id: u64,
+    pub sprite_draw_buffer: Vec<SendPtr<bw::Sprite>>,
+    /// The screen that the sprites in `sprite_draw_buffer` were culled against, taken once
+    /// the first sprite of a frame gets added.
+    pub sprite_draw_screen: Option<sprites::ScreenRect>,
+    pub bullets: HashSet<SendPtr<bw::Bullet>>,
+}
+
+enum Phase {
+    /// No game is running. BW still creates sprites for the lobby minimap preview, but it
+    /// uses its own arrays for them.
+    Idle,
+    InGame,
+    /// BW is writing a save of the running game, and has yet to write these chunks.
+    Saving(Vec<Chunk>),
+    /// Some of the chunks of a save have been loaded.
+    Loading(LoadProgress),
+    /// Every chunk has been loaded, but BW has not started the game yet.
+    Loaded,
+}
+
+struct LoadProgress {
+    limits: Limits,
+    /// Set once the sprite chunk has been loaded, units use its ids for lone sprites.
+    lone_sprites: Option<LoadMapping<bw::LoneSprite>>,
+    /// Ghosts and the lone sprite ids of their nuke dots, if the units were loaded before
+    /// the lone sprites existed.
+    nuke_dots: Vec<(u16, u32)>,
+    remaining: Vec<Chunk>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Chunk {
+    Sprites,
+    Bullets,
+    Units,
+}
+
+const ALL_CHUNKS: [Chunk; 3] = [Chunk::Sprites, Chunk::Bullets, Chunk::Units];
+
+/// Allocation counts of lone and fow sprites during a single game, logged at game end
+/// to catch any sprites that were not freed through the usual paths.
+#[derive(Default, Copy, Clone)]
+pub struct LoneSpriteStats {
+    pub created: u64,
+    pub freed: u64,
+}
+
+impl PluginState {
+    fn new() -> PluginState {
+        PluginState {
+            phase: Phase::Idle,
+            sprite_array: RawVec::with_capacity(SPRITE_LIMIT),
+            image_array: RawVec::with_capacity(IMAGE_LIMIT),
+            sprites: HashSet::new(),
+            lone_sprites: HashSet::new(),
+            lone_sprite_stats: LoneSpriteStats::default(),
+            next_sprite_id: 0,
+            sprite_draw_buffer: Vec::with_capacity(0x800),
+            sprite_draw_screen: None,
+            bullets: HashSet::new(),
+        }
+    }
+
+    /// Whether the plugin is responsible for the objects BW creates.
+    pub fn is_in_game(&self) -> bool {
+        !matches!(self.phase, Phase::Idle)
+    }
+
+    /// Frees everything that was allocated for the game, and clears BW's lists that
+    /// point to it.
+    pub unsafe fn reset_objects<M: GameMemory>(&mut self, game: &M) {
+        bullets::delete_all(game, self);
+        sprites::delete_all(game, self);
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            sprites: self.sprite_array.capacity as u32,
+            images: self.image_array.capacity as u32,
+        }
+    }
+
+    pub fn save_header(&self) -> SaveHeader {
+        SaveHeader {
+            plugin_version: env!("CARGO_PKG_VERSION").into(),
+            limits: self.limits(),
+        }
+    }
+
+    /// Has to be called before loading anything from a chunk. The first chunk of a save
+    /// grows the arrays to the limits of the save, which can't be done once any chunk has
+    /// been loaded, as the chunks keep pointers to the arrays.
+    pub unsafe fn begin_chunk(&mut self, header: &SaveHeader) -> Result<(), LoadError> {
+        if let Phase::Loading(ref progress) = self.phase {
+            if progress.limits != header.limits {
+                return Err(LoadError::Corrupted(format!(
+                    "The chunks were saved with different limits ({:?} and {:?})",
+                    progress.limits, header.limits,
+                )));
+            }
+            return Ok(());
+        }
+        if header.plugin_version != env!("CARGO_PKG_VERSION") {
+            info!("Loading a save made with version {}", header.plugin_version);
+        }
+        self.reserve_for_load(&header.limits)?;
+        self.phase = Phase::Loading(LoadProgress {
+            limits: header.limits,
+            lone_sprites: None,
+            nuke_dots: Vec::new(),
+            remaining: ALL_CHUNKS.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Grows the arrays to the limits `saved` was made with, or fails without changing
+    /// anything if they are too large.
+    unsafe fn reserve_for_load(&mut self, saved: &Limits) -> Result<(), LoadError> {
+        let sprites = saved.sprites as usize;
+        let images = saved.images as usize;
+        if sprites > SPRITE_LIMIT_MAX {
+            let limit = SPRITE_LIMIT_MAX as u32;
+            return Err(LoadError::LimitExceeded("sprites", sprites as u32, limit));
+        }
+        if images > IMAGE_LIMIT_MAX {
+            let limit = IMAGE_LIMIT_MAX as u32;
+            return Err(LoadError::LimitExceeded("images", images as u32, limit));
+        }
+        if sprites > self.sprite_array.capacity {
+            info!("Growing sprite limit from {} to {}", self.sprite_array.capacity, sprites);
+            self.sprite_array.reallocate(sprites);
+            self.sprites.clear();
+            self.sprite_draw_buffer.clear();
+        }
+        if images > self.image_array.capacity {
+            info!("Growing image limit from {} to {}", self.image_array.capacity, images);
+            self.image_array.reallocate(images);
+        }
+        Ok(())
+    }
+
+    /// The sprite chunk is expected to have set the deferred nuke dots by now.
+    pub fn set_lone_sprite_load_mapping(&mut self, lone_sprites: LoadMapping<bw::LoneSprite>) {
+        if let Phase::Loading(ref mut progress) = self.phase {
+            progress.lone_sprites = Some(lone_sprites);
+            progress.nuke_dots.clear();
+        }
+    }
+
+    /// `None` until the sprite chunk has been loaded.
+    pub fn lone_sprite_load_mapping(&self) -> Option<&LoadMapping<bw::LoneSprite>> {
+        match self.phase {
+            Phase::Loading(LoadProgress { lone_sprites: Some(ref mapping), .. }) => Some(mapping),
+            _ => None,
+        }
+    }
+
+    pub fn defer_nuke_dots(&mut self, nuke_dots: Vec<(u16, u32)>) {
+        if let Phase::Loading(ref mut progress) = self.phase {
+            progress.nuke_dots = nuke_dots;
+        }
+    }
+
+    pub fn deferred_nuke_dots(&self) -> &[(u16, u32)] {
+        match self.phase {
+            Phase::Loading(ref progress) => &progress.nuke_dots,
+            _ => &[],
+        }
+    }
+
+    pub fn chunk_loaded(&mut self, chunk: Chunk) {
+        let done = match self.phase {
+            Phase::Loading(ref mut progress) => {
+                progress.remaining.retain(|&x| x != chunk);
+                progress.remaining.is_empty()
+            }
+            _ => false,
+        };
+        if done {
+            self.phase = Phase::Loaded;
+        }
+    }
+
+    /// BW abandons the load if any chunk fails, so the plugin isn't in a game either.
+    pub fn load_failed(&mut self) {
+        self.phase = Phase::Idle;
+    }
+
+    /// Has to be called before saving a chunk, saves can only be made of a running game.
+    /// A chunk that was already saved starts a new save, as BW doesn't tell when one begins.
+    pub fn begin_save(&mut self, chunk: Chunk) -> Result<(), SaveError> {
+        match self.phase {
+            Phase::Saving(ref remaining) if remaining.contains(&chunk) => Ok(()),
+            Phase::InGame | Phase::Saving(_) => {
+                self.phase = Phase::Saving(ALL_CHUNKS.to_vec());
+                Ok(())
+            }
+            Phase::Idle | Phase::Loading(_) | Phase::Loaded => Err(SaveError::NotInGame),
+        }
+    }
+
+    pub fn chunk_saved(&mut self, chunk: Chunk) {
+        let done = match self.phase {
+            Phase::Saving(ref mut remaining) => {
+                remaining.retain(|&x| x != chunk);
+                remaining.is_empty()
+            }
+            _ => false,
+        };
+        if done {
+            self.phase = Phase::InGame;
+        }
+    }
+
+    /// BW stops writing the save, but the game keeps running.
+    pub fn save_failed(&mut self) {
+        if let Phase::Saving(_) = self.phase {
+            self.phase = Phase::InGame;
+        }
+    }
+}
+
+#[cfg(feature = "game")]
+pub unsafe fn init_game() {
+    static LOAD_DAT_FILES: Once = Once::new();
+    LOAD_DAT_FILES.call_once(load_dat_files);
+    game_hash::init_game();
+    let mut state = plugin_state().borrow_mut();
+    match mem::replace(&mut state.phase, Phase::InGame) {
+        // The objects were just loaded, don't throw them away.
+        Phase::Loaded => (),
+        // A load that didn't finish has left objects that BW doesn't know about.
+        Phase::Idle | Phase::InGame | Phase::Saving(_) | Phase::Loading(_) => {
+            state.reset_objects(&BwMemory)
+        }
+    }
+}
+
+/// Replaces BW's dat files with the ones in `dat_dir`. Done once the first game starts,
+/// as BW has loaded its own files by then.
+#[cfg(feature = "game")]
+fn load_dat_files() {
+    let dir = match config().dat_dir {
+        Some(ref s) => Path::new(s),
+        None => return,
+    };
+    for &kind in &dat::Dat::ALL {
+        let path = dir.join(kind.name());
+        if !path.exists() {
+            continue;
+        }
+        match dat::DatFile::from_file(&path, kind.layout(), kind.name()) {
+            Ok(file) => {
+                info!("Using {} with {} entries", path.display(), file.entries());
+                file.install(kind);
+            }
+            Err(e) => error!("Keeping BW's {}: {}", kind.name(), e),
+        }
+    }
+}
+
+#[cfg(feature = "game")]
+pub unsafe fn end_game() {
+    game_hash::end_game();
+    dat::revert_patches();
+    let mut state = plugin_state().borrow_mut();
+    state.reset_objects(&BwMemory);
+    state.phase = Phase::Idle;
+}
+
+pub struct RawVec<T> {
+    pub ptr: *mut T,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+unsafe impl<T> Send for RawVec<T> {}
+unsafe impl<T> Sync for RawVec<T> {}
+
+impl<T> RawVec<T> {
+    fn with_capacity(cap: usize) -> RawVec<T> {
+        let (ptr, size, capacity) = Vec::with_capacity(cap).into_raw_parts();
+        RawVec {
+            ptr,
+            size,
+            capacity,
+        }
+    }
+
+    /// Replaces the allocation with an empty one. Any pointers to the old elements become
+    /// invalid, so this can only be done when all of them are about to be overwritten
+    /// by a save that is being loaded.
+    unsafe fn reallocate(&mut self, capacity: usize) {
+        drop(Vec::from_raw_parts(self.ptr, 0, self.capacity));
+        *self = RawVec::with_capacity(capacity);
+    }
+
+    pub fn push(&mut self) -> *mut T {
+        unsafe {
+            if self.size == self.capacity {
+                null_mut()
+            } else {
+                self.size += 1;
+                self.ptr.add(self.size - 1)
+            }
+        }
+    }
+
+    /// 1-based index of an element in the array, 0 for null.
+    pub fn id(&self, ptr: *mut T) -> Option<u32> {
+        if ptr == null_mut() {
+            return Some(0);
+        }
+        let offset = (ptr as usize).wrapping_sub(self.ptr as usize);
+        let index = offset / mem::size_of::<T>();
+        if offset.is_multiple_of(mem::size_of::<T>()) && index < self.size {
+            Some(index as u32 + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Inverse of `id`. Any slot within the capacity is accepted, as a save may refer to
+    /// elements that haven't been loaded yet.
+    pub fn pointer(&self, id: u32) -> Option<*mut T> {
+        if id == 0 {
+            Some(null_mut())
+        } else if (id as usize - 1) < self.capacity {
+            unsafe { Some(self.ptr.add(id as usize - 1)) }
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = *mut T> {
+        unsafe {
+            let ptr = self.ptr;
+            (0..self.size).map(move |x| ptr.add(x))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use save::LoadMapping;
+    use save_format::SaveHeader;
+    use super::{Chunk, Phase, PluginState};
+
+    fn header(state: &PluginState) -> SaveHeader {
+        state.save_header()
+    }
+
+    fn is_loaded(state: &PluginState) -> bool {
+        matches!(state.phase, Phase::Loaded)
+    }
+
+    #[test]
+    fn chunks_in_any_order() {
+        let mut state = PluginState::new();
+        let header = header(&state);
+        unsafe {
+            state.begin_chunk(&header).unwrap();
+            state.chunk_loaded(Chunk::Bullets);
+            assert!(state.lone_sprite_load_mapping().is_none());
+            state.begin_chunk(&header).unwrap();
+            state.set_lone_sprite_load_mapping(LoadMapping::new());
+            state.chunk_loaded(Chunk::Sprites);
+            assert!(state.lone_sprite_load_mapping().is_some());
+            assert!(!is_loaded(&state));
+            state.begin_chunk(&header).unwrap();
+        }
+        state.chunk_loaded(Chunk::Units);
+        assert!(is_loaded(&state));
+        assert!(state.lone_sprite_load_mapping().is_none());
+    }
+
+    #[test]
+    fn failed_load() {
+        let mut state = PluginState::new();
+        let header = header(&state);
+        unsafe {
+            state.begin_chunk(&header).unwrap();
+            state.set_lone_sprite_load_mapping(LoadMapping::new());
+            state.chunk_loaded(Chunk::Sprites);
+            state.load_failed();
+            assert!(!state.is_in_game());
+            // The next save starts from the beginning.
+            state.begin_chunk(&header).unwrap();
+            state.chunk_loaded(Chunk::Units);
+            state.begin_chunk(&header).unwrap();
+            state.chunk_loaded(Chunk::Bullets);
+        }
+        assert!(!is_loaded(&state));
+        assert!(state.lone_sprite_load_mapping().is_none());
+    }
+
+    #[test]
+    fn chunks_from_different_saves() {
+        let mut state = PluginState::new();
+        let header = header(&state);
+        let mut other = header.clone();
+        other.limits.sprites += 1;
+        unsafe {
+            state.begin_chunk(&header).unwrap();
+            assert!(state.begin_chunk(&other).is_err());
+            let mut too_large = header.clone();
+            too_large.limits.images = !0;
+            state.load_failed();
+            assert!(state.begin_chunk(&too_large).is_err());
+            assert!(!state.is_in_game());
+        }
+    }
+
+    #[test]
+    fn save_phases() {
+        let mut state = PluginState::new();
+        assert!(state.begin_save(Chunk::Sprites).is_err());
+        state.phase = Phase::InGame;
+        state.begin_save(Chunk::Sprites).unwrap();
+        state.chunk_saved(Chunk::Sprites);
+        state.begin_save(Chunk::Units).unwrap();
+        state.chunk_saved(Chunk::Units);
+        assert!(matches!(state.phase, Phase::Saving(_)));
+        state.begin_save(Chunk::Bullets).unwrap();
+        state.chunk_saved(Chunk::Bullets);
+        assert!(matches!(state.phase, Phase::InGame));
+        // A failed save leaves the game running.
+        state.begin_save(Chunk::Sprites).unwrap();
+        state.save_failed();
+        assert!(matches!(state.phase, Phase::InGame));
+        let header = header(&state);
+        unsafe {
+            state.begin_chunk(&header).unwrap();
+        }
+        assert!(state.begin_save(Chunk::Sprites).is_err());
+    }
+}
@@ -1,28 +1,41 @@
+use std::convert::TryFrom;
 use std::mem;
 use std::ptr::null_mut;
 
 use bincode;
-use flate2;
+#[cfg(feature = "game")]
 use libc::c_void;
 
 use bw;
-use dat;
-use entity_serialize::{self, deserialize_entity, entity_serializable, EntitySerializable};
-use save::{fread, fwrite, fread_num, fwrite_num, SaveError, LoadError, print_text};
+use entity_serialize::{self, deserialize_entity, entity_serializable};
+#[cfg(feature = "game")]
+use game_memory::BwMemory;
+use game_memory::GameMemory;
+use save::{ErrorContext, LoadMapping, SaveError, LoadError};
+#[cfg(feature = "game")]
+use save::{fwrite_compressed, fwrite_num, read_chunk, print_text, show_load_error, BwFile};
+use save_format::{self, UnitAiSerializable, UnitGlobals, UnitSerializable};
+use save_format::{RallyPylonSerializable, UnitSpecificSerializable, UnitSpecificSerializable2};
+use save_format::UnitSpellsSerializable;
+use save_format::{UNIT_AI_COUNT, UNIT_COUNT, UNIT_SAVE_MAX_SIZE};
+#[cfg(feature = "game")]
+use save_format::{UNIT_SAVE_MAGIC, UNIT_SAVE_VERSION};
+#[cfg(feature = "game")]
+use state::Chunk;
+use state::plugin_state;
 use sprites::{
-    sprite_to_id_current_mapping,
-    sprite_from_id_current_mapping,
-    lone_sprite_from_id_current_mapping,
-    lone_sprite_to_id_current_mapping,
+    sprite_to_id,
+    sprite_from_id,
+    lone_sprite_to_id,
 };
 
-const UNIT_SAVE_MAGIC: u16 = 0xffed;
-// 16 megabytes, should be more than enough, both compressed and without.
-const UNIT_SAVE_MAX_SIZE: u32 = 0x1_000_000;
+struct ConvertUnits<'a, M: GameMemory + 'a>(&'a M);
 
-struct ConvertUnits;
+const ORDER_COUNT: u16 = 2000;
+/// BW allocates a path for each unit.
+const PATH_COUNT: u16 = UNIT_COUNT;
 
-const GHOST: u16 = 0x01;
+pub const GHOST: u16 = 0x01;
 const CARRIER: u16 = 0x48;
 const WARBRINGER: u16 = 0x51;
 const GANTRITHOR: u16 = 0x52;
@@ -30,260 +43,226 @@ const REAVER: u16 = 0x53;
 const SCARAB: u16 = 0x55;
 const INTERCEPTOR: u16 = 0x49;
 const NUCLEAR_SILO: u16 = 0x6c;
-const PYLON: u16 = 0x9c;
+pub const PYLON: u16 = 0x9c;
 const MINERAL_FIELD1: u16 = 0xb0;
 const MINERAL_FIELD2: u16 = 0xb1;
 const MINERAL_FIELD3: u16 = 0xb2;
 const VESPENE_GEYSER: u16 = 0xbc;
 
-impl entity_serialize::SaveEntityPointer for ConvertUnits {
+impl<'a, M: GameMemory> entity_serialize::SaveEntityPointer for ConvertUnits<'a, M> {
     type Pointer = bw::Unit;
     fn pointer_to_id(&self, val: *mut bw::Unit) -> Result<u32, SaveError> {
-        Ok(unit_to_id(val) as u32)
+        Ok(unit_to_id(self.0, val)? as u32)
     }
 }
 
-impl entity_serialize::LoadEntityPointer for ConvertUnits {
+impl<'a, M: GameMemory> entity_serialize::LoadEntityPointer for ConvertUnits<'a, M> {
     type Pointer = bw::Unit;
     fn id_to_pointer(&self, val: u32) -> Result<*mut bw::Unit, LoadError> {
-        unit_from_id(val as u16)
+        unit_from_u32(self.0, val)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct SaveGlobals {
-    first_active: u16,
-    last_active: u16,
-    first_hidden: u16,
-    last_hidden: u16,
-    first_dying: u16,
-    last_dying: u16,
-    first_revealer: u16,
-    last_revealer: u16,
-    first_free: u16,
-    last_free: u16,
-    first_invisible: u16,
-    player_units: [u16; 0xc],
-}
-
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
-enum UnitAiSerializable {
-    NoAi,
-    Guard(u16),
-    Worker(u16),
-    Building(u16),
-    Military(u16),
-}
-
 impl UnitAiSerializable {
-    unsafe fn new(ai: *mut bw::UnitAi) -> Result<UnitAiSerializable, SaveError> {
+    unsafe fn new<M: GameMemory>(
+        game: &M,
+        ai: *mut bw::UnitAi,
+    ) -> Result<UnitAiSerializable, SaveError> {
         use self::UnitAiSerializable::*;
         if ai == null_mut() {
             Ok(NoAi)
         } else {
             match (*ai).ty {
                 1 => {
-                    let ptr: *mut bw::GuardAi = &mut bw::guard_ais[0];
-                    let val = (ai as usize - ptr as usize) / mem::size_of::<bw::GuardAi>();
-                    assert!(val < 1000);
-                    Ok(Guard(val as u16))
+                    let ais = (*game.guard_ais()).as_mut_ptr();
+                    Ok(Guard(array_index(ai as *mut bw::GuardAi, ais, UNIT_AI_COUNT)? - 1))
                 }
                 2 => {
-                    let ptr: *mut bw::WorkerAi = &mut bw::worker_ais[0];
-                    let val = (ai as usize - ptr as usize) / mem::size_of::<bw::WorkerAi>();
-                    assert!(val < 1000);
-                    Ok(Worker(val as u16))
+                    let ais = (*game.worker_ais()).as_mut_ptr();
+                    Ok(Worker(array_index(ai as *mut bw::WorkerAi, ais, UNIT_AI_COUNT)? - 1))
                 }
                 3 => {
-                    let ptr: *mut bw::BuildingAi = &mut bw::building_ais[0];
-                    let val = (ai as usize - ptr as usize) / mem::size_of::<bw::BuildingAi>();
-                    assert!(val < 1000);
-                    Ok(Building(val as u16))
+                    let ais = (*game.building_ais()).as_mut_ptr();
+                    Ok(Building(array_index(ai as *mut bw::BuildingAi, ais, UNIT_AI_COUNT)? - 1))
                 }
                 4 => {
-                    let ptr: *mut bw::MilitaryAi = &mut bw::military_ais[0];
-                    let val = (ai as usize - ptr as usize) / mem::size_of::<bw::MilitaryAi>();
-                    assert!(val < 1000);
-                    Ok(Military(val as u16))
+                    let ais = (*game.military_ais()).as_mut_ptr();
+                    Ok(Military(array_index(ai as *mut bw::MilitaryAi, ais, UNIT_AI_COUNT)? - 1))
                 }
                 _ => Err(SaveError::InvalidUnitAi((*ai).ty)),
             }
         }
     }
 
-    unsafe fn to_pointer(self) -> Result<*mut bw::UnitAi, LoadError> {
+    unsafe fn to_pointer<M: GameMemory>(self, game: &M) -> Result<*mut bw::UnitAi, LoadError> {
         use self::UnitAiSerializable::*;
         match self {
             NoAi => Ok(null_mut()),
             Guard(val) => {
                 let val = val as usize;
-                if val >= 1000 {
+                if val >= UNIT_AI_COUNT as usize {
                     Err(LoadError::Corrupted(format!("Invalid unit ai {:?}", self)))
                 } else {
-                    Ok(&mut bw::guard_ais[val] as *mut bw::GuardAi as *mut bw::UnitAi)
+                    Ok(&mut (*game.guard_ais())[val] as *mut bw::GuardAi as *mut bw::UnitAi)
                 }
             }
             Worker(val) => {
                 let val = val as usize;
-                if val >= 1000 {
+                if val >= UNIT_AI_COUNT as usize {
                     Err(LoadError::Corrupted(format!("Invalid unit ai {:?}", self)))
                 } else {
-                    Ok(&mut bw::worker_ais[val] as *mut bw::WorkerAi as *mut bw::UnitAi)
+                    Ok(&mut (*game.worker_ais())[val] as *mut bw::WorkerAi as *mut bw::UnitAi)
                 }
             }
             Building(val) => {
                 let val = val as usize;
-                if val >= 1000 {
+                if val >= UNIT_AI_COUNT as usize {
                     Err(LoadError::Corrupted(format!("Invalid unit ai {:?}", self)))
                 } else {
-                    Ok(&mut bw::building_ais[val] as *mut bw::BuildingAi as *mut bw::UnitAi)
+                    Ok(&mut (*game.building_ais())[val] as *mut bw::BuildingAi as *mut bw::UnitAi)
                 }
             }
             Military(val) => {
                 let val = val as usize;
-                if val >= 1000 {
+                if val >= UNIT_AI_COUNT as usize {
                     Err(LoadError::Corrupted(format!("Invalid unit ai {:?}", self)))
                 } else {
-                    Ok(&mut bw::military_ais[val] as *mut bw::MilitaryAi as *mut bw::UnitAi)
+                    Ok(&mut (*game.military_ais())[val] as *mut bw::MilitaryAi as *mut bw::UnitAi)
                 }
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct UnitSpecificSerializable([u8; 0x10]);
-
 impl UnitSpecificSerializable {
-    unsafe fn new(
+    unsafe fn new<M: GameMemory>(
+        game: &M,
         mut data: [u8; 0x10],
         unit_id: u16,
         is_building: bool,
     ) -> Result<UnitSpecificSerializable, SaveError> {
         let ptr = data.as_mut_ptr();
         if has_hangar(unit_id) {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(4) as *mut u32) =
-                unit_to_id(*(ptr.offset(4) as *const *mut bw::Unit)) as u32;
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
+            write_u32(ptr.offset(4), unit_to_id(game, read_pointer(ptr.offset(4)))? as u32);
         } else if unit_id == INTERCEPTOR || unit_id == SCARAB {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(4) as *mut u32) =
-                unit_to_id(*(ptr.offset(4) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(8) as *mut u32) =
-                unit_to_id(*(ptr.offset(8) as *const *mut bw::Unit)) as u32;
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
+            write_u32(ptr.offset(4), unit_to_id(game, read_pointer(ptr.offset(4)))? as u32);
+            write_u32(ptr.offset(8), unit_to_id(game, read_pointer(ptr.offset(8)))? as u32);
         } else if is_building {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
-        } else if is_worker(unit_id) {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(8) as *mut u32) =
-                unit_to_id(*(ptr.offset(8) as *const *mut bw::Unit)) as u32;
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
+        } else if is_worker(game, unit_id) {
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
+            write_u32(ptr.offset(8), unit_to_id(game, read_pointer(ptr.offset(8)))? as u32);
         }
         Ok(UnitSpecificSerializable(data))
     }
 
-    unsafe fn deserialize(
+    unsafe fn deserialize<M: GameMemory>(
         mut self,
+        game: &M,
         unit_id: u16,
         is_building: bool
     ) -> Result<[u8; 0x10], LoadError> {
         let ptr = self.0.as_mut_ptr();
         if has_hangar(unit_id) {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
-            *(ptr.offset(4) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(4) as *const u16))?;
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
+            write_pointer(ptr.offset(4), unit_from_u32(game, read_u32(ptr.offset(4)))?);
         } else if unit_id == INTERCEPTOR || unit_id == SCARAB {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
-            *(ptr.offset(4) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(4) as *const u16))?;
-            *(ptr.offset(8) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(8) as *const u16))?;
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
+            write_pointer(ptr.offset(4), unit_from_u32(game, read_u32(ptr.offset(4)))?);
+            write_pointer(ptr.offset(8), unit_from_u32(game, read_u32(ptr.offset(8)))?);
         } else if is_building {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
-        } else if is_worker(unit_id) {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
-            *(ptr.offset(8) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(8) as *const u16))?;
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
+        } else if is_worker(game, unit_id) {
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
+            write_pointer(ptr.offset(8), unit_from_u32(game, read_u32(ptr.offset(8)))?);
         }
         Ok(self.0)
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct UnitSpecificSerializable2([u8; 0xc]);
-
 impl UnitSpecificSerializable2 {
-    unsafe fn new(
+    unsafe fn new<M: GameMemory>(
+        game: &M,
         mut data: [u8; 0xc],
         unit_id: u16,
     ) -> Result<UnitSpecificSerializable2, SaveError> {
         let ptr = data.as_mut_ptr();
-        if is_resource(unit_id) {
-            *(ptr.offset(4) as *mut u32) =
-                unit_to_id(*(ptr.offset(4) as *const *mut bw::Unit)) as u32;
-        } else if is_powerup(unit_id) {
-            *(ptr.offset(4) as *mut u32) =
-                unit_to_id(*(ptr.offset(4) as *const *mut bw::Unit)) as u32;
-        } else if is_worker(unit_id) {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(4) as *mut u32) =
-                unit_to_id(*(ptr.offset(4) as *const *mut bw::Unit)) as u32;
-            *(ptr.offset(8) as *mut u32) =
-                unit_to_id(*(ptr.offset(8) as *const *mut bw::Unit)) as u32;
+        if is_resource(unit_id) || is_powerup(game, unit_id) {
+            write_u32(ptr.offset(4), unit_to_id(game, read_pointer(ptr.offset(4)))? as u32);
+        } else if is_worker(game, unit_id) {
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
+            write_u32(ptr.offset(4), unit_to_id(game, read_pointer(ptr.offset(4)))? as u32);
+            write_u32(ptr.offset(8), unit_to_id(game, read_pointer(ptr.offset(8)))? as u32);
         } else if unit_id == NUCLEAR_SILO {
-            *(ptr.offset(0) as *mut u32) =
-                unit_to_id(*(ptr.offset(0) as *const *mut bw::Unit)) as u32;
+            write_u32(ptr.offset(0), unit_to_id(game, read_pointer(ptr.offset(0)))? as u32);
         } else if unit_id == GHOST {
-            *(ptr.offset(0) as *mut u32) = lone_sprite_to_id_current_mapping(
-                *(ptr.offset(0) as *const *mut bw::LoneSprite)
-            )? as u32;
+            write_u32(ptr.offset(0), lone_sprite_to_id(game, read_pointer(ptr.offset(0)))?);
         } else if unit_id == PYLON {
-            *(ptr.offset(0) as *mut u32) =
-                sprite_to_id_current_mapping(*(ptr.offset(0) as *const *mut bw::Sprite))? as u32;
+            write_u32(ptr.offset(0), sprite_to_id(read_pointer(ptr.offset(0)))?);
         }
         Ok(UnitSpecificSerializable2(data))
     }
 
-    unsafe fn deserialize(
+    /// Also returns the lone sprite id of a ghost's nuke dot, which is left null here, as
+    /// the lone sprites may not have been loaded yet.
+    unsafe fn deserialize<M: GameMemory>(
         mut self,
+        game: &M,
         unit_id: u16,
-    ) -> Result<[u8; 0xc], LoadError> {
+    ) -> Result<([u8; 0xc], u32), LoadError> {
         let ptr = self.0.as_mut_ptr();
-        if is_resource(unit_id) {
-            *(ptr.offset(4) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(4) as *const u16))?;
-        } else if is_powerup(unit_id) {
-            *(ptr.offset(4) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(4) as *const u16))?;
-        } else if is_worker(unit_id) {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
-            *(ptr.offset(4) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(4) as *const u16))?;
-            *(ptr.offset(8) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(8) as *const u16))?;
+        let mut nuke_dot = 0;
+        if is_resource(unit_id) || is_powerup(game, unit_id) {
+            write_pointer(ptr.offset(4), unit_from_u32(game, read_u32(ptr.offset(4)))?);
+        } else if is_worker(game, unit_id) {
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
+            write_pointer(ptr.offset(4), unit_from_u32(game, read_u32(ptr.offset(4)))?);
+            write_pointer(ptr.offset(8), unit_from_u32(game, read_u32(ptr.offset(8)))?);
         } else if unit_id == NUCLEAR_SILO {
-            *(ptr.offset(0) as *mut *mut bw::Unit) =
-                unit_from_id(*(ptr.offset(0) as *const u16))?;
+            write_pointer(ptr.offset(0), unit_from_u32(game, read_u32(ptr.offset(0)))?);
         } else if unit_id == GHOST {
-            *(ptr.offset(0) as *mut *mut bw::LoneSprite) =
-                lone_sprite_from_id_current_mapping(*(ptr.offset(0) as *const u32))?;
+            nuke_dot = read_u32(ptr.offset(0));
+            write_pointer(ptr.offset(0), null_mut::<bw::LoneSprite>());
         } else if unit_id == PYLON {
-            *(ptr.offset(0) as *mut *mut bw::Sprite) =
-                sprite_from_id_current_mapping(*(ptr.offset(0) as *const u32))?;
+            write_pointer(ptr.offset(0), sprite_from_id(read_u32(ptr.offset(0)))?);
+        }
+        Ok((self.0, nuke_dot))
+    }
+
+    /// Clears the sprite ids which aren't same for every player. Pylon auras are only
+    /// created for the local player, and the id of a ghost's nuke dot depends on the lone
+    /// sprites before it, so only whether the ghost has one is kept.
+    pub unsafe fn clear_local_sprite_ids(&mut self, unit_id: u16) {
+        let ptr = self.0.as_mut_ptr();
+        if unit_id == GHOST {
+            let has_nuke_dot = read_u32(ptr.offset(0)) != 0;
+            write_u32(ptr.offset(0), has_nuke_dot as u32);
+        } else if unit_id == PYLON {
+            write_u32(ptr.offset(0), 0);
         }
-        Ok(self.0)
     }
 }
 
+/// The unit-specific fields are byte arrays, in which BW stores 32-bit pointers that may
+/// not be aligned.
+unsafe fn read_pointer<T>(ptr: *const u8) -> *mut T {
+    read_u32(ptr) as usize as *mut T
+}
+
+unsafe fn write_pointer<T>(ptr: *mut u8, value: *mut T) {
+    write_u32(ptr, value as usize as u32)
+}
+
+unsafe fn read_u32(ptr: *const u8) -> u32 {
+    (ptr as *const u32).read_unaligned()
+}
+
+unsafe fn write_u32(ptr: *mut u8, value: u32) {
+    (ptr as *mut u32).write_unaligned(value)
+}
+
 fn has_hangar(unit_id: u16) -> bool {
     unit_id == CARRIER ||
         unit_id == GANTRITHOR ||
@@ -298,207 +277,143 @@ fn is_resource(unit_id: u16) -> bool {
         unit_id == VESPENE_GEYSER
 }
 
-unsafe fn is_worker(unit_id: u16) -> bool {
-    dat::units::flags(unit_id) & 0x8 != 0
-}
-
-unsafe fn is_powerup(unit_id: u16) -> bool {
-    dat::units::flags(unit_id) & 0x800 != 0
+/// Only valid for unit ids below `game.unit_type_count()`.
+fn is_worker<M: GameMemory>(game: &M, unit_id: u16) -> bool {
+    game.unit_flags(unit_id) & 0x8 != 0
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-struct RallyPylonSerializable {
-    val1: u16,
-    val2: u16,
-    val3: u16,
+fn is_powerup<M: GameMemory>(game: &M, unit_id: u16) -> bool {
+    game.unit_flags(unit_id) & 0x800 != 0
 }
 
 impl RallyPylonSerializable {
-    unsafe fn new(data: [u8; 0x8], unit_id: u16) -> RallyPylonSerializable {
+    unsafe fn new<M: GameMemory>(
+        game: &M,
+        data: [u8; 0x8],
+        unit_id: u16,
+    ) -> Result<RallyPylonSerializable, SaveError> {
         let data = data.as_ptr();
         if unit_id == PYLON {
-            RallyPylonSerializable {
-                val1: unit_to_id(*(data.offset(0) as *const *mut bw::Unit)),
-                val2: unit_to_id(*(data.offset(4) as *const *mut bw::Unit)),
+            Ok(RallyPylonSerializable {
+                val1: unit_to_id(game, read_pointer(data.offset(0)))?,
+                val2: unit_to_id(game, read_pointer(data.offset(4)))?,
                 val3: 0,
-            }
+            })
         } else {
             // Whatever
-            RallyPylonSerializable {
-                val1: *(data.offset(0) as *const u16),
-                val2: *(data.offset(2) as *const u16),
-                val3: unit_to_id(*(data.offset(4) as *const *mut bw::Unit)),
-            }
+            Ok(RallyPylonSerializable {
+                val1: (data.offset(0) as *const u16).read_unaligned(),
+                val2: (data.offset(2) as *const u16).read_unaligned(),
+                val3: unit_to_id(game, read_pointer(data.offset(4)))?,
+            })
         }
     }
 
-    unsafe fn deserialize(self, unit_id: u16) -> Result<[u8; 8], LoadError> {
+    unsafe fn deserialize<M: GameMemory>(
+        self,
+        game: &M,
+        unit_id: u16,
+    ) -> Result<[u8; 8], LoadError> {
         let mut result = [0u8; 8];
         let ptr = result.as_mut_ptr();
         if unit_id == PYLON {
-            *(ptr.offset(0) as *mut *mut bw::Unit) = unit_from_id(self.val1)?;
-            *(ptr.offset(4) as *mut *mut bw::Unit) = unit_from_id(self.val2)?;
+            write_pointer(ptr.offset(0), unit_from_id(game, self.val1)?);
+            write_pointer(ptr.offset(4), unit_from_id(game, self.val2)?);
         } else {
-            *(ptr.offset(0) as *mut u16) = self.val1;
-            *(ptr.offset(2) as *mut u16) = self.val2;
-            *(ptr.offset(4) as *mut *mut bw::Unit) = unit_from_id(self.val3)?;
+            (ptr.offset(0) as *mut u16).write_unaligned(self.val1);
+            (ptr.offset(2) as *mut u16).write_unaligned(self.val2);
+            write_pointer(ptr.offset(4), unit_from_id(game, self.val3)?);
         }
         Ok(result)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct UnitSerializable {
-    entity: EntitySerializable,
-    shields: i32,
-    unit_id: u16,
-    unused66: u16,
-    next_player_unit: u16,
-    prev_player_unit: u16,
-    subunit: u16,
-    order_queue_begin: u16,
-    order_queue_end: u16,
-    previous_attacker: u16,
-    related: u16,
-    highlight_order_count: u8,
-    order_wait: u8,
-    unk86: u8,
-    attack_notify_timer: u8,
-    previous_unit_id: u16,
-    minimap_draw_counter: u8,
-    minimap_draw_color: u8,
-    unused8c: u16,
-    rank: u8,
-    kills: u8,
-    last_attacking_player: u8,
-    secondary_order_wait: u8,
-    ai_spell_flags: u8,
-    order_flags: u8,
-    buttons: u16,
-    invisibility_effects: u8,
-    movement_state: u8,
-    build_queue: [u16; 5],
-    energy: u16,
-    current_build_slot: u8,
-    minor_unique_index: u8,
-    secondary_order: u8,
-    building_overlay_state: u8,
-    build_hp_gain: u16,
-    build_shield_gain: u16,
-    remaining_build_time: u16,
-    previous_hp: u16,
-    loaded_units: [u16; 8],
-    unit_specific: UnitSpecificSerializable,
-    unit_specific2: UnitSpecificSerializable2,
-    flags: u32,
-    carried_powerup_flags: u8,
-    wireframe_seed: u8,
-    secondary_order_state: u8,
-    move_target_update_timer: u8,
-    detection_status: u32,
-    unke8: u16,
-    unkea: u16,
-    currently_building: u16,
-    next_invisible: u16,
-    prev_invisible: u16,
-    rally_pylon: RallyPylonSerializable,
-    path: u16,
-    path_frame: u8,
-    pathing_flags: u8,
-    _unk106: u8,
-    _unk107: u8,
-    collision_points: [u16; 0x4],
-    spells: UnitSpellsSerializable,
-    bullet_spread_seed: u16,
-    _padding132: [u8; 2],
-    ai: UnitAiSerializable,
-    air_strength: u16,
-    ground_strength: u16,
-    pos_search_left: u32,
-    pos_search_right: u32,
-    pos_search_top: u32,
-    pos_search_bottom: u32,
-    repulse: bw::Repulse,
-}
-
-#[derive(Serialize, Deserialize)]
-struct UnitSpellsSerializable {
-    death_timer: u16,
-    defensive_matrix_dmg: u16,
-    matrix_timer: u8,
-    stim_timer: u8,
-    ensnare_timer: u8,
-    lockdown_timer: u8,
-    irradiate_timer: u8,
-    stasis_timer: u8,
-    plague_timer: u8,
-    is_under_storm: u8,
-    irradiated_by: u16,
-    irradiate_player: u8,
-    parasited_by_players: u8,
-    master_spell_timer: u8,
-    is_blind: u8,
-    maelstrom_timer: u8,
-    _unk125: u8,
-    acid_spore_count: u8,
-    acid_spore_timers: [u8; 0x9],
-}
-
+#[cfg(feature = "game")]
 pub unsafe fn save_unit_chunk(file: *mut c_void) -> u32 {
-    if let Err(e) = save_units(file) {
-        error!("Couldn't save units: {}", e);
+    if let Err(e) = save_units(file).context(|| "Units") {
+        plugin_state().borrow_mut().save_failed();
+        error!("Couldn't save: {}", e);
         print_text(&format!("Unable to save the game: {}", e));
         return 0;
     }
+    plugin_state().borrow_mut().chunk_saved(Chunk::Units);
     1
 }
 
+#[cfg(feature = "game")]
 unsafe fn save_units(file: *mut c_void) -> Result<(), SaveError> {
-    let data = serialize_units()?;
+    plugin_state().borrow_mut().begin_save(Chunk::Units)?;
+    let data = serialize_units(&BwMemory)?;
     fwrite_num(file, UNIT_SAVE_MAGIC)?;
-    fwrite_num(file, 1u32)?;
-    fwrite_num(file, data.len() as u32)?;
-    fwrite(file, &data)?;
+    fwrite_num(file, UNIT_SAVE_VERSION)?;
+    fwrite_compressed(file, data)?;
     Ok(())
 }
 
-unsafe fn serialize_units() -> Result<Vec<u8>, SaveError> {
-    let buf = Vec::with_capacity(0x10000);
-    let mut writer = flate2::write::DeflateEncoder::new(buf, flate2::Compression::Default);
+pub unsafe fn serialize_units<M: GameMemory>(game: &M) -> Result<Vec<u8>, SaveError> {
+    let mut buf = Vec::with_capacity(0x10000);
 
     let size_limit = bincode::Bounded(UNIT_SAVE_MAX_SIZE as u64);
-    let globals = SaveGlobals {
-        first_active: unit_to_id(*bw::first_active_unit),
-        last_active: unit_to_id(*bw::last_active_unit),
-        first_hidden: unit_to_id(*bw::first_hidden_unit),
-        last_hidden: unit_to_id(*bw::last_hidden_unit),
-        first_dying: unit_to_id(*bw::first_dying_unit),
-        last_dying: unit_to_id(*bw::last_dying_unit),
-        first_revealer: unit_to_id(*bw::first_revealer),
-        last_revealer: unit_to_id(*bw::last_revealer),
-        first_invisible: unit_to_id(*bw::first_invisible_unit),
-        first_free: unit_to_id(*bw::first_free_unit),
-        last_free: unit_to_id(*bw::last_free_unit),
+    let free_units = free_unit_slots(game)?;
+    let globals = UnitGlobals {
+        first_active: unit_to_id(game, *game.first_active_unit()).context(|| "first_active")?,
+        last_active: unit_to_id(game, *game.last_active_unit()).context(|| "last_active")?,
+        first_hidden: unit_to_id(game, *game.first_hidden_unit()).context(|| "first_hidden")?,
+        last_hidden: unit_to_id(game, *game.last_hidden_unit()).context(|| "last_hidden")?,
+        first_dying: unit_to_id(game, *game.first_dying_unit()).context(|| "first_dying")?,
+        last_dying: unit_to_id(game, *game.last_dying_unit()).context(|| "last_dying")?,
+        first_revealer: unit_to_id(game, *game.first_revealer()).context(|| "first_revealer")?,
+        last_revealer: unit_to_id(game, *game.last_revealer()).context(|| "last_revealer")?,
+        first_invisible: unit_to_id(game, *game.first_invisible_unit())
+            .context(|| "first_invisible")?,
+        live_unit_count: free_units.iter().filter(|&&free| !free).count() as u16,
         player_units: {
             let mut ids = [0; 0xc];
-            for (&unit, out) in bw::first_player_unit.iter().zip(ids.iter_mut()) {
-                *out = unit_to_id(unit);
+            let player_units = (*game.first_player_unit()).iter().zip(ids.iter_mut());
+            for (i, (&unit, out)) in player_units.enumerate() {
+                *out = unit_to_id(game, unit).context(|| format!("Player {} units", i))?;
             }
             ids
         }
     };
-    bincode::serialize_into(&mut writer, &globals, size_limit)?;
-    for unit in bw::units.iter() {
-        let serializable = unit_serializable(unit)?;
-        bincode::serialize_into(&mut writer, &serializable, size_limit)?;
-        if writer.total_in() > UNIT_SAVE_MAX_SIZE as u64{
-            return Err(SaveError::SizeLimit(writer.total_in()));
+    bincode::serialize_into(&mut buf, &plugin_state().borrow().save_header(), size_limit)?;
+    bincode::serialize_into(&mut buf, &globals, size_limit)?;
+    for (i, unit) in (*game.units()).iter().enumerate() {
+        if free_units[i] {
+            continue;
+        }
+        let unit_id = unit.unit_id;
+        let serializable = unit_serializable(game, unit)
+            .context(|| format!("Unit 0x{:x} ({:x})", i + 1, unit_id))?;
+        bincode::serialize_into(&mut buf, &(i as u16 + 1, serializable), size_limit)?;
+        if buf.len() > UNIT_SAVE_MAX_SIZE as usize {
+            return Err(SaveError::SizeLimit(buf.len() as u64));
+        }
+    }
+    Ok(buf)
+}
+
+/// Marks the slots of the unit array that are in the free unit list.
+pub unsafe fn free_unit_slots<M: GameMemory>(game: &M) -> Result<Vec<bool>, SaveError> {
+    let mut free_units = vec![false; UNIT_COUNT as usize];
+    let mut unit = *game.first_free_unit();
+    let mut count = 0;
+    while unit != null_mut() {
+        // The list can't have more units than the array, unless it loops back.
+        count += 1;
+        if count > UNIT_COUNT {
+            return Err(SaveError::InvalidPointer).context(|| "Free unit list loops");
         }
+        free_units[unit_to_id(game, unit).context(|| "Free units")? as usize - 1] = true;
+        unit = (*unit).entity.next as *mut bw::Unit;
     }
-    Ok(writer.finish()?)
+    Ok(free_units)
 }
 
-unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, SaveError> {
+pub unsafe fn unit_serializable<M: GameMemory>(
+    game: &M,
+    unit: *const bw::Unit,
+) -> Result<UnitSerializable, SaveError> {
     let bw::Unit {
         ref entity,
         shields,
@@ -593,17 +508,17 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
     } = *unit;
     let is_building = flags & 0x2 != 0;
     Ok(UnitSerializable {
-        entity: entity_serializable(entity, &ConvertUnits)?,
+        entity: entity_serializable(game, entity, &ConvertUnits(game)).context(|| "entity")?,
         shields,
         unit_id,
         unused66,
-        next_player_unit: unit_to_id(next_player_unit),
-        prev_player_unit: unit_to_id(prev_player_unit),
-        subunit: unit_to_id(subunit),
-        order_queue_begin: order_to_id(order_queue_begin),
-        order_queue_end: order_to_id(order_queue_end),
-        previous_attacker: unit_to_id(previous_attacker),
-        related: unit_to_id(related),
+        next_player_unit: unit_to_id(game, next_player_unit).context(|| "next_player_unit")?,
+        prev_player_unit: unit_to_id(game, prev_player_unit).context(|| "prev_player_unit")?,
+        subunit: unit_to_id(game, subunit).context(|| "subunit")?,
+        order_queue_begin: order_to_id(game, order_queue_begin).context(|| "order_queue_begin")?,
+        order_queue_end: order_to_id(game, order_queue_end).context(|| "order_queue_end")?,
+        previous_attacker: unit_to_id(game, previous_attacker).context(|| "previous_attacker")?,
+        related: unit_to_id(game, related).context(|| "related")?,
         highlight_order_count,
         order_wait,
         unk86,
@@ -632,8 +547,10 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
         remaining_build_time,
         previous_hp,
         loaded_units,
-        unit_specific: UnitSpecificSerializable::new(unit_specific, unit_id, is_building)?,
-        unit_specific2: UnitSpecificSerializable2::new(unit_specific2, unit_id)?,
+        unit_specific: UnitSpecificSerializable::new(game, unit_specific, unit_id, is_building)
+            .context(|| "unit_specific")?,
+        unit_specific2: UnitSpecificSerializable2::new(game, unit_specific2, unit_id)
+            .context(|| "unit_specific2")?,
         flags,
         carried_powerup_flags,
         wireframe_seed,
@@ -642,11 +559,12 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
         detection_status,
         unke8,
         unkea,
-        currently_building: unit_to_id(currently_building),
-        next_invisible: unit_to_id(next_invisible),
-        prev_invisible: unit_to_id(prev_invisible),
-        rally_pylon: RallyPylonSerializable::new(rally_pylon, unit_id),
-        path: path_to_id(path),
+        currently_building: unit_to_id(game, currently_building).context(|| "currently_building")?,
+        next_invisible: unit_to_id(game, next_invisible).context(|| "next_invisible")?,
+        prev_invisible: unit_to_id(game, prev_invisible).context(|| "prev_invisible")?,
+        rally_pylon: RallyPylonSerializable::new(game, rally_pylon, unit_id)
+            .context(|| "rally_pylon")?,
+        path: path_to_id(game, path).context(|| "path")?,
         path_frame,
         pathing_flags,
         _unk106,
@@ -663,7 +581,7 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
             stasis_timer,
             plague_timer,
             is_under_storm,
-            irradiated_by: unit_to_id(irradiated_by),
+            irradiated_by: unit_to_id(game, irradiated_by).context(|| "irradiated_by")?,
             irradiate_player,
             parasited_by_players,
             master_spell_timer,
@@ -675,7 +593,7 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
         },
         bullet_spread_seed,
         _padding132,
-        ai: UnitAiSerializable::new(ai)?,
+        ai: UnitAiSerializable::new(game, ai).context(|| "ai")?,
         air_strength,
         ground_strength,
         pos_search_left,
@@ -686,69 +604,155 @@ unsafe fn unit_serializable(unit: *const bw::Unit) -> Result<UnitSerializable, S
     })
 }
 
+#[cfg(feature = "game")]
 pub unsafe fn load_unit_chunk(file: *mut c_void, save_version: u32) -> u32 {
     if save_version != 3 {
         error!("Unusupported save version: {}", save_version);
+        plugin_state().borrow_mut().load_failed();
         return 0;
     }
-    if let Err(e) = load_units(file) {
-        info!("Couldn't load a save: {}", e);
+    if let Err(e) = read_units(file).context(|| "Units") {
+        error!("Couldn't load a save: {}", e);
+        show_load_error(&e);
+        plugin_state().borrow_mut().load_failed();
         return 0;
     }
+    plugin_state().borrow_mut().chunk_loaded(Chunk::Units);
     1
 }
 
-unsafe fn load_units(file: *mut c_void) -> Result<(), LoadError> {
-    let magic = fread_num::<u16>(file)?;
-    if magic != UNIT_SAVE_MAGIC {
-        return Err(LoadError::WrongMagic(magic));
+#[cfg(feature = "game")]
+unsafe fn read_units(file: *mut c_void) -> Result<(), LoadError> {
+    let data = read_chunk(BwFile(file), UNIT_SAVE_MAGIC, UNIT_SAVE_VERSION, UNIT_SAVE_MAX_SIZE)?;
+    load_units(&BwMemory, &data)?;
+    add_units_to_game()
+}
+
+/// Loads the decompressed unit chunk. The units still have to be added to BW's
+/// position search and other structures that aren't saved.
+pub unsafe fn load_units<M: GameMemory>(game: &M, data: &[u8]) -> Result<(), LoadError> {
+    let chunk = save_format::decode_units(data)?;
+    let globals = chunk.globals;
+    plugin_state().borrow_mut().begin_chunk(&chunk.header)?;
+    let mut nuke_dots = Vec::new();
+    let units = chunk.units.iter().map(|&(id, ref serialized)| {
+        let (unit, nuke_dot) = deserialize_unit(game, serialized)
+            .context(|| format!("Unit 0x{:x} ({:x})", id, serialized.unit_id))?;
+        if nuke_dot != 0 {
+            nuke_dots.push((id, nuke_dot));
+        }
+        Ok((id, unit))
+    }).collect::<Result<Vec<_>, LoadError>>()?;
+    // If the sprite chunk hasn't been loaded yet, it sets the nuke dots once it is.
+    let resolved_nuke_dots = match plugin_state().borrow().lone_sprite_load_mapping() {
+        Some(lone_sprites) => Some(resolve_nuke_dots(&nuke_dots, lone_sprites)?),
+        None => None,
+    };
+    let first_active = unit_from_id(game, globals.first_active)?;
+    let first_hidden = unit_from_id(game, globals.first_hidden)?;
+    let first_dying = unit_from_id(game, globals.first_dying)?;
+    let first_revealer = unit_from_id(game, globals.first_revealer)?;
+    let first_invisible = unit_from_id(game, globals.first_invisible)?;
+    let last_active = unit_from_id(game, globals.last_active)?;
+    let last_hidden = unit_from_id(game, globals.last_hidden)?;
+    let last_dying = unit_from_id(game, globals.last_dying)?;
+    let last_revealer = unit_from_id(game, globals.last_revealer)?;
+    let player_units = globals.player_units.iter()
+        .map(|&x| unit_from_id(game, x))
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
+    // Nothing can fail anymore, so the units can be written to BW's globals.
+    let mut live_units = [false; UNIT_COUNT as usize];
+    for (id, unit) in units {
+        // The ids were checked to be valid and unique when decoding.
+        let index = id as usize - 1;
+        live_units[index] = true;
+        (*game.units())[index] = unit;
     }
-    let version = fread_num::<u32>(file)?;
-    if version != 1 {
-        return Err(LoadError::Version(version));
+    rebuild_free_units(game, &live_units);
+    *game.first_active_unit() = first_active;
+    *game.first_hidden_unit() = first_hidden;
+    *game.first_dying_unit() = first_dying;
+    *game.first_revealer() = first_revealer;
+    *game.first_invisible_unit() = first_invisible;
+    *game.last_active_unit() = last_active;
+    *game.last_hidden_unit() = last_hidden;
+    *game.last_dying_unit() = last_dying;
+    *game.last_revealer() = last_revealer;
+    for (unit, saved) in (*game.first_player_unit()).iter_mut().zip(player_units) {
+        *unit = saved;
     }
-    let size = fread_num::<u32>(file)?;
-    if size > UNIT_SAVE_MAX_SIZE {
-        return Err(LoadError::Corrupted(format!("Unit chunk size {} is too large", size)));
+    match resolved_nuke_dots {
+        Some(resolved) => set_nuke_dots(game, &resolved),
+        None => plugin_state().borrow_mut().defer_nuke_dots(nuke_dots),
     }
-    let data = fread(file, size)?;
-    let mut reader = flate2::read::DeflateDecoder::new(&data[..]);
+    Ok(())
+}
 
+/// Ghosts' nuke dots are lone sprites, which are identified by their position in the lone
+/// sprite list of the sprite chunk, so they can only be resolved once both the unit and
+/// the sprite chunk have been loaded.
+pub fn resolve_nuke_dots(
+    nuke_dots: &[(u16, u32)],
+    lone_sprites: &LoadMapping<bw::LoneSprite>,
+) -> Result<Vec<(u16, *mut bw::LoneSprite)>, LoadError> {
+    nuke_dots.iter().map(|&(unit, id)| {
+        let sprite = lone_sprites.pointer(id)
+            .context(|| format!("Nuke dot of unit 0x{:x}", unit))?;
+        Ok((unit, sprite))
+    }).collect()
+}
 
-    let size_limit = bincode::Bounded(UNIT_SAVE_MAX_SIZE as u64);
-    let globals: SaveGlobals = bincode::deserialize_from(&mut reader, size_limit)?;
-    for unit in bw::units.iter_mut() {
-        let serialized = bincode::deserialize_from(&mut reader, size_limit)?;
-        *unit = deserialize_unit(&serialized)?;
-        if reader.total_out() > UNIT_SAVE_MAX_SIZE as u64 {
-            return Err(LoadError::SizeLimit)
-        }
-    }
-    *bw::first_active_unit = unit_from_id(globals.first_active)?;
-    *bw::first_hidden_unit = unit_from_id(globals.first_hidden)?;
-    *bw::first_dying_unit = unit_from_id(globals.first_dying)?;
-    *bw::first_revealer= unit_from_id(globals.first_revealer)?;
-    *bw::first_free_unit = unit_from_id(globals.first_free)?;
-    *bw::first_invisible_unit = unit_from_id(globals.first_invisible)?;
-    *bw::last_active_unit = unit_from_id(globals.last_active)?;
-    *bw::last_hidden_unit = unit_from_id(globals.last_hidden)?;
-    *bw::last_dying_unit = unit_from_id(globals.last_dying)?;
-    *bw::last_revealer= unit_from_id(globals.last_revealer)?;
-    *bw::last_free_unit = unit_from_id(globals.last_free)?;
-    for (unit, &saved) in bw::first_player_unit.iter_mut().zip(globals.player_units.iter()) {
-        *unit = unit_from_id(saved)?;
+pub unsafe fn set_nuke_dots<M: GameMemory>(game: &M, nuke_dots: &[(u16, *mut bw::LoneSprite)]) {
+    for &(unit, sprite) in nuke_dots {
+        let unit = &mut (*game.units())[unit as usize - 1];
+        write_pointer(unit.unit_specific2.as_mut_ptr(), sprite);
     }
+}
 
+#[cfg(feature = "game")]
+unsafe fn add_units_to_game() -> Result<(), LoadError> {
+    // A corrupted save may have the list loop back, so it's not walked past the amount
+    // of units there are.
     let mut unit = *bw::first_active_unit;
+    let mut count = 0;
     while unit != null_mut() {
-        add_unit_to_game(unit);
+        count += 1;
+        if count > UNIT_COUNT {
+            return Err(LoadError::Corrupted("Active unit list loops".into()));
+        }
+        add_unit_to_game(unit)?;
         unit = (*unit).entity.next as *mut bw::Unit;
     }
 
     Ok(())
 }
 
-unsafe fn add_unit_to_game(unit: *mut bw::Unit) {
+/// Clears the unit slots that weren't in the save, and links them to the free list
+/// in index order.
+pub unsafe fn rebuild_free_units<M: GameMemory>(game: &M, live_units: &[bool]) {
+    let mut prev: *mut bw::Unit = null_mut();
+    *game.first_free_unit() = null_mut();
+    let units = (*game.units()).iter_mut().zip(live_units.iter());
+    for (unit, _) in units.filter(|&(_, &live)| !live) {
+        let unit: *mut bw::Unit = unit;
+        *unit = mem::zeroed();
+        (*unit).entity.prev = prev as *mut bw::Entity;
+        if prev == null_mut() {
+            *game.first_free_unit() = unit;
+        } else {
+            (*prev).entity.next = unit as *mut bw::Entity;
+        }
+        prev = unit;
+    }
+    *game.last_free_unit() = prev;
+}
+
+#[cfg(feature = "game")]
+unsafe fn add_unit_to_game(unit: *mut bw::Unit) -> Result<(), LoadError> {
+    if (*unit).entity.sprite == null_mut() {
+        return Err(LoadError::Corrupted(format!("Active unit {:p} has no sprite", unit)));
+    }
     if (*unit).pos_search_left != !0 {
         (*unit).pos_search_left = !0;
         (*unit).pos_search_top = !0;
@@ -764,9 +768,14 @@ unsafe fn add_unit_to_game(unit: *mut bw::Unit) {
             bw::add_to_repulse_chunk(unit);
         }
     }
+    Ok(())
 }
 
-unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadError> {
+/// Returns the unit with the lone sprite id of its nuke dot, see `resolve_nuke_dots`.
+unsafe fn deserialize_unit<M: GameMemory>(
+    game: &M,
+    unit: &UnitSerializable,
+) -> Result<(bw::Unit, u32), LoadError> {
     let UnitSerializable {
         ref entity,
         shields,
@@ -859,19 +868,24 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
         pos_search_bottom,
         ref repulse,
     } = *unit;
+    if unit_id as u32 >= game.unit_type_count() {
+        return Err(LoadError::Corrupted(format!("Invalid unit type 0x{:x}", unit_id)));
+    }
     let is_building = flags & 0x2 != 0;
-    Ok(bw::Unit {
-        entity: deserialize_entity(entity, &ConvertUnits)?,
+    let (unit_specific2, nuke_dot) = unit_specific2.clone().deserialize(game, unit_id)
+        .context(|| "unit_specific2")?;
+    let unit = bw::Unit {
+        entity: deserialize_entity(game, entity, &ConvertUnits(game)).context(|| "entity")?,
         shields,
         unit_id,
         unused66,
-        next_player_unit: unit_from_id(next_player_unit)?,
-        prev_player_unit: unit_from_id(prev_player_unit)?,
-        subunit: unit_from_id(subunit)?,
-        order_queue_begin: order_from_id(order_queue_begin)?,
-        order_queue_end: order_from_id(order_queue_end)?,
-        previous_attacker: unit_from_id(previous_attacker)?,
-        related: unit_from_id(related)?,
+        next_player_unit: unit_from_id(game, next_player_unit).context(|| "next_player_unit")?,
+        prev_player_unit: unit_from_id(game, prev_player_unit).context(|| "prev_player_unit")?,
+        subunit: unit_from_id(game, subunit).context(|| "subunit")?,
+        order_queue_begin: order_from_id(game, order_queue_begin).context(|| "order_queue_begin")?,
+        order_queue_end: order_from_id(game, order_queue_end).context(|| "order_queue_end")?,
+        previous_attacker: unit_from_id(game, previous_attacker).context(|| "previous_attacker")?,
+        related: unit_from_id(game, related).context(|| "related")?,
         highlight_order_count,
         order_wait,
         unk86,
@@ -900,8 +914,9 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
         remaining_build_time,
         previous_hp,
         loaded_units,
-        unit_specific: unit_specific.clone().deserialize(unit_id, is_building)?,
-        unit_specific2: unit_specific2.clone().deserialize(unit_id)?,
+        unit_specific: unit_specific.clone().deserialize(game, unit_id, is_building)
+            .context(|| "unit_specific")?,
+        unit_specific2,
         flags,
         carried_powerup_flags,
         wireframe_seed,
@@ -910,11 +925,12 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
         detection_status,
         unke8,
         unkea,
-        currently_building: unit_from_id(currently_building)?,
-        next_invisible: unit_from_id(next_invisible)?,
-        prev_invisible: unit_from_id(prev_invisible)?,
-        rally_pylon: rally_pylon.clone().deserialize(unit_id)?,
-        path: path_from_id(path)?,
+        currently_building: unit_from_id(game, currently_building)
+            .context(|| "currently_building")?,
+        next_invisible: unit_from_id(game, next_invisible).context(|| "next_invisible")?,
+        prev_invisible: unit_from_id(game, prev_invisible).context(|| "prev_invisible")?,
+        rally_pylon: rally_pylon.clone().deserialize(game, unit_id).context(|| "rally_pylon")?,
+        path: path_from_id(game, path).context(|| "path")?,
         path_frame,
         pathing_flags,
         _unk106,
@@ -931,7 +947,7 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
             stasis_timer,
             plague_timer,
             is_under_storm,
-            irradiated_by: unit_from_id(irradiated_by)?,
+            irradiated_by: unit_from_id(game, irradiated_by).context(|| "irradiated_by")?,
             irradiate_player,
             parasited_by_players,
             master_spell_timer,
@@ -943,7 +959,7 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
         },
         bullet_spread_seed,
         _padding132,
-        ai: ai.to_pointer()?,
+        ai: ai.to_pointer(game).context(|| "ai")?,
         air_strength,
         ground_strength,
         pos_search_left,
@@ -951,71 +967,135 @@ unsafe fn deserialize_unit(unit: &UnitSerializable) -> Result<bw::Unit, LoadErro
         pos_search_top,
         pos_search_bottom,
         repulse: repulse.clone(),
-    })
+    };
+    Ok((unit, nuke_dot))
 }
 
-pub fn unit_to_id(val: *mut bw::Unit) -> u16 {
-    unsafe {
-        if val == null_mut() {
-            0
-        } else {
-            let ptr: *mut bw::Unit = &mut bw::units[0];
-            let val = (val as usize - ptr as usize) / mem::size_of::<bw::Unit>();
-            assert!(val < 1700);
-            val as u16 + 1
-        }
+/// Converts a pointer to an element of BW's array starting at `start` to a 1-based index,
+/// or 0 for null.
+fn array_index<T>(val: *mut T, start: *mut T, len: u16) -> Result<u16, SaveError> {
+    if val == null_mut() {
+        return Ok(0);
+    }
+    let offset = (val as usize).wrapping_sub(start as usize);
+    let size = mem::size_of::<T>();
+    if !offset.is_multiple_of(size) || offset / size >= len as usize {
+        Err(SaveError::InvalidPointer)
+    } else {
+        Ok((offset / size) as u16 + 1)
     }
 }
 
-pub fn unit_from_id(val: u16) -> Result<*mut bw::Unit, LoadError> {
+pub fn unit_to_id<M: GameMemory>(game: &M, val: *mut bw::Unit) -> Result<u16, SaveError> {
+    unsafe { array_index(val, (*game.units()).as_mut_ptr(), UNIT_COUNT) }
+}
+
+pub fn unit_from_id<M: GameMemory>(game: &M, val: u16) -> Result<*mut bw::Unit, LoadError> {
     if val == 0 {
         Ok(null_mut())
-    } else if val <= 1700 {
-        unsafe { Ok(&mut bw::units[val as usize - 1]) }
+    } else if val <= UNIT_COUNT {
+        unsafe { Ok(&mut (*game.units())[val as usize - 1]) }
     } else {
         Err(LoadError::Corrupted(format!("Invalid unit id 0x{:x}", val)))
     }
 }
 
-pub fn order_to_id(val: *mut bw::Order) -> u16 {
-    unsafe {
-        if val == null_mut() {
-            0
-        } else {
-            let ptr: *mut bw::Order = &mut bw::orders[0];
-            let val = (val as usize - ptr as usize) / mem::size_of::<bw::Order>();
-            assert!(val < 2000);
-            val as u16 + 1
-        }
-    }
+/// Unit-specific data and entities store unit ids as 32-bit values.
+fn unit_from_u32<M: GameMemory>(game: &M, val: u32) -> Result<*mut bw::Unit, LoadError> {
+    let id = u16::try_from(val)
+        .map_err(|_| LoadError::Corrupted(format!("Invalid unit id 0x{:x}", val)))?;
+    unit_from_id(game, id)
+}
+
+pub fn order_to_id<M: GameMemory>(game: &M, val: *mut bw::Order) -> Result<u16, SaveError> {
+    unsafe { array_index(val, (*game.orders()).as_mut_ptr(), ORDER_COUNT) }
 }
 
-pub fn order_from_id(val: u16) -> Result<*mut bw::Order, LoadError> {
+pub fn order_from_id<M: GameMemory>(game: &M, val: u16) -> Result<*mut bw::Order, LoadError> {
     if val == 0 {
         Ok(null_mut())
-    } else if val <= 2000 {
-        unsafe { Ok(&mut bw::orders[val as usize - 1]) }
+    } else if val <= ORDER_COUNT {
+        unsafe { Ok(&mut (*game.orders())[val as usize - 1]) }
     } else {
         Err(LoadError::Corrupted(format!("Invalid order id 0x{:x}", val)))
     }
 }
 
-pub fn path_to_id(val: *mut bw::Path) -> u16 {
-    unsafe {
-        if val == null_mut() {
-            0
-        } else {
-            let ptr: *mut bw::Path = *bw::path_array_start;
-            let val = (val as usize - ptr as usize) / mem::size_of::<bw::Path>();
-            val as u16 + 1
-        }
-    }
+pub fn path_to_id<M: GameMemory>(game: &M, val: *mut bw::Path) -> Result<u16, SaveError> {
+    unsafe { array_index(val, *game.path_array_start(), PATH_COUNT) }
 }
 
-pub fn path_from_id(val: u16) -> Result<*mut bw::Path, LoadError> {
+pub fn path_from_id<M: GameMemory>(game: &M, val: u16) -> Result<*mut bw::Path, LoadError> {
     if val == 0 {
         Ok(null_mut())
+    } else if val <= PATH_COUNT {
+        unsafe { Ok((*game.path_array_start()).add(val as usize - 1)) }
     } else {
-        unsafe { Ok(bw::path_array_start.offset(val as isize - 1)) }
+        Err(LoadError::Corrupted(format!("Invalid path id 0x{:x}", val)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr::null_mut;
+
+    use bincode;
+
+    use game_memory::GameMemory;
+    use game_memory::test::test_game;
+    use save_format;
+    use sprites;
+    use state::plugin_state;
+
+    use super::{free_unit_slots, load_units, path_from_id, path_to_id, serialize_units};
+
+    #[test]
+    fn invalid_path_ids() {
+        unsafe {
+            let test = test_game(1);
+            let game = &test.game;
+            let paths = *game.path_array_start();
+            assert_eq!(path_to_id(game, paths.add(3)).unwrap(), 4);
+            assert!(path_to_id(game, paths.wrapping_sub(1)).is_err());
+            assert!(path_to_id(game, (paths as *mut u8).add(1) as *mut _).is_err());
+            assert_eq!(path_from_id(game, 4).unwrap(), paths.add(3));
+            assert!(path_from_id(game, 0x6a5).is_err());
+        }
+    }
+
+    #[test]
+    fn looping_free_units() {
+        unsafe {
+            let test = test_game(3);
+            let game = &test.game;
+            let units = &mut *game.units();
+            assert!(free_unit_slots(game).is_ok());
+            units[5].entity.next = &mut units[4].entity;
+            units[4].entity.next = &mut units[5].entity;
+            *game.first_free_unit() = &mut units[4];
+            assert!(free_unit_slots(game).is_err());
+        }
+    }
+
+    #[test]
+    fn failed_load_keeps_globals() {
+        unsafe {
+            let mut test = test_game(7);
+            let sprites = sprites::serialize_sprites(&test.game).unwrap();
+            let mut units = serialize_units(&test.game).unwrap();
+            plugin_state().borrow_mut().reset_objects(&test.game);
+            // The last player unit list is the last global that gets resolved.
+            let header = save_format::decode_units(&units).unwrap().header;
+            let offset = bincode::serialized_size(&header) as usize + 0x2a;
+            units[offset] = 0xff;
+            units[offset + 1] = 0xff;
+
+            let loaded = test.empty_memory();
+            sprites::load_sprites(&loaded, &sprites).unwrap();
+            assert!(load_units(&loaded, &units).is_err());
+            assert!((*loaded.units()).iter().all(|unit| unit.entity.sprite == null_mut()));
+            assert_eq!(*loaded.first_active_unit(), null_mut());
+            assert_eq!(*loaded.first_free_unit(), null_mut());
+        }
     }
 }